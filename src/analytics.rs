@@ -0,0 +1,258 @@
+use std::time::SystemTime;
+
+use crate::{MatchingEngine, OrderBook};
+
+//Book analytics
+// Microstructure metrics read straight from the levels of an OrderBook. The
+// time-weighted spread is kept incrementally: after every change to a book the
+// spread that was quoted since the previous change is weighted by how long it
+// lasted. Periods with one side of the book empty have no spread and are left out.
+
+#[derive(Clone, Debug, Copy, PartialEq)]
+pub struct BookAnalytics {
+    spread_: Option<f32>,
+    mid_: Option<f64>,
+    microprice_: Option<f64>,
+    imbalance_: Option<f64>,
+}
+
+impl BookAnalytics {
+    pub fn spread(&self) -> Option<f32> {
+        self.spread_
+    }
+
+    pub fn mid(&self) -> Option<f64> {
+        self.mid_
+    }
+
+    //mid weighted towards the side with less qty at the touch
+    pub fn microprice(&self) -> Option<f64> {
+        self.microprice_
+    }
+
+    //(bid qty - ask qty) / (bid qty + ask qty) over the analysed levels, in [-1, 1]
+    pub fn imbalance(&self) -> Option<f64> {
+        self.imbalance_
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct SpreadTracker {
+    //spread quoted since last_change_
+    spread_: Option<f32>,
+    last_change_: SystemTime,
+    weighted_spread_: f64,
+    quoted_seconds_: f64,
+}
+
+impl SpreadTracker {
+    fn new(p_time: SystemTime) -> Self {
+        SpreadTracker {
+            spread_: None,
+            last_change_: p_time,
+            weighted_spread_: 0.0,
+            quoted_seconds_: 0.0,
+        }
+    }
+
+    //(weighted spread, quoted seconds) up to p_now
+    fn totals(&self, p_now: SystemTime) -> (f64, f64) {
+        let elapsed = p_now
+            .duration_since(self.last_change_)
+            .unwrap_or_default()
+            .as_secs_f64();
+        match self.spread_ {
+            None => (self.weighted_spread_, self.quoted_seconds_),
+            Some(spread) => (
+                self.weighted_spread_ + spread as f64 * elapsed,
+                self.quoted_seconds_ + elapsed,
+            ),
+        }
+    }
+
+    fn update(&mut self, p_spread: Option<f32>, p_time: SystemTime) {
+        if p_time < self.last_change_ {
+            //out of order time stamp, the spread only changes from now on
+            self.spread_ = p_spread;
+            return;
+        }
+        let (weighted_spread, quoted_seconds) = self.totals(p_time);
+        self.weighted_spread_ = weighted_spread;
+        self.quoted_seconds_ = quoted_seconds;
+        self.spread_ = p_spread;
+        self.last_change_ = p_time;
+    }
+}
+
+impl OrderBook {
+    pub(crate) fn analytics(&self, p_levels: usize) -> BookAnalytics {
+        let best_bid_offer = self.best_bid_offer();
+        let (bid, ask) = match (best_bid_offer.bid(), best_bid_offer.ask()) {
+            (Some(bid), Some(ask)) => (bid, ask),
+            _ => {
+                return BookAnalytics {
+                    spread_: None,
+                    mid_: None,
+                    microprice_: None,
+                    imbalance_: None,
+                }
+            }
+        };
+
+        let bid_price = bid.price() as f64;
+        let ask_price = ask.price() as f64;
+        let bid_qty = bid.qty() as f64;
+        let ask_qty = ask.qty() as f64;
+        let depth = self.depth(p_levels);
+        let bid_depth: f64 = depth.bids().iter().map(|level| level.qty() as f64).sum();
+        let ask_depth: f64 = depth.asks().iter().map(|level| level.qty() as f64).sum();
+        return BookAnalytics {
+            spread_: best_bid_offer.spread(),
+            mid_: Some((bid_price + ask_price) / 2.0),
+            microprice_: Some((bid_price * ask_qty + ask_price * bid_qty) / (bid_qty + ask_qty)),
+            imbalance_: Some((bid_depth - ask_depth) / (bid_depth + ask_depth)),
+        };
+    }
+
+    //(bid qty, ask qty) priced within p_ticks ticks of the best price of each side
+    pub(crate) fn depth_within(&self, p_ticks: u32, p_tick_size: f32) -> (i64, i64) {
+        let distance = p_ticks as f32 * p_tick_size;
+        //half a tick of slack against float rounding of the prices
+        let slack = p_tick_size / 2.0;
+        let bid_qty = match self.best_bid_price() {
+            None => 0,
+            Some(best_bid) => self
+                .bids_
+                .iter()
+                .map(|level| level.summary())
+                .take_while(|level| level.price() >= best_bid - distance - slack)
+                .map(|level| level.qty() as i64)
+                .sum(),
+        };
+        let ask_qty = match self.best_ask_price() {
+            None => 0,
+            Some(best_ask) => self
+                .asks_
+                .iter()
+                .map(|level| level.summary())
+                .take_while(|level| level.price() <= best_ask + distance + slack)
+                .map(|level| level.qty() as i64)
+                .sum(),
+        };
+        return (bid_qty, ask_qty);
+    }
+}
+
+impl MatchingEngine {
+    //metrics of the book of p_symbol, imbalance over the top p_levels of each side
+    pub fn book_analytics(&self, p_symbol: &String, p_levels: usize) -> Option<BookAnalytics> {
+        self.order_book_by_symbol_
+            .get(p_symbol)
+            .map(|order_book| order_book.analytics(p_levels))
+    }
+
+    pub fn depth_within(
+        &self,
+        p_symbol: &String,
+        p_ticks: u32,
+        p_tick_size: f32,
+    ) -> Option<(i64, i64)> {
+        self.order_book_by_symbol_
+            .get(p_symbol)
+            .map(|order_book| order_book.depth_within(p_ticks, p_tick_size))
+    }
+
+    //average spread weighted by time up to p_now, None when it was never quoted
+    pub fn time_weighted_spread(&self, p_symbol: &String, p_now: SystemTime) -> Option<f64> {
+        let (weighted_spread, quoted_seconds) = self.spread_by_symbol_.get(p_symbol)?.totals(p_now);
+        if quoted_seconds <= 0.0 {
+            return None;
+        }
+        return Some(weighted_spread / quoted_seconds);
+    }
+
+    //starts a new time-weighted spread period for p_symbol at p_now
+    pub fn reset_time_weighted_spread(&mut self, p_symbol: &String, p_now: SystemTime) {
+        if let Some(tracker) = self.spread_by_symbol_.get_mut(p_symbol) {
+            let spread = tracker.spread_;
+            *tracker = SpreadTracker::new(p_now);
+            tracker.spread_ = spread;
+        }
+    }
+
+    pub(crate) fn update_spread(&mut self, p_symbol: &String, p_time: SystemTime) {
+        let spread = match self.order_book_by_symbol_.get(p_symbol) {
+            None => return,
+            Some(order_book) => order_book.best_bid_offer().spread(),
+        };
+        self.spread_by_symbol_
+            .entry(p_symbol.to_owned())
+            .or_insert_with(|| SpreadTracker::new(p_time))
+            .update(spread, p_time);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::test_order;
+    use crate::{process_event, EventType, Order, OrderSide};
+    use std::time::Duration;
+
+    fn order(p_id: &str, p_side: OrderSide, p_qty: i32, p_price: f32, p_time: SystemTime) -> Order {
+        test_order(p_id, p_side, p_qty, p_price).with_time(p_time)
+    }
+
+    #[test]
+    fn metrics_from_levels() {
+        let symbol = String::from("REL");
+        let start = SystemTime::now();
+        let mut engine = MatchingEngine::new();
+        let orders = [
+            order("1", OrderSide::Buy, 300, 99.0, start),
+            order("2", OrderSide::Buy, 100, 98.5, start),
+            order("3", OrderSide::Buy, 100, 97.0, start),
+            order("4", OrderSide::Sell, 100, 100.0, start),
+            order("5", OrderSide::Sell, 100, 100.5, start),
+        ];
+        for mut order in orders {
+            process_event(EventType::New, &mut order, &mut engine).unwrap();
+        }
+
+        let analytics = engine.book_analytics(&symbol, 1).unwrap();
+        assert_eq!(analytics.spread(), Some(1.0));
+        assert_eq!(analytics.mid(), Some(99.5));
+        assert_eq!(analytics.microprice(), Some(99.75));
+        assert_eq!(analytics.imbalance(), Some(0.5));
+        assert_eq!(engine.depth_within(&symbol, 2, 0.5), Some((400, 200)));
+        assert_eq!(engine.depth_within(&symbol, 0, 0.5), Some((300, 100)));
+    }
+
+    #[test]
+    fn spread_weighted_by_time() {
+        let symbol = String::from("REL");
+        let start = SystemTime::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+        let clock = ManualClock::new(start);
+        let mut engine = MatchingEngine::new();
+        engine.set_clock(clock.clone());
+        let mut buy = order("1", OrderSide::Buy, 100, 99.0, at(0));
+        process_event(EventType::New, &mut buy, &mut engine).unwrap();
+        //spread 2 from 10s
+        clock.set(at(10));
+        let mut sell = order("2", OrderSide::Sell, 100, 101.0, at(10));
+        process_event(EventType::New, &mut sell, &mut engine).unwrap();
+        assert_eq!(engine.time_weighted_spread(&symbol, at(10)), None);
+
+        //spread 1 from 40s
+        clock.set(at(40));
+        let mut sell = order("3", OrderSide::Sell, 100, 100.0, at(40));
+        process_event(EventType::New, &mut sell, &mut engine).unwrap();
+        //2 for 30s then 1 for 30s
+        assert_eq!(engine.time_weighted_spread(&symbol, at(70)), Some(1.5));
+
+        engine.reset_time_weighted_spread(&symbol, at(70));
+        assert_eq!(engine.time_weighted_spread(&symbol, at(80)), Some(1.0));
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::BTreeSet;
+
+use crate::order_feed::Fill;
+use crate::{Level, MatchingResult, OrderBook, OrderSide};
+
+//Auction uncross
+// Orders collected during a call period rest in the book without matching.
+// When the call ends the book is uncrossed at the single price which executes
+// the largest quantity, ties broken by the smallest imbalance and then by market
+// pressure (highest price when buyers are left over, lowest otherwise).
+
+impl OrderBook {
+    fn executable_qty_at(&self, p_price: f32) -> (i32, i32) {
+        let buy_qty: i32 = self
+            .bids_
+            .iter()
+            .filter(|level| level.price_ >= p_price)
+            .map(|level| level.orders_.iter().map(|order| order.qty_).sum::<i32>())
+            .sum();
+        let sell_qty: i32 = self
+            .asks_
+            .iter()
+            .filter(|level| level.price_ <= p_price)
+            .map(|level| level.orders_.iter().map(|order| order.qty_).sum::<i32>())
+            .sum();
+        return (buy_qty, sell_qty);
+    }
+
+    //returns (uncross price, executable qty) or None when the book is not crossed
+    pub(crate) fn equilibrium(&self) -> Option<(f32, i32)> {
+        // (price, executable qty, buy qty - sell qty)
+        let mut best: Option<(f32, i32, i32)> = None;
+        // market-on-close orders rest at 0 or infinity and never set the price
+        let candidate_prices = self
+            .bids_
+            .iter()
+            .chain(self.asks_.iter())
+            .map(|l| l.price_)
+            .filter(|price| price.is_finite() && *price > 0.0);
+        for price in candidate_prices {
+            let (buy_qty, sell_qty) = self.executable_qty_at(price);
+            let executable = buy_qty.min(sell_qty);
+            if executable == 0 {
+                continue;
+            }
+            let surplus = buy_qty - sell_qty;
+            let better = match best {
+                None => true,
+                Some((best_price, best_executable, best_surplus)) => {
+                    if executable != best_executable {
+                        executable > best_executable
+                    } else if surplus.abs() != best_surplus.abs() {
+                        surplus.abs() < best_surplus.abs()
+                    } else if surplus > 0 {
+                        price > best_price
+                    } else {
+                        price < best_price
+                    }
+                }
+            };
+            if better {
+                best = Some((price, executable, surplus));
+            }
+        }
+        return best.map(|(price, executable, _)| (price, executable));
+    }
+
+    //executes every crossed order at the equilibrium price in price/time priority
+    pub(crate) fn uncross(&mut self) -> Option<MatchingResult> {
+        let (price, executable) = self.equilibrium()?;
+        println!("Uncrossing {executable} at {price}");
+
+        let mut result = MatchingResult::default();
+        fill_side(&mut self.bids_, price, executable, &mut result);
+        fill_side(&mut self.asks_, price, executable, &mut result);
+        result.executed_qty_ = executable;
+        result.executed_price_ = price;
+        let trade_id = self.next_trade_id();
+        self.publish_executions(&mut result, Some(trade_id));
+        self.unindex_executed(&result);
+        println!("After uncross {:?}", self);
+        return Some(result);
+    }
+}
+
+fn crosses(p_level: &Level, p_price: f32) -> bool {
+    match p_level.side_ {
+        OrderSide::Buy => p_level.price_ >= p_price,
+        OrderSide::Sell => p_level.price_ <= p_price,
+    }
+}
+
+fn fill_side(
+    p_levels: &mut BTreeSet<Level>,
+    p_price: f32,
+    p_qty: i32,
+    p_result: &mut MatchingResult,
+) {
+    let mut remaining_qty = p_qty;
+    while remaining_qty > 0 {
+        let mut level = match p_levels.pop_first() {
+            None => return,
+            Some(level) => level,
+        };
+        if !crosses(&level, p_price) {
+            p_levels.insert(level);
+            return;
+        }
+
+        while remaining_qty > 0 {
+            let mut order = match level.orders_.pop_first() {
+                None => break,
+                Some(order) => order,
+            };
+            p_result.matched_order_ids_.push(order.id_.to_owned());
+            let fill_qty = order.qty_.min(remaining_qty);
+            p_result
+                .fills_
+                .push(Fill::new(&order.id_, order.side_, p_price, fill_qty));
+            order.qty_ -= fill_qty;
+            remaining_qty -= fill_qty;
+            if order.qty_ > 0 {
+                level.orders_.insert(order);
+            }
+        }
+
+        if !level.orders_.is_empty() {
+            p_levels.insert(level);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_order;
+
+    fn add(p_book: &mut OrderBook, p_id: &str, p_side: OrderSide, p_qty: i32, p_price: f32) {
+        let mut order = test_order(p_id, p_side, p_qty, p_price);
+        p_book.add_order(&mut order);
+    }
+
+    #[test]
+    fn uncross_at_max_volume_price() {
+        let mut book = OrderBook::new(&String::from("REL"));
+        add(&mut book, "b1", OrderSide::Buy, 100, 101.0);
+        add(&mut book, "b2", OrderSide::Buy, 100, 100.0);
+        add(&mut book, "s1", OrderSide::Sell, 150, 99.0);
+        add(&mut book, "s2", OrderSide::Sell, 100, 102.0);
+
+        assert_eq!(book.equilibrium(), Some((100.0, 150)));
+        let result = book.uncross().unwrap();
+        assert_eq!(result.executed_qty_, 150);
+        assert_eq!(result.executed_price_, 100.0);
+        assert_eq!(result.matched_order_ids_, vec!["b1", "b2", "s1"]);
+
+        //50 of b2 and all of s2 are left, book is no longer crossed
+        assert_eq!(book.equilibrium(), None);
+        assert_eq!(
+            book.bids_.first().unwrap().orders_.first().unwrap().qty_,
+            50
+        );
+        assert_eq!(book.asks_.len(), 1);
+    }
+
+    #[test]
+    fn uncross_not_crossed() {
+        let mut book = OrderBook::new(&String::from("REL"));
+        add(&mut book, "b1", OrderSide::Buy, 100, 99.0);
+        add(&mut book, "s1", OrderSide::Sell, 100, 100.0);
+        assert!(book.uncross().is_none());
+    }
+}
@@ -0,0 +1,627 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::time::SystemTime;
+
+use crate::events::EngineEvent;
+use crate::gtc::END_OF_DAY;
+use crate::order_feed::OrderUpdate;
+use crate::{EventType, MatchingEngine, MatchingResult, Order, OrderSide};
+
+//Audit trail
+// With an AuditTrail attached the engine keeps the whole life of every order it
+// sees: the request as received, its acceptance or rejection, each fill with its
+// trade id, replaces with the values before and after, cancels and the DAY orders
+// expired at the end of the day. Records carry their own sequence number, the
+// engine input sequence they belong to and the engine time, and can be looked up
+// per order id or written out as CSV for a CAT like report. The trail follows the
+// engine from the moment it is attached and is not part of a snapshot, orders
+// resting from before are only known from what happens to them afterwards.
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AuditAction {
+    Received,
+    Accepted,
+    Filled,
+    Replaced,
+    Cancelled,
+    Expired,
+    Rejected,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AuditRecord {
+    #[cfg_attr(feature = "serde", serde(rename = "seq"))]
+    seq_: u64,
+    // input being processed, or the last one for transitions and the end of day
+    #[cfg_attr(feature = "serde", serde(rename = "input_seq"))]
+    input_seq_: u64,
+    #[cfg_attr(feature = "serde", serde(rename = "time"))]
+    time_: SystemTime,
+    #[cfg_attr(feature = "serde", serde(rename = "symbol"))]
+    symbol_: String,
+    #[cfg_attr(feature = "serde", serde(rename = "order_id"))]
+    order_id_: String,
+    #[cfg_attr(feature = "serde", serde(rename = "client_id"))]
+    client_id_: String,
+    #[cfg_attr(feature = "serde", serde(rename = "action"))]
+    action_: AuditAction,
+    // None for an order the trail never saw the side of
+    #[cfg_attr(feature = "serde", serde(rename = "side"))]
+    side_: Option<OrderSide>,
+    // limit price, execution price for a fill
+    #[cfg_attr(feature = "serde", serde(rename = "price"))]
+    price_: f32,
+    // order qty, filled qty for a fill, leaves qty for a cancel
+    #[cfg_attr(feature = "serde", serde(rename = "qty"))]
+    qty_: i32,
+    // values before a replace
+    #[cfg_attr(feature = "serde", serde(rename = "old_price"))]
+    old_price_: Option<f32>,
+    #[cfg_attr(feature = "serde", serde(rename = "old_qty"))]
+    old_qty_: Option<i32>,
+    #[cfg_attr(feature = "serde", serde(rename = "trade_id"))]
+    trade_id_: Option<u64>,
+    // request kind, rejection or cancel reason
+    #[cfg_attr(feature = "serde", serde(rename = "detail"))]
+    detail_: String,
+}
+
+impl AuditRecord {
+    pub fn seq(&self) -> u64 {
+        self.seq_
+    }
+
+    pub fn input_seq(&self) -> u64 {
+        self.input_seq_
+    }
+
+    pub fn time(&self) -> SystemTime {
+        self.time_
+    }
+
+    pub fn symbol(&self) -> &String {
+        &self.symbol_
+    }
+
+    pub fn order_id(&self) -> &String {
+        &self.order_id_
+    }
+
+    pub fn client_id(&self) -> &String {
+        &self.client_id_
+    }
+
+    pub fn action(&self) -> AuditAction {
+        self.action_
+    }
+
+    pub fn side(&self) -> Option<OrderSide> {
+        self.side_
+    }
+
+    pub fn price(&self) -> f32 {
+        self.price_
+    }
+
+    pub fn qty(&self) -> i32 {
+        self.qty_
+    }
+
+    pub fn old_price(&self) -> Option<f32> {
+        self.old_price_
+    }
+
+    pub fn old_qty(&self) -> Option<i32> {
+        self.old_qty_
+    }
+
+    pub fn trade_id(&self) -> Option<u64> {
+        self.trade_id_
+    }
+
+    pub fn detail(&self) -> &String {
+        &self.detail_
+    }
+}
+
+//what the trail knows of an order to fill in the records of engine events
+#[derive(Clone, Debug)]
+struct AuditedOrder {
+    client_id_: String,
+    side_: Option<OrderSide>,
+    price_: f32,
+    leaves_qty_: i32,
+}
+
+#[derive(Clone, Debug)]
+pub struct AuditTrail {
+    records_: Vec<AuditRecord>,
+    // indexes into records_, in order
+    records_by_order_: BTreeMap<String, Vec<usize>>,
+    orders_: BTreeMap<String, AuditedOrder>,
+    // engine input sequence and time the records being added belong to
+    input_seq_: u64,
+    time_: SystemTime,
+}
+
+impl Default for AuditTrail {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AuditTrail {
+    pub fn new() -> Self {
+        AuditTrail {
+            records_: Vec::new(),
+            records_by_order_: BTreeMap::new(),
+            orders_: BTreeMap::new(),
+            input_seq_: 0,
+            time_: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    pub fn records(&self) -> &[AuditRecord] {
+        &self.records_
+    }
+
+    //every record of p_order_id in the order they happened
+    pub fn history(&self, p_order_id: &str) -> Vec<&AuditRecord> {
+        match self.records_by_order_.get(p_order_id) {
+            None => Vec::new(),
+            Some(indexes) => indexes.iter().map(|index| &self.records_[*index]).collect(),
+        }
+    }
+
+    //writes every record as a CSV line after a header line, times in nanoseconds since the epoch
+    pub fn write_csv(&self, p_writer: &mut impl Write) -> Result<(), String> {
+        let mut csv = String::from(
+            "seq,input_seq,time,symbol,order_id,client_id,action,side,price,qty,old_price,old_qty,trade_id,detail\n",
+        );
+        for record in &self.records_ {
+            let time = record
+                .time_
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos();
+            csv.push_str(&format!(
+                "{},{},{},{},{},{},{:?},{},{},{},{},{},{},{}\n",
+                record.seq_,
+                record.input_seq_,
+                time,
+                csv_field(&record.symbol_),
+                csv_field(&record.order_id_),
+                csv_field(&record.client_id_),
+                record.action_,
+                record
+                    .side_
+                    .map(|side| format!("{:?}", side))
+                    .unwrap_or_default(),
+                record.price_,
+                record.qty_,
+                optional_field(record.old_price_),
+                optional_field(record.old_qty_),
+                optional_field(record.trade_id_),
+                csv_field(&record.detail_),
+            ));
+        }
+        return p_writer
+            .write_all(csv.as_bytes())
+            .map_err(|error| format!("Failed to write the audit trail: {}", error));
+    }
+
+    fn at(&mut self, p_input_seq: u64, p_time: SystemTime) {
+        self.input_seq_ = p_input_seq;
+        self.time_ = p_time;
+    }
+
+    //adds a record of p_order_id filled in from what is known of the order,
+    //p_price None takes its limit price
+    fn push(
+        &mut self,
+        p_symbol: &str,
+        p_order_id: &str,
+        p_action: AuditAction,
+        p_price: Option<f32>,
+        p_qty: i32,
+    ) -> &mut AuditRecord {
+        let (client_id, side, price) = match self.orders_.get(p_order_id) {
+            None => (String::new(), None, 0.0),
+            Some(order) => (order.client_id_.to_owned(), order.side_, order.price_),
+        };
+        let index = self.records_.len();
+        self.records_.push(AuditRecord {
+            seq_: index as u64 + 1,
+            input_seq_: self.input_seq_,
+            time_: self.time_,
+            symbol_: p_symbol.to_owned(),
+            order_id_: p_order_id.to_owned(),
+            client_id_: client_id,
+            action_: p_action,
+            side_: side,
+            price_: p_price.unwrap_or(price),
+            qty_: p_qty,
+            old_price_: None,
+            old_qty_: None,
+            trade_id_: None,
+            detail_: String::new(),
+        });
+        self.records_by_order_
+            .entry(p_order_id.to_owned())
+            .or_default()
+            .push(index);
+        return &mut self.records_[index];
+    }
+
+    fn leaves_qty(&self, p_order_id: &str) -> i32 {
+        self.orders_
+            .get(p_order_id)
+            .map(|order| order.leaves_qty_)
+            .unwrap_or_default()
+    }
+
+    fn reduce_leaves(&mut self, p_order_id: &str, p_qty: i32) {
+        if let Some(order) = self.orders_.get_mut(p_order_id) {
+            order.leaves_qty_ = (order.leaves_qty_ - p_qty).max(0);
+        }
+    }
+
+    fn received(&mut self, p_event_type: EventType, p_order: &Order) {
+        let audited_order = AuditedOrder {
+            client_id_: p_order.client_id_.to_owned(),
+            side_: Some(p_order.side_),
+            price_: p_order.price_,
+            leaves_qty_: p_order.qty_,
+        };
+        match p_event_type {
+            EventType::New => {
+                self.orders_.insert(p_order.id_.to_owned(), audited_order);
+            }
+            EventType::Rpl | EventType::Cxl => {
+                self.orders_
+                    .entry(p_order.id_.to_owned())
+                    .or_insert(audited_order);
+            }
+        }
+        let request = match p_event_type {
+            EventType::New => "new order",
+            EventType::Rpl => "replace request",
+            EventType::Cxl => "cancel request",
+        };
+        let record = self.push(
+            &p_order.symbol_,
+            &p_order.id_,
+            AuditAction::Received,
+            Some(p_order.price_),
+            p_order.qty_,
+        );
+        record.detail_ = request.to_owned();
+    }
+
+    //p_order is the input as received
+    fn processed(
+        &mut self,
+        p_event_type: EventType,
+        p_order: &Order,
+        p_result: &Result<Option<MatchingResult>, String>,
+    ) {
+        if let Err(reason) = p_result {
+            let record = self.push(
+                &p_order.symbol_,
+                &p_order.id_,
+                AuditAction::Rejected,
+                Some(p_order.price_),
+                p_order.qty_,
+            );
+            record.detail_ = reason.to_owned();
+            if let EventType::New = p_event_type {
+                self.reduce_leaves(&p_order.id_, p_order.qty_);
+            }
+            return;
+        }
+
+        match p_event_type {
+            EventType::New => {
+                self.push(
+                    &p_order.symbol_,
+                    &p_order.id_,
+                    AuditAction::Accepted,
+                    Some(p_order.price_),
+                    p_order.qty_,
+                );
+            }
+            EventType::Rpl => {
+                let old_order = self.orders_.get(&p_order.id_).cloned();
+                if let Some(order) = self.orders_.get_mut(&p_order.id_) {
+                    order.price_ = p_order.price_;
+                    order.leaves_qty_ = p_order.qty_;
+                }
+                let record = self.push(
+                    &p_order.symbol_,
+                    &p_order.id_,
+                    AuditAction::Replaced,
+                    Some(p_order.price_),
+                    p_order.qty_,
+                );
+                record.old_price_ = old_order.as_ref().map(|order| order.price_);
+                record.old_qty_ = old_order.as_ref().map(|order| order.leaves_qty_);
+            }
+            EventType::Cxl => {
+                let leaves_qty = self.leaves_qty(&p_order.id_);
+                self.cancelled(&p_order.symbol_, &p_order.id_, leaves_qty, "cancel request");
+            }
+        }
+    }
+
+    //fills of the incoming order p_order_id, the counterpart of each resting order fill
+    fn taker_fills(&mut self, p_symbol: &str, p_order_id: &str, p_result: &Option<MatchingResult>) {
+        if let Some(match_result) = p_result {
+            for fill in &match_result.fills_ {
+                self.filled(
+                    p_symbol,
+                    p_order_id,
+                    fill.price(),
+                    fill.qty(),
+                    fill.trade_id(),
+                );
+            }
+        }
+    }
+
+    fn filled(
+        &mut self,
+        p_symbol: &str,
+        p_order_id: &str,
+        p_price: f32,
+        p_qty: i32,
+        p_trade_id: u64,
+    ) -> &mut AuditRecord {
+        self.reduce_leaves(p_order_id, p_qty);
+        let record = self.push(
+            p_symbol,
+            p_order_id,
+            AuditAction::Filled,
+            Some(p_price),
+            p_qty,
+        );
+        record.trade_id_ = Some(p_trade_id);
+        return record;
+    }
+
+    fn cancelled(&mut self, p_symbol: &str, p_order_id: &str, p_qty: i32, p_reason: &str) {
+        let action = if p_reason == END_OF_DAY {
+            AuditAction::Expired
+        } else {
+            AuditAction::Cancelled
+        };
+        self.reduce_leaves(p_order_id, p_qty);
+        let record = self.push(p_symbol, p_order_id, action, None, p_qty);
+        record.detail_ = p_reason.to_owned();
+    }
+
+    //fills, cancels and rejections among p_events
+    fn events(&mut self, p_events: &[EngineEvent]) {
+        for event in p_events {
+            match event {
+                EngineEvent::OrderFeed(message) => {
+                    if let OrderUpdate::Execute {
+                        order_id_,
+                        side_,
+                        price_,
+                        qty_,
+                        trade_id_,
+                    } = message.update()
+                    {
+                        let record =
+                            self.filled(message.symbol(), order_id_, *price_, *qty_, *trade_id_);
+                        record.side_ = Some(*side_);
+                    }
+                }
+                EngineEvent::OrderCancelled {
+                    symbol_,
+                    order_id_,
+                    reason_,
+                } => {
+                    let leaves_qty = self.leaves_qty(order_id_);
+                    self.cancelled(symbol_, order_id_, leaves_qty, reason_);
+                }
+                EngineEvent::SelfTradePrevented {
+                    symbol_,
+                    aggressor_order_id_,
+                    resting_order_id_,
+                    mode_,
+                    aggressor_cancelled_qty_,
+                    resting_cancelled_qty_,
+                } => {
+                    let reason = format!("self-trade prevention {:?}", mode_);
+                    if *aggressor_cancelled_qty_ > 0 {
+                        self.cancelled(
+                            symbol_,
+                            aggressor_order_id_,
+                            *aggressor_cancelled_qty_,
+                            &reason,
+                        );
+                    }
+                    if *resting_cancelled_qty_ > 0 {
+                        self.cancelled(
+                            symbol_,
+                            resting_order_id_,
+                            *resting_cancelled_qty_,
+                            &reason,
+                        );
+                    }
+                }
+                EngineEvent::OrderRejected {
+                    symbol_,
+                    order_id_,
+                    reason_,
+                } => {
+                    let leaves_qty = self.leaves_qty(order_id_);
+                    self.reduce_leaves(order_id_, leaves_qty);
+                    let record =
+                        self.push(symbol_, order_id_, AuditAction::Rejected, None, leaves_qty);
+                    record.detail_ = reason_.to_owned();
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn csv_field(p_value: &str) -> String {
+    if p_value.contains([',', '"', '\n', '\r']) {
+        return format!("\"{}\"", p_value.replace('"', "\"\""));
+    }
+    return p_value.to_owned();
+}
+
+fn optional_field<T: ToString>(p_value: Option<T>) -> String {
+    p_value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+impl MatchingEngine {
+    //from now on the life of every order is recorded in p_audit_trail
+    pub fn attach_audit_trail(&mut self, p_audit_trail: AuditTrail) {
+        self.audit_trail_ = Some(p_audit_trail);
+    }
+
+    pub fn detach_audit_trail(&mut self) -> Option<AuditTrail> {
+        self.audit_trail_.take()
+    }
+
+    pub fn audit_trail(&self) -> Option<&AuditTrail> {
+        self.audit_trail_.as_ref()
+    }
+
+    //records p_order as received, before it is processed
+    pub(crate) fn audit_input(&mut self, p_event_type: EventType, p_order: &Order) {
+        if let Some(audit_trail) = &mut self.audit_trail_ {
+            audit_trail.at(self.input_seq_, p_order.entry_time_);
+            audit_trail.received(p_event_type, p_order);
+        }
+    }
+
+    //records what came of p_received, the input as received, and the engine events
+    //it produced from p_first_event on
+    pub(crate) fn audit_result(
+        &mut self,
+        p_event_type: EventType,
+        p_received: &Order,
+        p_result: &Result<Option<MatchingResult>, String>,
+        p_first_event: usize,
+    ) {
+        if let Some(audit_trail) = &mut self.audit_trail_ {
+            audit_trail.at(self.input_seq_, p_received.entry_time_);
+            audit_trail.processed(p_event_type, p_received, p_result);
+            if let Ok(match_result) = p_result {
+                audit_trail.taker_fills(&p_received.symbol_, &p_received.id_, match_result);
+            }
+            audit_trail.events(&self.events_[p_first_event..]);
+        }
+    }
+
+    //records the fills of p_order, an order processed outside of an input
+    pub(crate) fn audit_fills(&mut self, p_order: &Order, p_result: &Option<MatchingResult>) {
+        if let Some(audit_trail) = &mut self.audit_trail_ {
+            audit_trail.at(self.input_seq_, self.clock_.now());
+            audit_trail.taker_fills(&p_order.symbol_, &p_order.id_, p_result);
+        }
+    }
+
+    //records the engine events from p_first_event on
+    pub(crate) fn audit_events(&mut self, p_first_event: usize) {
+        if let Some(audit_trail) = &mut self.audit_trail_ {
+            audit_trail.at(self.input_seq_, self.clock_.now());
+            audit_trail.events(&self.events_[p_first_event..]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::process_event;
+    use crate::test_order;
+    use std::time::Duration;
+
+    fn order(p_id: &str, p_client_id: &str, p_side: OrderSide, p_qty: i32, p_price: f32) -> Order {
+        test_order(p_id, p_side, p_qty, p_price).with_client(p_client_id)
+    }
+
+    fn actions(p_engine: &MatchingEngine, p_order_id: &str) -> Vec<AuditAction> {
+        p_engine
+            .audit_trail()
+            .unwrap()
+            .history(p_order_id)
+            .iter()
+            .map(|record| record.action())
+            .collect()
+    }
+
+    #[test]
+    fn order_lifecycle_recorded_and_exported() {
+        let mut engine = MatchingEngine::new();
+        engine.set_clock(ManualClock::new(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_000),
+        ));
+        engine.attach_audit_trail(AuditTrail::new());
+        let inputs = [
+            (EventType::New, order("1", "C1", OrderSide::Buy, 100, 100.0)),
+            (EventType::New, order("2", "C2", OrderSide::Sell, 40, 100.0)),
+            (EventType::Rpl, order("1", "C1", OrderSide::Buy, 80, 101.0)),
+            (EventType::Cxl, order("1", "C1", OrderSide::Buy, 80, 101.0)),
+            (EventType::Cxl, order("9", "C1", OrderSide::Buy, 10, 101.0)),
+            (EventType::New, order("3", "C2", OrderSide::Sell, 10, 105.0)),
+        ];
+        for (event_type, mut order) in inputs {
+            let _ = process_event(event_type, &mut order, &mut engine);
+        }
+        engine.purge_day_orders().unwrap();
+
+        use AuditAction::*;
+        assert_eq!(
+            actions(&engine, "1"),
+            vec![Received, Accepted, Filled, Received, Replaced, Received, Cancelled]
+        );
+        assert_eq!(actions(&engine, "2"), vec![Received, Accepted, Filled]);
+        assert_eq!(actions(&engine, "9"), vec![Received, Rejected]);
+        assert_eq!(actions(&engine, "3"), vec![Received, Accepted, Expired]);
+
+        let audit_trail = engine.audit_trail().unwrap();
+        let history = audit_trail.history("1");
+        assert_eq!(history[2].qty(), 40);
+        assert_eq!(history[2].trade_id(), Some(1));
+        assert_eq!(history[2].input_seq(), 2);
+        assert_eq!(history[4].old_price(), Some(100.0));
+        assert_eq!(history[4].old_qty(), Some(60));
+        assert_eq!(history[4].qty(), 80);
+        assert_eq!(history[6].qty(), 80);
+        assert_eq!(history[6].client_id(), "C1");
+        assert_eq!(audit_trail.history("2")[2].side(), Some(OrderSide::Sell));
+        assert_eq!(audit_trail.history("3")[2].qty(), 10);
+        let seqs: Vec<u64> = audit_trail
+            .records()
+            .iter()
+            .map(|record| record.seq())
+            .collect();
+        assert_eq!(
+            seqs,
+            (1..=audit_trail.records().len() as u64).collect::<Vec<u64>>()
+        );
+
+        let mut csv = Vec::new();
+        audit_trail.write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), audit_trail.records().len() + 1);
+        assert!(lines[0].starts_with("seq,input_seq,time,symbol,order_id"));
+        assert_eq!(
+            lines[history[4].seq() as usize],
+            "8,3,1000000000000,REL,1,C1,Replaced,Buy,101,80,100,60,,"
+        );
+        assert!(csv.contains("\"Failed to remove original order, cancel failed\""));
+    }
+}
@@ -0,0 +1,525 @@
+use std::time::{Duration, SystemTime};
+
+use crate::events::EngineEvent;
+use crate::ticker::Trade;
+use crate::MatchingEngine;
+
+//OHLCV bars
+// Trades of a symbol are aggregated into bars for every configured BarInterval.
+// Time bars are aligned to multiples of the interval since the unix epoch and close
+// when a trade or MatchingEngine::close_bars sees a time at or after their end. The
+// intervals without trades are published as one gap bar spanning all of them: OHLC
+// all at the previous close, zero volume and zero trades. Volume bars hold exactly
+// their volume, a trade crossing the boundary is split over two bars. Tick bars
+// close after a fixed number of trades.
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BarInterval {
+    Time(Duration),
+    Volume(i64),
+    Tick(u64),
+}
+
+impl BarInterval {
+    pub fn seconds(p_seconds: u64) -> Self {
+        BarInterval::Time(Duration::from_secs(p_seconds))
+    }
+
+    pub fn minutes(p_minutes: u64) -> Self {
+        BarInterval::Time(Duration::from_secs(p_minutes * 60))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let valid = match self {
+            BarInterval::Time(duration) => !duration.is_zero(),
+            BarInterval::Volume(volume) => *volume > 0,
+            BarInterval::Tick(trades) => *trades > 0,
+        };
+        if !valid {
+            return Err(format!("Invalid bar interval {:?}", self));
+        }
+        return Ok(());
+    }
+
+    //start of the time bar holding p_time
+    fn bar_start(p_duration: Duration, p_time: SystemTime) -> SystemTime {
+        let since_epoch = p_time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let bar_nanos = p_duration.as_nanos();
+        let start = since_epoch - since_epoch % bar_nanos;
+        return SystemTime::UNIX_EPOCH + Duration::from_nanos(start as u64);
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Bar {
+    #[cfg_attr(feature = "serde", serde(rename = "symbol"))]
+    symbol_: String,
+    #[cfg_attr(feature = "serde", serde(rename = "interval"))]
+    interval_: BarInterval,
+    #[cfg_attr(feature = "serde", serde(rename = "open_time"))]
+    open_time_: SystemTime,
+    //end of the interval for time bars, time of the last trade otherwise
+    #[cfg_attr(feature = "serde", serde(rename = "close_time"))]
+    close_time_: SystemTime,
+    #[cfg_attr(feature = "serde", serde(rename = "open"))]
+    open_: f32,
+    #[cfg_attr(feature = "serde", serde(rename = "high"))]
+    high_: f32,
+    #[cfg_attr(feature = "serde", serde(rename = "low"))]
+    low_: f32,
+    #[cfg_attr(feature = "serde", serde(rename = "close"))]
+    close_: f32,
+    #[cfg_attr(feature = "serde", serde(rename = "volume"))]
+    volume_: i64,
+    #[cfg_attr(feature = "serde", serde(rename = "trade_count"))]
+    trade_count_: u64,
+}
+
+impl Bar {
+    fn gap(
+        p_symbol: &String,
+        p_interval: BarInterval,
+        p_open_time: SystemTime,
+        p_close_time: SystemTime,
+        p_price: f32,
+    ) -> Self {
+        Bar {
+            symbol_: p_symbol.to_owned(),
+            interval_: p_interval,
+            open_time_: p_open_time,
+            close_time_: p_close_time,
+            open_: p_price,
+            high_: p_price,
+            low_: p_price,
+            close_: p_price,
+            volume_: 0,
+            trade_count_: 0,
+        }
+    }
+
+    fn record(&mut self, p_price: f32, p_qty: i32) {
+        if self.trade_count_ == 0 {
+            self.open_ = p_price;
+            self.high_ = p_price;
+            self.low_ = p_price;
+        }
+        self.high_ = self.high_.max(p_price);
+        self.low_ = self.low_.min(p_price);
+        self.close_ = p_price;
+        self.volume_ += p_qty as i64;
+        self.trade_count_ += 1;
+    }
+
+    pub fn symbol(&self) -> &String {
+        &self.symbol_
+    }
+
+    pub fn interval(&self) -> BarInterval {
+        self.interval_
+    }
+
+    pub fn open_time(&self) -> SystemTime {
+        self.open_time_
+    }
+
+    pub fn close_time(&self) -> SystemTime {
+        self.close_time_
+    }
+
+    pub fn open(&self) -> f32 {
+        self.open_
+    }
+
+    pub fn high(&self) -> f32 {
+        self.high_
+    }
+
+    pub fn low(&self) -> f32 {
+        self.low_
+    }
+
+    pub fn close(&self) -> f32 {
+        self.close_
+    }
+
+    pub fn volume(&self) -> i64 {
+        self.volume_
+    }
+
+    pub fn trade_count(&self) -> u64 {
+        self.trade_count_
+    }
+
+    //no trades in the interval
+    pub fn is_gap(&self) -> bool {
+        self.trade_count_ == 0
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct BarAggregator {
+    interval_: BarInterval,
+    //bar being built, for time bars it may be a gap bar still waiting for trades
+    current_: Option<Bar>,
+}
+
+impl BarAggregator {
+    fn new(p_interval: BarInterval) -> Self {
+        BarAggregator {
+            interval_: p_interval,
+            current_: None,
+        }
+    }
+
+    //closes the time bar if it ends at or before p_now. The intervals without trades
+    //until the one holding p_now are closed as a single gap bar, however many
+    fn roll(&mut self, p_now: SystemTime, p_closed: &mut Vec<Bar>) {
+        let duration = match self.interval_ {
+            BarInterval::Time(duration) => duration,
+            _ => return,
+        };
+        match &self.current_ {
+            Some(current) if current.close_time_ <= p_now => (),
+            _ => return,
+        }
+        let open_time = BarInterval::bar_start(duration, p_now);
+        let mut closed = self.current_.take().unwrap();
+        let gap_open_time = if closed.is_gap() {
+            closed.open_time_
+        } else {
+            p_closed.push(closed.clone());
+            closed.close_time_
+        };
+        if gap_open_time < open_time {
+            closed = Bar::gap(
+                &closed.symbol_,
+                self.interval_,
+                gap_open_time,
+                open_time,
+                closed.close_,
+            );
+            p_closed.push(closed.clone());
+        }
+        self.current_ = Some(Bar::gap(
+            &closed.symbol_,
+            self.interval_,
+            open_time,
+            open_time + duration,
+            closed.close_,
+        ));
+    }
+
+    fn record(&mut self, p_trade: &Trade, p_closed: &mut Vec<Bar>) {
+        self.roll(p_trade.time(), p_closed);
+        match self.interval_ {
+            BarInterval::Time(duration) => {
+                let open_time = BarInterval::bar_start(duration, p_trade.time());
+                let current = self.current_.get_or_insert_with(|| {
+                    Bar::gap(
+                        p_trade.symbol(),
+                        self.interval_,
+                        open_time,
+                        open_time + duration,
+                        p_trade.price(),
+                    )
+                });
+                current.record(p_trade.price(), p_trade.qty());
+            }
+            BarInterval::Volume(bar_volume) => {
+                let mut remaining_qty = p_trade.qty() as i64;
+                while remaining_qty > 0 {
+                    let current = self.current_.get_or_insert_with(|| {
+                        Bar::gap(
+                            p_trade.symbol(),
+                            self.interval_,
+                            p_trade.time(),
+                            p_trade.time(),
+                            p_trade.price(),
+                        )
+                    });
+                    let qty = remaining_qty.min(bar_volume - current.volume_);
+                    current.record(p_trade.price(), qty as i32);
+                    current.close_time_ = p_trade.time();
+                    remaining_qty -= qty;
+                    if current.volume_ == bar_volume {
+                        p_closed.push(self.current_.take().unwrap());
+                    }
+                }
+            }
+            BarInterval::Tick(bar_trades) => {
+                let current = self.current_.get_or_insert_with(|| {
+                    Bar::gap(
+                        p_trade.symbol(),
+                        self.interval_,
+                        p_trade.time(),
+                        p_trade.time(),
+                        p_trade.price(),
+                    )
+                });
+                current.record(p_trade.price(), p_trade.qty());
+                current.close_time_ = p_trade.time();
+                if current.trade_count_ == bar_trades {
+                    p_closed.push(self.current_.take().unwrap());
+                }
+            }
+        }
+    }
+}
+
+impl MatchingEngine {
+    //starts aggregating the trades of p_symbol into p_interval bars
+    pub fn add_bar_interval(
+        &mut self,
+        p_symbol: &String,
+        p_interval: BarInterval,
+    ) -> Result<(), String> {
+        p_interval.validate()?;
+        let aggregators = self.bars_by_symbol_.entry(p_symbol.to_owned()).or_default();
+        if aggregators
+            .iter()
+            .any(|aggregator| aggregator.interval_ == p_interval)
+        {
+            return Err(format!(
+                "Bar interval {:?} already set for symbol {}",
+                p_interval, p_symbol
+            ));
+        }
+        aggregators.push(BarAggregator::new(p_interval));
+        return Ok(());
+    }
+
+    //bar of p_interval still open for p_symbol
+    pub fn current_bar(&self, p_symbol: &String, p_interval: BarInterval) -> Option<&Bar> {
+        self.bars_by_symbol_
+            .get(p_symbol)?
+            .iter()
+            .find(|aggregator| aggregator.interval_ == p_interval)?
+            .current_
+            .as_ref()
+    }
+
+    //closes the time bars of all symbols ending at or before the time of the engine
+    //clock, to be called periodically so that quiet symbols still publish their bars
+    pub fn close_bars(&mut self) {
+        let now = self.clock_.now();
+        let mut symbols: Vec<String> = self.bars_by_symbol_.keys().cloned().collect();
+        symbols.sort();
+        for symbol in symbols {
+            let mut closed = Vec::new();
+            for aggregator in self.bars_by_symbol_.get_mut(&symbol).unwrap() {
+                aggregator.roll(now, &mut closed);
+            }
+            self.events_
+                .extend(closed.into_iter().map(EngineEvent::Bar));
+        }
+    }
+
+    pub(crate) fn aggregate_bars(&mut self, p_symbol: &String, p_trades: &[Trade]) {
+        let aggregators = match self.bars_by_symbol_.get_mut(p_symbol) {
+            None => return,
+            Some(aggregators) => aggregators,
+        };
+        let mut closed = Vec::new();
+        for trade in p_trades {
+            for aggregator in aggregators.iter_mut() {
+                aggregator.record(trade, &mut closed);
+            }
+        }
+        self.events_
+            .extend(closed.into_iter().map(EngineEvent::Bar));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn trade(p_seconds: u64, p_price: f32, p_qty: i32) -> Trade {
+        Trade::new(
+            &String::from("REL"),
+            0,
+            p_price,
+            p_qty,
+            "",
+            "",
+            SystemTime::UNIX_EPOCH + Duration::from_secs(p_seconds),
+        )
+    }
+
+    fn bars(p_engine: &mut MatchingEngine) -> Vec<Bar> {
+        p_engine
+            .drain_events()
+            .into_iter()
+            .filter_map(|event| match event {
+                EngineEvent::Bar(bar) => Some(bar),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn time_bars_with_gaps() {
+        let symbol = String::from("REL");
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+        let mut engine = MatchingEngine::new();
+        engine.set_clock(clock.clone());
+        engine
+            .add_bar_interval(&symbol, BarInterval::minutes(1))
+            .unwrap();
+        assert!(engine
+            .add_bar_interval(&symbol, BarInterval::seconds(60))
+            .is_err());
+
+        let trades = [
+            trade(60, 100.0, 10),
+            trade(70, 103.0, 5),
+            trade(90, 99.0, 5),
+            trade(200, 101.0, 20),
+        ];
+        engine.aggregate_bars(&symbol, &trades);
+        let closed = bars(&mut engine);
+        assert_eq!(closed.len(), 2);
+        let first = &closed[0];
+        assert_eq!(
+            (
+                first.open(),
+                first.high(),
+                first.low(),
+                first.close(),
+                first.volume()
+            ),
+            (100.0, 103.0, 99.0, 99.0, 20)
+        );
+        assert_eq!(
+            first.open_time(),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(60)
+        );
+        assert!(closed[1].is_gap());
+        assert_eq!(closed[1].close(), 99.0);
+        assert_eq!(
+            closed[1].open_time(),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(120)
+        );
+
+        let current = engine
+            .current_bar(&symbol, BarInterval::minutes(1))
+            .unwrap();
+        assert_eq!((current.open(), current.volume()), (101.0, 20));
+
+        //nothing traded after the third bar, the clock alone closes it and the next gap
+        clock.set(SystemTime::UNIX_EPOCH + Duration::from_secs(300));
+        engine.close_bars();
+        let closed = bars(&mut engine);
+        assert_eq!(closed.len(), 2);
+        assert_eq!(closed[0].trade_count(), 1);
+        assert!(closed[1].is_gap());
+        assert_eq!(closed[1].close(), 101.0);
+    }
+
+    #[test]
+    fn long_time_gap_published_as_one_bar() {
+        let symbol = String::from("REL");
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+        let mut engine = MatchingEngine::new();
+        engine.set_clock(clock.clone());
+        engine
+            .add_bar_interval(&symbol, BarInterval::seconds(1))
+            .unwrap();
+
+        //about a century of one second intervals between the two trades
+        let far_future = 3_000_000_000;
+        engine.aggregate_bars(&symbol, &[trade(10, 100.0, 10)]);
+        engine.aggregate_bars(&symbol, &[trade(far_future + 5, 101.0, 10)]);
+        let closed = bars(&mut engine);
+        assert_eq!(closed.len(), 2);
+        assert_eq!(closed[0].trade_count(), 1);
+        assert!(closed[1].is_gap());
+        assert_eq!(
+            closed[1].open_time(),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(11)
+        );
+        assert_eq!(
+            closed[1].close_time(),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(far_future + 5)
+        );
+        assert_eq!(closed[1].close(), 100.0);
+
+        //the clock closes the traded bar, then the quiet ones in one gap bar
+        clock.set(SystemTime::UNIX_EPOCH + Duration::from_secs(2 * far_future));
+        engine.close_bars();
+        let closed = bars(&mut engine);
+        assert_eq!(closed.len(), 2);
+        assert_eq!(closed[0].close(), 101.0);
+        assert!(closed[1].is_gap());
+        clock.set(SystemTime::UNIX_EPOCH + Duration::from_secs(3 * far_future));
+        engine.close_bars();
+        let closed = bars(&mut engine);
+        assert_eq!(closed.len(), 1);
+        assert!(closed[0].is_gap());
+        assert_eq!(
+            closed[0].open_time(),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(2 * far_future)
+        );
+    }
+
+    #[test]
+    fn volume_and_tick_bars() {
+        let symbol = String::from("REL");
+        let mut engine = MatchingEngine::new();
+        engine
+            .add_bar_interval(&symbol, BarInterval::Volume(100))
+            .unwrap();
+        engine
+            .add_bar_interval(&symbol, BarInterval::Tick(2))
+            .unwrap();
+        assert!(engine
+            .add_bar_interval(&symbol, BarInterval::Tick(0))
+            .is_err());
+
+        let trades = [
+            trade(1, 100.0, 60),
+            trade(2, 101.0, 60),
+            trade(3, 102.0, 180),
+        ];
+        engine.aggregate_bars(&symbol, &trades);
+        let closed = bars(&mut engine);
+
+        let volume_bars: Vec<&Bar> = closed
+            .iter()
+            .filter(|bar| bar.interval() == BarInterval::Volume(100))
+            .collect();
+        assert_eq!(volume_bars.len(), 3);
+        assert!(volume_bars.iter().all(|bar| bar.volume() == 100));
+        assert_eq!(
+            (volume_bars[0].open(), volume_bars[0].close()),
+            (100.0, 101.0)
+        );
+        assert_eq!(
+            (volume_bars[1].open(), volume_bars[1].close()),
+            (101.0, 102.0)
+        );
+        assert!(engine
+            .current_bar(&symbol, BarInterval::Volume(100))
+            .is_none());
+
+        let tick_bars: Vec<&Bar> = closed
+            .iter()
+            .filter(|bar| bar.interval() == BarInterval::Tick(2))
+            .collect();
+        assert_eq!(tick_bars.len(), 1);
+        assert_eq!(tick_bars[0].volume(), 120);
+        assert_eq!(
+            engine
+                .current_bar(&symbol, BarInterval::Tick(2))
+                .unwrap()
+                .trade_count(),
+            1
+        );
+    }
+}
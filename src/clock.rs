@@ -0,0 +1,205 @@
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::{MatchingEngine, MatchingResult, Order};
+
+//Engine clock
+// Every time the engine hands out comes from its Clock: the acceptance time
+// stamped on each input as its entry time, the fill times, and the time of
+// transitions and auctions. Fills of continuous trading carry the acceptance time
+// of the order that triggered them, so an input and everything it produced share
+// one timestamp. The engine owns its clock, wall clock time unless another one is
+// set, the time an order was built with by its sender plays no part. Accepted
+// inputs are journaled and replicated with their acceptance time, which is what
+// the ReplayClock follows when they are applied again.
+
+pub trait Clock: Debug + Send {
+    fn now(&self) -> SystemTime;
+
+    //sees the time carried by an input or schedule run, only a replay clock follows it
+    fn observe(&mut self, _p_time: SystemTime) {}
+}
+
+//wall clock time
+#[derive(Clone, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+//time set by hand, clones share the same time so a test can keep one to move it
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    now_: Arc<Mutex<SystemTime>>,
+}
+
+impl ManualClock {
+    pub fn new(p_start: SystemTime) -> Self {
+        ManualClock {
+            now_: Arc::new(Mutex::new(p_start)),
+        }
+    }
+
+    pub fn set(&self, p_time: SystemTime) {
+        *self.now_.lock().unwrap_or_else(|error| error.into_inner()) = p_time;
+    }
+
+    pub fn advance(&self, p_duration: Duration) {
+        let mut now = self.now_.lock().unwrap_or_else(|error| error.into_inner());
+        *now += p_duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.now_.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+//time of the latest input, never going back. What an input journaled, replicated
+//or sequenced with its acceptance time is applied with
+#[derive(Clone, Debug)]
+pub struct ReplayClock {
+    now_: SystemTime,
+}
+
+impl Default for ReplayClock {
+    fn default() -> Self {
+        ReplayClock {
+            now_: SystemTime::UNIX_EPOCH,
+        }
+    }
+}
+
+impl Clock for ReplayClock {
+    fn now(&self) -> SystemTime {
+        self.now_
+    }
+
+    fn observe(&mut self, p_time: SystemTime) {
+        if p_time > self.now_ {
+            self.now_ = p_time;
+        }
+    }
+}
+
+impl MatchingEngine {
+    pub fn set_clock(&mut self, p_clock: impl Clock + 'static) {
+        self.clock_ = Box::new(p_clock);
+    }
+
+    //current time of the engine clock
+    pub fn engine_time(&self) -> SystemTime {
+        self.clock_.now()
+    }
+
+    pub(crate) fn advance_time(&mut self, p_time: SystemTime) {
+        self.clock_.observe(p_time);
+    }
+
+    //runs p_apply with a ReplayClock standing at p_time, the acceptance time of a
+    //journaled, replicated or sequenced input, then gives the engine its own clock back
+    pub(crate) fn at_input_time<T>(
+        &mut self,
+        p_time: SystemTime,
+        p_apply: impl FnOnce(&mut MatchingEngine) -> T,
+    ) -> T {
+        let mut replay_clock = ReplayClock::default();
+        replay_clock.observe(p_time);
+        let own_clock = std::mem::replace(&mut self.clock_, Box::new(replay_clock));
+        let result = p_apply(self);
+        self.clock_ = own_clock;
+        return result;
+    }
+
+    //stamps p_order with its acceptance time, whatever time the sender put in
+    pub(crate) fn stamp_acceptance(&mut self, p_order: &mut Order) {
+        p_order.entry_time_ = self.clock_.now();
+    }
+
+    pub(crate) fn stamp_fills(p_result: &mut Option<MatchingResult>, p_time: SystemTime) {
+        if let Some(match_result) = p_result {
+            for fill in &mut match_result.fills_ {
+                fill.time_ = p_time;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::journal::Journal;
+    use crate::test_order;
+    use crate::{process_event, EventType, OrderSide};
+
+    fn order(p_id: &str, p_side: OrderSide, p_qty: i32, p_time: SystemTime) -> Order {
+        test_order(p_id, p_side, p_qty, 100.0).with_time(p_time)
+    }
+
+    #[test]
+    fn manual_clock_stamps_acceptance_and_fills() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let clock = ManualClock::new(start);
+        let mut engine = MatchingEngine::new();
+        engine.set_clock(clock.clone());
+
+        //whatever time the sender put in is replaced
+        let mut buy = order("1", OrderSide::Buy, 100, SystemTime::now());
+        process_event(EventType::New, &mut buy, &mut engine).unwrap();
+        assert_eq!(buy.entry_time_, start);
+
+        clock.advance(Duration::from_millis(250));
+        let mut sell = order("2", OrderSide::Sell, 40, SystemTime::UNIX_EPOCH);
+        let result = process_event(EventType::New, &mut sell, &mut engine)
+            .unwrap()
+            .unwrap();
+        let fill_time = start + Duration::from_millis(250);
+        assert_eq!(result.fills_[0].time(), fill_time);
+        assert_eq!(engine.engine_time(), fill_time);
+        assert_eq!(
+            engine
+                .trade_statistics(&String::from("REL"))
+                .unwrap()
+                .volume(),
+            40
+        );
+    }
+
+    #[test]
+    fn replay_follows_acceptance_times() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let path = std::env::temp_dir().join(format!("clock_{}.journal", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        //the engine time is its own, not the one the sender put in
+        let before = SystemTime::now();
+        let mut engine = MatchingEngine::new();
+        let mut buy = order("1", OrderSide::Buy, 100, start);
+        process_event(EventType::New, &mut buy, &mut engine).unwrap();
+        assert!(buy.entry_time_ >= before);
+
+        let clock = ManualClock::new(start);
+        let mut primary = MatchingEngine::new();
+        primary.set_clock(clock.clone());
+        primary.attach_journal(Journal::open(&path).unwrap());
+        let mut buy = order("1", OrderSide::Buy, 100, SystemTime::now());
+        process_event(EventType::New, &mut buy, &mut primary).unwrap();
+        clock.advance(Duration::from_secs(5));
+        let mut sell = order("2", OrderSide::Sell, 40, SystemTime::now());
+        process_event(EventType::New, &mut sell, &mut primary).unwrap();
+
+        //each input is applied again at the time it was accepted at, then the engine
+        //is back on its own clock
+        let mut replayed = MatchingEngine::new();
+        replayed.replay(&Journal::read(&path).unwrap());
+        assert_eq!(replayed.drain_events(), primary.drain_events());
+        assert_eq!(replayed.state_hash(), primary.state_hash());
+        assert!(replayed.engine_time() >= before);
+        let _ = std::fs::remove_file(&path);
+    }
+}
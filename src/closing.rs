@@ -0,0 +1,292 @@
+use std::time::Duration;
+
+use crate::events::EngineEvent;
+use crate::session::{time_of_day, TradingState};
+use crate::{MatchingEngine, MatchingResult, Order, OrderSide, OrderType};
+
+//On-close orders
+// Market-on-close and limit-on-close orders never take part in continuous trading.
+// They wait in a closing book per symbol and only join the book when the closing
+// auction uncrosses, MOC orders at any price and LOC orders at their limit.
+// Entry, replace and cancel are only accepted before the symbol's cut-off time,
+// and whatever is left after the closing uncross is cancelled.
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ClosingBook {
+    // in arrival order
+    pub(crate) orders_: Vec<Order>,
+    // time of day in UTC after which on-close orders can not be entered or changed
+    pub(crate) cutoff_: Option<Duration>,
+}
+
+impl ClosingBook {
+    fn is_after_cutoff(&self, p_order: &Order) -> bool {
+        match self.cutoff_ {
+            None => false,
+            Some(cutoff) => time_of_day(p_order.entry_time_) >= cutoff,
+        }
+    }
+
+    fn position(&self, p_order: &Order) -> Option<usize> {
+        self.orders_
+            .iter()
+            .position(|order| order.id_ == p_order.id_)
+    }
+}
+
+impl Order {
+    pub fn is_on_close(&self) -> bool {
+        matches!(self.type_, OrderType::Moc | OrderType::Loc)
+    }
+}
+
+impl MatchingEngine {
+    pub fn set_closing_cutoff(&mut self, p_symbol: &String, p_time_of_day: Duration) {
+        self.closing_book_by_symbol_
+            .entry(p_symbol.to_owned())
+            .or_default()
+            .cutoff_ = Some(p_time_of_day);
+    }
+
+    pub fn on_close_order_count(&self, p_symbol: &String) -> usize {
+        match self.closing_book_by_symbol_.get(p_symbol) {
+            None => 0,
+            Some(closing_book) => closing_book.orders_.len(),
+        }
+    }
+
+    //true when p_order refers to an order waiting in the closing book
+    pub(crate) fn is_resting_on_close(&self, p_order: &Order) -> bool {
+        match self.closing_book_by_symbol_.get(&p_order.symbol_) {
+            None => false,
+            Some(closing_book) => closing_book.position(p_order).is_some(),
+        }
+    }
+
+    pub(crate) fn accept_on_close(&mut self, p_order: &Order) -> Result<(), String> {
+        let trading_state = self.trading_state(&p_order.symbol_);
+        if !matches!(
+            trading_state,
+            TradingState::PreOpen | TradingState::OpeningAuction | TradingState::Continuous
+        ) {
+            return Err(format!(
+                "On-close order {} is not accepted while {} is {:?}",
+                p_order.id_, p_order.symbol_, trading_state
+            ));
+        }
+
+        let closing_book = self
+            .closing_book_by_symbol_
+            .entry(p_order.symbol_.to_owned())
+            .or_default();
+        if closing_book.is_after_cutoff(p_order) {
+            return Err(format!(
+                "On-close order {} received after the cut-off of {}",
+                p_order.id_, p_order.symbol_
+            ));
+        }
+        closing_book.orders_.push(p_order.to_owned());
+        println!("Order id {:?} added into closing book", p_order.id_);
+        return Ok(());
+    }
+
+    //replace keeps the order's place in the closing book
+    pub(crate) fn replace_on_close(&mut self, p_order: &Order) -> Result<(), String> {
+        if !p_order.is_on_close() {
+            return Err(format!(
+                "On-close order {} can not be replaced by a {:?} order",
+                p_order.id_, p_order.type_
+            ));
+        }
+        let closing_book = self.closing_book_mut(p_order)?;
+        let position = closing_book.position(p_order).unwrap_or_default();
+        closing_book.orders_[position] = p_order.to_owned();
+        return Ok(());
+    }
+
+    pub(crate) fn cancel_on_close(&mut self, p_order: &Order) -> Result<(), String> {
+        let closing_book = self.closing_book_mut(p_order)?;
+        if let Some(position) = closing_book.position(p_order) {
+            closing_book.orders_.remove(position);
+        }
+        return Ok(());
+    }
+
+    fn closing_book_mut(&mut self, p_order: &Order) -> Result<&mut ClosingBook, String> {
+        let closing_book = match self.closing_book_by_symbol_.get_mut(&p_order.symbol_) {
+            None => return Err(format!("No on-close orders for symbol {}", p_order.symbol_)),
+            Some(closing_book) => closing_book,
+        };
+        if closing_book.is_after_cutoff(p_order) {
+            return Err(format!(
+                "On-close order {} can not be changed after the cut-off of {}",
+                p_order.id_, p_order.symbol_
+            ));
+        }
+        return Ok(closing_book);
+    }
+
+    //closing uncross, on-close orders join the book and unexecuted ones are cancelled
+    pub(crate) fn uncross_closing(&mut self, p_symbol: &String) -> Option<MatchingResult> {
+        let on_close_orders = match self.closing_book_by_symbol_.get_mut(p_symbol) {
+            None => Vec::new(),
+            Some(closing_book) => std::mem::take(&mut closing_book.orders_),
+        };
+        if !self.contains(p_symbol) {
+            self.add_order_book(p_symbol);
+        }
+        let order_book = self.get_book_by_symbol(p_symbol)?;
+
+        for on_close_order in &on_close_orders {
+            let mut auction_order = on_close_order.to_owned();
+            if let OrderType::Moc = auction_order.type_ {
+                auction_order.price_ = match auction_order.side_ {
+                    OrderSide::Buy => f32::INFINITY,
+                    OrderSide::Sell => 0.0,
+                };
+            }
+            order_book.add_order(&mut auction_order);
+        }
+
+        let uncross_result = order_book.uncross();
+
+        let mut unexecuted_order_ids = Vec::new();
+        for on_close_order in &on_close_orders {
+            if order_book.remove_order_by_id(on_close_order) {
+                unexecuted_order_ids.push(on_close_order.id_.to_owned());
+            }
+        }
+        for order_id in unexecuted_order_ids {
+            self.events_.push(EngineEvent::OrderCancelled {
+                symbol_: p_symbol.to_owned(),
+                order_id_: order_id,
+                reason_: String::from("on-close order not executed in the closing auction"),
+            });
+        }
+        return uncross_result;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::test_order;
+    use crate::{process_event, EventType};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    fn order(
+        p_id: &str,
+        p_side: OrderSide,
+        p_type: OrderType,
+        p_qty: i32,
+        p_price: f32,
+        p_time: SystemTime,
+    ) -> Order {
+        test_order(p_id, p_side, p_qty, p_price)
+            .with_type(p_type)
+            .with_time(p_time)
+    }
+
+    #[test]
+    fn on_close_orders_execute_at_closing_price() {
+        let symbol = String::from("REL");
+        let morning = UNIX_EPOCH + Duration::from_secs(DAY * 20000 + 10 * 3600);
+        let mut engine = MatchingEngine::new();
+        engine.set_clock(ManualClock::new(morning));
+        engine.set_closing_cutoff(&symbol, Duration::from_secs(15 * 3600 + 50 * 60));
+
+        let mut moc_buy = order("1", OrderSide::Buy, OrderType::Moc, 200, 0.0, morning);
+        let mut loc_sell = order("2", OrderSide::Sell, OrderType::Loc, 100, 99.0, morning);
+        let mut loc_sell_high = order("3", OrderSide::Sell, OrderType::Loc, 100, 105.0, morning);
+        let mut limit_sell = order("4", OrderSide::Sell, OrderType::Limit, 100, 100.0, morning);
+        let mut limit_buy = order("5", OrderSide::Buy, OrderType::Limit, 100, 98.0, morning);
+        for on_close in [&mut moc_buy, &mut loc_sell, &mut loc_sell_high] {
+            let result = process_event(EventType::New, on_close, &mut engine);
+            assert!(result.unwrap().is_none());
+        }
+        process_event(EventType::New, &mut limit_sell, &mut engine).unwrap();
+        process_event(EventType::New, &mut limit_buy, &mut engine).unwrap();
+        assert_eq!(engine.on_close_order_count(&symbol), 3);
+        assert_eq!(engine.order_book_by_symbol_[&symbol].asks_.len(), 1);
+
+        engine
+            .transition(&symbol, TradingState::ClosingAuction)
+            .unwrap();
+        let result = engine.transition(&symbol, TradingState::Closed).unwrap();
+        let result = result.unwrap();
+        assert_eq!(result.executed_qty_, 200);
+        assert_eq!(result.executed_price_, 100.0);
+        assert_eq!(engine.on_close_order_count(&symbol), 0);
+
+        //the LOC sell above the closing price is cancelled, the limit buy stays
+        let cancelled_order_ids: Vec<String> = engine
+            .drain_events()
+            .into_iter()
+            .filter_map(|event| match event {
+                EngineEvent::OrderCancelled { order_id_, .. } => Some(order_id_),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(cancelled_order_ids, vec!["3"]);
+        assert_eq!(engine.order_book_by_symbol_[&symbol].bids_.len(), 1);
+        assert!(engine.order_book_by_symbol_[&symbol].asks_.is_empty());
+    }
+
+    #[test]
+    fn cutoff_restricts_entry_and_cancel() {
+        let symbol = String::from("REL");
+        let midnight = UNIX_EPOCH + Duration::from_secs(DAY * 20000);
+        let before = midnight + Duration::from_secs(15 * 3600);
+        let after = midnight + Duration::from_secs(15 * 3600 + 55 * 60);
+        let clock = ManualClock::new(before);
+        let mut engine = MatchingEngine::new();
+        engine.set_clock(clock.clone());
+        engine.set_closing_cutoff(&symbol, Duration::from_secs(15 * 3600 + 50 * 60));
+
+        let mut moc = order("1", OrderSide::Buy, OrderType::Moc, 100, 0.0, before);
+        process_event(EventType::New, &mut moc, &mut engine).unwrap();
+        let mut cancel = order("1", OrderSide::Buy, OrderType::Moc, 100, 0.0, before);
+        assert!(process_event(EventType::Cxl, &mut cancel, &mut engine).is_ok());
+        let mut moc = order("2", OrderSide::Buy, OrderType::Moc, 100, 0.0, before);
+        process_event(EventType::New, &mut moc, &mut engine).unwrap();
+
+        //the time of the orders is replaced by the one of the engine clock
+        clock.set(after);
+        let mut late = order("3", OrderSide::Buy, OrderType::Moc, 100, 0.0, before);
+        assert!(process_event(EventType::New, &mut late, &mut engine).is_err());
+        let mut cancel = order("2", OrderSide::Buy, OrderType::Moc, 100, 0.0, before);
+        assert!(process_event(EventType::Cxl, &mut cancel, &mut engine).is_err());
+        assert_eq!(engine.on_close_order_count(&symbol), 1);
+    }
+
+    #[test]
+    fn replace_can_not_change_to_or_from_on_close() {
+        let symbol = String::from("REL");
+        let morning = UNIX_EPOCH + Duration::from_secs(DAY * 20000 + 10 * 3600);
+        let mut engine = MatchingEngine::new();
+        engine.set_clock(ManualClock::new(morning));
+        engine.set_closing_cutoff(&symbol, Duration::from_secs(15 * 3600 + 50 * 60));
+
+        let mut limit_buy = order("1", OrderSide::Buy, OrderType::Limit, 100, 99.0, morning);
+        process_event(EventType::New, &mut limit_buy, &mut engine).unwrap();
+        let mut moc_buy = order("2", OrderSide::Buy, OrderType::Moc, 100, 0.0, morning);
+        process_event(EventType::New, &mut moc_buy, &mut engine).unwrap();
+
+        //a resting limit order can not move into the closing book
+        let mut to_loc = order("1", OrderSide::Buy, OrderType::Loc, 100, 99.0, morning);
+        assert!(process_event(EventType::Rpl, &mut to_loc, &mut engine).is_err());
+        //and an on-close order can not move into continuous trading
+        let mut to_limit = order("2", OrderSide::Buy, OrderType::Limit, 100, 99.0, morning);
+        assert!(process_event(EventType::Rpl, &mut to_limit, &mut engine).is_err());
+
+        assert_eq!(engine.on_close_order_count(&symbol), 1);
+        let bids = &engine.order_book_by_symbol_[&symbol].bids_;
+        let resting: Vec<&Order> = bids.iter().flat_map(|level| level.orders_.iter()).collect();
+        assert_eq!(resting.len(), 1);
+        assert!(!resting[0].is_on_close());
+        assert_eq!(resting[0].price_, 99.0);
+    }
+}
@@ -0,0 +1,268 @@
+use std::fmt::Debug;
+use std::time::SystemTime;
+
+use crate::journal::{
+    event_type_from_byte, event_type_to_byte, put_order, put_string, put_time, Reader,
+};
+use crate::session::TradingState;
+use crate::snapshot::{put_orders, state_from_byte, state_to_byte};
+use crate::{apply_event, EventType, MatchingEngine, MatchingResult, Order};
+
+//Engine commands
+// Orders are not the only inputs: halts and resumes, manual and scheduled
+// transitions, the price band timer, the end of day purge and the GTC import
+// change the engine too. The methods standing for them go through an
+// EngineCommand, journaled, numbered and folded into the state hash like an order
+// and applied at its acceptance time, so a journal replay or a backup repeats
+// them with the same outcome. A command carries everything it needs to be applied
+// again, the GTC import the orders read from its file.
+//
+// Layout of an input: the event type (1) and the order of an order input, or 3,
+// the acceptance time and the command kind (1) followed by its fields.
+
+const COMMAND_INPUT: u8 = 3;
+
+const HALT: u8 = 0;
+const HALT_ALL: u8 = 1;
+const RESUME: u8 = 2;
+const RESUME_ALL: u8 = 3;
+const TRANSITION: u8 = 4;
+const APPLY_SCHEDULE: u8 = 5;
+const CHECK_PRICE_BANDS: u8 = 6;
+const PURGE_DAY_ORDERS: u8 = 7;
+const IMPORT_GTC_ORDERS: u8 = 8;
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum EngineCommand {
+    Halt {
+        #[cfg_attr(feature = "serde", serde(rename = "symbol"))]
+        symbol_: String,
+        #[cfg_attr(feature = "serde", serde(rename = "reason"))]
+        reason_: String,
+    },
+    HaltAll {
+        #[cfg_attr(feature = "serde", serde(rename = "reason"))]
+        reason_: String,
+    },
+    Resume {
+        #[cfg_attr(feature = "serde", serde(rename = "symbol"))]
+        symbol_: String,
+        #[cfg_attr(feature = "serde", serde(rename = "state"))]
+        state_: TradingState,
+    },
+    ResumeAll {
+        #[cfg_attr(feature = "serde", serde(rename = "state"))]
+        state_: TradingState,
+    },
+    Transition {
+        #[cfg_attr(feature = "serde", serde(rename = "symbol"))]
+        symbol_: String,
+        #[cfg_attr(feature = "serde", serde(rename = "state"))]
+        state_: TradingState,
+    },
+    ApplySchedule {
+        #[cfg_attr(feature = "serde", serde(rename = "now"))]
+        now_: SystemTime,
+    },
+    CheckPriceBands {
+        #[cfg_attr(feature = "serde", serde(rename = "now"))]
+        now_: SystemTime,
+    },
+    PurgeDayOrders,
+    ImportGtcOrders {
+        #[cfg_attr(feature = "serde", serde(rename = "orders"))]
+        orders_: Vec<Order>,
+    },
+}
+
+//what the engine is given, an order with its event type or a command with the
+//time it was accepted at
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum EngineInput {
+    Order {
+        #[cfg_attr(feature = "serde", serde(rename = "event_type"))]
+        event_type_: EventType,
+        #[cfg_attr(feature = "serde", serde(rename = "order"))]
+        order_: Order,
+    },
+    Command {
+        #[cfg_attr(feature = "serde", serde(rename = "time"))]
+        time_: SystemTime,
+        #[cfg_attr(feature = "serde", serde(rename = "command"))]
+        command_: EngineCommand,
+    },
+}
+
+pub(crate) fn put_order_input(p_buffer: &mut Vec<u8>, p_event_type: EventType, p_order: &Order) {
+    p_buffer.push(event_type_to_byte(p_event_type));
+    put_order(p_buffer, p_order);
+}
+
+pub(crate) fn put_command_input(
+    p_buffer: &mut Vec<u8>,
+    p_time: SystemTime,
+    p_command: &EngineCommand,
+) {
+    p_buffer.push(COMMAND_INPUT);
+    put_time(p_buffer, p_time);
+    match p_command {
+        EngineCommand::Halt { symbol_, reason_ } => {
+            p_buffer.push(HALT);
+            put_string(p_buffer, symbol_);
+            put_string(p_buffer, reason_);
+        }
+        EngineCommand::HaltAll { reason_ } => {
+            p_buffer.push(HALT_ALL);
+            put_string(p_buffer, reason_);
+        }
+        EngineCommand::Resume { symbol_, state_ } => {
+            p_buffer.push(RESUME);
+            put_string(p_buffer, symbol_);
+            p_buffer.push(state_to_byte(*state_));
+        }
+        EngineCommand::ResumeAll { state_ } => {
+            p_buffer.push(RESUME_ALL);
+            p_buffer.push(state_to_byte(*state_));
+        }
+        EngineCommand::Transition { symbol_, state_ } => {
+            p_buffer.push(TRANSITION);
+            put_string(p_buffer, symbol_);
+            p_buffer.push(state_to_byte(*state_));
+        }
+        EngineCommand::ApplySchedule { now_ } => {
+            p_buffer.push(APPLY_SCHEDULE);
+            put_time(p_buffer, *now_);
+        }
+        EngineCommand::CheckPriceBands { now_ } => {
+            p_buffer.push(CHECK_PRICE_BANDS);
+            put_time(p_buffer, *now_);
+        }
+        EngineCommand::PurgeDayOrders => p_buffer.push(PURGE_DAY_ORDERS),
+        EngineCommand::ImportGtcOrders { orders_ } => {
+            p_buffer.push(IMPORT_GTC_ORDERS);
+            put_orders(p_buffer, orders_.iter());
+        }
+    }
+}
+
+pub(crate) fn put_input(p_buffer: &mut Vec<u8>, p_input: &EngineInput) {
+    match p_input {
+        EngineInput::Order {
+            event_type_,
+            order_,
+        } => put_order_input(p_buffer, *event_type_, order_),
+        EngineInput::Command { time_, command_ } => put_command_input(p_buffer, *time_, command_),
+    }
+}
+
+impl Reader<'_> {
+    pub(crate) fn input(&mut self) -> Result<EngineInput, String> {
+        let kind = self.u8()?;
+        if kind != COMMAND_INPUT {
+            return Ok(EngineInput::Order {
+                event_type_: event_type_from_byte(kind)?,
+                order_: self.order()?,
+            });
+        }
+        let time = self.time()?;
+        let command = match self.u8()? {
+            HALT => EngineCommand::Halt {
+                symbol_: self.string()?,
+                reason_: self.string()?,
+            },
+            HALT_ALL => EngineCommand::HaltAll {
+                reason_: self.string()?,
+            },
+            RESUME => EngineCommand::Resume {
+                symbol_: self.string()?,
+                state_: state_from_byte(self.u8()?)?,
+            },
+            RESUME_ALL => EngineCommand::ResumeAll {
+                state_: state_from_byte(self.u8()?)?,
+            },
+            TRANSITION => EngineCommand::Transition {
+                symbol_: self.string()?,
+                state_: state_from_byte(self.u8()?)?,
+            },
+            APPLY_SCHEDULE => EngineCommand::ApplySchedule { now_: self.time()? },
+            CHECK_PRICE_BANDS => EngineCommand::CheckPriceBands { now_: self.time()? },
+            PURGE_DAY_ORDERS => EngineCommand::PurgeDayOrders,
+            IMPORT_GTC_ORDERS => EngineCommand::ImportGtcOrders {
+                orders_: self.orders()?,
+            },
+            kind => return Err(format!("Unknown engine command {}", kind)),
+        };
+        return Ok(EngineInput::Command {
+            time_: time,
+            command_: command,
+        });
+    }
+}
+
+impl MatchingEngine {
+    //applies p_command through the method it stands for, returns the results of
+    //the uncrosses and of the queued orders it released
+    pub fn apply_command(
+        &mut self,
+        p_command: &EngineCommand,
+    ) -> Result<Vec<MatchingResult>, String> {
+        match p_command {
+            EngineCommand::Halt { symbol_, reason_ } => {
+                self.halt(symbol_, reason_).map(|_| Vec::new())
+            }
+            EngineCommand::HaltAll { reason_ } => self.halt_all(reason_).map(|_| Vec::new()),
+            EngineCommand::Resume { symbol_, state_ } => self.resume(symbol_, *state_),
+            EngineCommand::ResumeAll { state_ } => self.resume_all(*state_),
+            EngineCommand::Transition { symbol_, state_ } => self
+                .transition(symbol_, *state_)
+                .map(|uncross_result| uncross_result.into_iter().collect()),
+            EngineCommand::ApplySchedule { now_ } => self.apply_schedule(*now_),
+            EngineCommand::CheckPriceBands { now_ } => {
+                self.check_price_bands(*now_).map(|_| Vec::new())
+            }
+            EngineCommand::PurgeDayOrders => self.purge_day_orders().map(|_| Vec::new()),
+            EngineCommand::ImportGtcOrders { orders_ } => {
+                self.load_gtc_orders(orders_).map(|_| Vec::new())
+            }
+        }
+    }
+
+    //applies p_input again at the time it was accepted at, what a journal replay
+    //and a backup do. An attached journal records it again
+    pub(crate) fn apply_input(&mut self, p_input: &EngineInput) -> Result<(), String> {
+        match p_input {
+            EngineInput::Order {
+                event_type_,
+                order_,
+            } => {
+                let mut order = order_.to_owned();
+                self.at_input_time(order.entry_time_, |engine| {
+                    apply_event(*event_type_, &mut order, engine)
+                })?;
+            }
+            EngineInput::Command { time_, command_ } => {
+                self.at_input_time(*time_, |engine| engine.apply_command(command_))?;
+            }
+        }
+        return Ok(());
+    }
+
+    //journals p_command, numbers it and runs p_apply at its acceptance time, the
+    //result and the engine events folded into the state hash
+    pub(crate) fn process_command<T: Debug>(
+        &mut self,
+        p_command: &EngineCommand,
+        p_apply: impl FnOnce(&mut MatchingEngine) -> Result<T, String>,
+    ) -> Result<T, String> {
+        let time = self.clock_.now();
+        self.journal_command(time, p_command)?;
+        return self.at_input_time(time, |engine| {
+            let first_event = engine.accept_command(time, p_command);
+            let result = p_apply(engine);
+            engine.hash_outputs(first_event, &result);
+            result
+        });
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Condvar, Mutex, TryLockError};
+use std::time::{Duration, Instant};
+
+use crate::depth_feed::DepthSnapshot;
+use crate::events::EngineEvent;
+use crate::market_data::BookDepth;
+
+//Conflated market data
+// The Conflator sits on the matching thread and folds the depth updates of the
+// engine events into a book per symbol. What a consumer gets is only the latest
+// top levels of every symbol which changed since it last looked, however many
+// updates that was. Handing over never waits for the consumer: when the shared
+// slot is busy the Conflator keeps the pending books and retries with the next
+// events or on flush.
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConflatedDepth {
+    symbol_: String,
+    //depth update sequence the book is at
+    seq_: u64,
+    depth_: BookDepth,
+    //depth updates folded into this one since the previous delivery
+    conflated_updates_: u64,
+}
+
+impl ConflatedDepth {
+    pub fn symbol(&self) -> &String {
+        &self.symbol_
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq_
+    }
+
+    pub fn depth(&self) -> &BookDepth {
+        &self.depth_
+    }
+
+    pub fn conflated_updates(&self) -> u64 {
+        self.conflated_updates_
+    }
+
+    //newer replaces self, keeping count of what was skipped
+    fn merge(&mut self, p_newer: ConflatedDepth) {
+        let conflated_updates = self.conflated_updates_ + p_newer.conflated_updates_;
+        *self = p_newer;
+        self.conflated_updates_ = conflated_updates;
+    }
+}
+
+impl BookDepth {
+    fn top(&self, p_levels: usize) -> BookDepth {
+        BookDepth {
+            bids_: self.bids_.iter().take(p_levels).cloned().collect(),
+            asks_: self.asks_.iter().take(p_levels).cloned().collect(),
+        }
+    }
+}
+
+//latest depth per symbol not yet taken by the consumer
+type ConflationSlot = Arc<(Mutex<BTreeMap<String, ConflatedDepth>>, Condvar)>;
+
+//producer side, fed with the engine events on the matching thread
+pub struct Conflator {
+    levels_: usize,
+    books_: HashMap<String, DepthSnapshot>,
+    //changed since the last successful hand over
+    pending_: BTreeMap<String, ConflatedDepth>,
+    slot_: ConflationSlot,
+}
+
+//consumer side, may live on any thread
+pub struct ConflatedFeed {
+    slot_: ConflationSlot,
+    last_tick_: Option<Instant>,
+}
+
+//conflates to the top p_levels of each side
+pub fn conflation(p_levels: usize) -> (Conflator, ConflatedFeed) {
+    let slot: ConflationSlot = Arc::new((Mutex::new(BTreeMap::new()), Condvar::new()));
+    let conflator = Conflator {
+        levels_: p_levels,
+        books_: HashMap::new(),
+        pending_: BTreeMap::new(),
+        slot_: slot.clone(),
+    };
+    let feed = ConflatedFeed {
+        slot_: slot,
+        last_tick_: None,
+    };
+    return (conflator, feed);
+}
+
+impl Conflator {
+    //starts p_symbol from a snapshot instead of an empty book, for a conflator
+    //attached after the symbol started trading
+    pub fn seed(&mut self, p_snapshot: DepthSnapshot) {
+        self.books_
+            .insert(p_snapshot.symbol().to_owned(), p_snapshot);
+    }
+
+    //number of symbols waiting for the slot
+    pub fn pending(&self) -> usize {
+        self.pending_.len()
+    }
+
+    pub fn on_events(&mut self, p_events: &[EngineEvent]) -> Result<(), String> {
+        let mut changed: BTreeMap<String, u64> = BTreeMap::new();
+        for event in p_events {
+            if let EngineEvent::DepthUpdate(update) = event {
+                let book = self
+                    .books_
+                    .entry(update.symbol().to_owned())
+                    .or_insert_with(|| DepthSnapshot::empty(update.symbol()));
+                if update.seq() <= book.seq() {
+                    continue;
+                }
+                book.apply(update)?;
+                *changed.entry(update.symbol().to_owned()).or_default() += 1;
+            }
+        }
+
+        for (symbol, updates) in changed {
+            let book = &self.books_[&symbol];
+            let conflated = ConflatedDepth {
+                symbol_: symbol.to_owned(),
+                seq_: book.seq(),
+                depth_: book.depth().top(self.levels_),
+                conflated_updates_: updates,
+            };
+            match self.pending_.get_mut(&symbol) {
+                None => {
+                    self.pending_.insert(symbol, conflated);
+                }
+                Some(pending) => pending.merge(conflated),
+            }
+        }
+        self.flush();
+        return Ok(());
+    }
+
+    //hands the pending books over unless the consumer holds the slot,
+    //returns true when nothing is left pending
+    pub fn flush(&mut self) -> bool {
+        if self.pending_.is_empty() {
+            return true;
+        }
+        let (slot, ready) = &*self.slot_;
+        let mut latest = match slot.try_lock() {
+            Ok(latest) => latest,
+            Err(TryLockError::WouldBlock) => return false,
+            Err(TryLockError::Poisoned(error)) => error.into_inner(),
+        };
+        for (symbol, conflated) in std::mem::take(&mut self.pending_) {
+            match latest.get_mut(&symbol) {
+                None => {
+                    latest.insert(symbol, conflated);
+                }
+                Some(previous) => previous.merge(conflated),
+            }
+        }
+        ready.notify_all();
+        return true;
+    }
+}
+
+impl ConflatedFeed {
+    //takes whatever changed since the last call, never waits
+    pub fn poll(&mut self) -> Vec<ConflatedDepth> {
+        let (slot, _) = &*self.slot_;
+        let mut latest = slot.lock().unwrap_or_else(|error| error.into_inner());
+        return std::mem::take(&mut *latest).into_values().collect();
+    }
+
+    //waits up to p_timeout for something to change
+    pub fn wait(&mut self, p_timeout: Duration) -> Vec<ConflatedDepth> {
+        let (slot, ready) = &*self.slot_;
+        let latest = slot.lock().unwrap_or_else(|error| error.into_inner());
+        let (mut latest, _) = ready
+            .wait_timeout_while(latest, p_timeout, |latest| latest.is_empty())
+            .unwrap_or_else(|error| error.into_inner());
+        return std::mem::take(&mut *latest).into_values().collect();
+    }
+
+    //polls at a fixed rate: sleeps until p_interval after the previous tick
+    pub fn next_tick(&mut self, p_interval: Duration) -> Vec<ConflatedDepth> {
+        let now = Instant::now();
+        let tick = match self.last_tick_ {
+            Some(last_tick) if last_tick + p_interval > now => last_tick + p_interval,
+            _ => now,
+        };
+        std::thread::sleep(tick - now);
+        self.last_tick_ = Some(tick);
+        return self.poll();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_order;
+    use crate::{process_event, EventType, MatchingEngine, Order, OrderSide};
+
+    fn order(p_id: &str, p_symbol: &str, p_side: OrderSide, p_qty: i32, p_price: f32) -> Order {
+        test_order(p_id, p_side, p_qty, p_price).with_symbol(p_symbol)
+    }
+
+    #[test]
+    fn latest_top_levels_per_symbol() {
+        let mut engine = MatchingEngine::new();
+        let (mut conflator, mut feed) = conflation(2);
+        let orders = [
+            order("1", "REL", OrderSide::Buy, 100, 99.0),
+            order("2", "REL", OrderSide::Buy, 100, 98.0),
+            order("3", "REL", OrderSide::Buy, 100, 97.0),
+            order("4", "TCS", OrderSide::Sell, 10, 500.0),
+            order("5", "REL", OrderSide::Sell, 40, 99.0),
+            order("6", "REL", OrderSide::Sell, 10, 101.0),
+        ];
+        for mut order in orders {
+            process_event(EventType::New, &mut order, &mut engine).unwrap();
+            conflator.on_events(&engine.drain_events()).unwrap();
+        }
+
+        let conflated = feed.poll();
+        assert_eq!(conflated.len(), 2);
+        let rel = &conflated[0];
+        assert_eq!(rel.symbol(), "REL");
+        assert_eq!(rel.conflated_updates(), 5);
+        assert_eq!(rel.depth(), &engine.depth(&String::from("REL"), 2).unwrap());
+        assert_eq!(conflated[1].symbol(), "TCS");
+        assert!(feed.poll().is_empty());
+    }
+
+    #[test]
+    fn busy_consumer_does_not_block() {
+        let symbol = String::from("REL");
+        let mut engine = MatchingEngine::new();
+        let (mut conflator, mut feed) = conflation(1);
+
+        //consumer in the middle of reading
+        let slot = feed.slot_.clone();
+        let guard = slot.0.lock().unwrap();
+        for (id, price) in [("1", 99.0), ("2", 100.0)] {
+            let mut buy = order(id, "REL", OrderSide::Buy, 100, price);
+            process_event(EventType::New, &mut buy, &mut engine).unwrap();
+            conflator.on_events(&engine.drain_events()).unwrap();
+        }
+        assert_eq!(conflator.pending(), 1);
+        drop(guard);
+
+        assert!(conflator.flush());
+        let conflated = feed.wait(Duration::from_secs(1));
+        assert_eq!(conflated.len(), 1);
+        assert_eq!(conflated[0].depth(), &engine.depth(&symbol, 1).unwrap());
+        assert_eq!(
+            conflated[0].seq(),
+            engine.depth_snapshot(&symbol).unwrap().seq()
+        );
+        assert!(feed.next_tick(Duration::from_millis(1)).is_empty());
+    }
+}
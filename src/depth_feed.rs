@@ -0,0 +1,519 @@
+use std::collections::VecDeque;
+
+use crate::events::EngineEvent;
+use crate::market_data::{BookDepth, PriceLevel};
+use crate::order_feed::OrderUpdate;
+use crate::{Level, MatchingEngine, OrderBook, OrderSide};
+
+//Level 2 incremental depth feed
+// Every order feed message of a book marks the level it changes. After every
+// change to the book only those levels are compared with what was published last
+// and the difference goes out as New/Change/Delete of a level at a position, best
+// price being position 0. Applying the updates in sequence to a snapshot taken at
+// sequence N gives the depth at any later sequence. The last DEPTH_HISTORY_LEN
+// updates per symbol are kept so late joiners can recover.
+
+const DEPTH_HISTORY_LEN: usize = 10_000;
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DepthAction {
+    //level inserted at the position, levels below move down by one
+    New,
+    Change,
+    //level removed from the position, levels below move up by one
+    Delete,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DepthUpdate {
+    #[cfg_attr(feature = "serde", serde(rename = "symbol"))]
+    symbol_: String,
+    #[cfg_attr(feature = "serde", serde(rename = "seq"))]
+    seq_: u64,
+    #[cfg_attr(feature = "serde", serde(rename = "side"))]
+    side_: OrderSide,
+    #[cfg_attr(feature = "serde", serde(rename = "action"))]
+    action_: DepthAction,
+    #[cfg_attr(feature = "serde", serde(rename = "position"))]
+    position_: usize,
+    #[cfg_attr(feature = "serde", serde(rename = "level"))]
+    level_: PriceLevel,
+}
+
+impl DepthUpdate {
+    pub fn symbol(&self) -> &String {
+        &self.symbol_
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq_
+    }
+
+    pub fn side(&self) -> OrderSide {
+        self.side_
+    }
+
+    pub fn action(&self) -> DepthAction {
+        self.action_
+    }
+
+    pub fn position(&self) -> usize {
+        self.position_
+    }
+
+    pub fn level(&self) -> PriceLevel {
+        self.level_
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DepthSnapshot {
+    #[cfg_attr(feature = "serde", serde(rename = "symbol"))]
+    symbol_: String,
+    #[cfg_attr(feature = "serde", serde(rename = "seq"))]
+    seq_: u64,
+    #[cfg_attr(feature = "serde", serde(rename = "depth"))]
+    depth_: BookDepth,
+}
+
+impl DepthSnapshot {
+    //depth of a symbol before its first update
+    pub(crate) fn empty(p_symbol: &String) -> Self {
+        DepthSnapshot {
+            symbol_: p_symbol.to_owned(),
+            seq_: 0,
+            depth_: BookDepth {
+                bids_: Vec::new(),
+                asks_: Vec::new(),
+            },
+        }
+    }
+
+    pub fn symbol(&self) -> &String {
+        &self.symbol_
+    }
+
+    //sequence of the last update included in the snapshot
+    pub fn seq(&self) -> u64 {
+        self.seq_
+    }
+
+    pub fn depth(&self) -> &BookDepth {
+        &self.depth_
+    }
+
+    //applies an update, it has to be the next one in sequence
+    pub fn apply(&mut self, p_update: &DepthUpdate) -> Result<(), String> {
+        if p_update.seq_ != self.seq_ + 1 {
+            return Err(format!(
+                "Depth update {} of {} does not follow snapshot sequence {}",
+                p_update.seq_, self.symbol_, self.seq_
+            ));
+        }
+        self.depth_.apply(p_update)?;
+        self.seq_ = p_update.seq_;
+        return Ok(());
+    }
+}
+
+impl BookDepth {
+    fn side_mut(&mut self, p_side: OrderSide) -> &mut Vec<PriceLevel> {
+        match p_side {
+            OrderSide::Buy => &mut self.bids_,
+            OrderSide::Sell => &mut self.asks_,
+        }
+    }
+
+    fn apply(&mut self, p_update: &DepthUpdate) -> Result<(), String> {
+        let levels = self.side_mut(p_update.side_);
+        let position = p_update.position_;
+        let in_range = match p_update.action_ {
+            DepthAction::New => position <= levels.len(),
+            DepthAction::Change | DepthAction::Delete => position < levels.len(),
+        };
+        if !in_range {
+            return Err(format!(
+                "Depth update {} position {} is out of range",
+                p_update.seq_, position
+            ));
+        }
+        match p_update.action_ {
+            DepthAction::New => levels.insert(position, p_update.level_),
+            DepthAction::Change => levels[position] = p_update.level_,
+            DepthAction::Delete => {
+                levels.remove(position);
+            }
+        }
+        return Ok(());
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct DepthHistory {
+    // depth as published at base_seq_, the oldest state that can still be rebuilt
+    base_: BookDepth,
+    base_seq_: u64,
+    updates_: VecDeque<DepthUpdate>,
+    published_: BookDepth,
+    seq_: u64,
+}
+
+impl DepthHistory {
+    pub(crate) fn new() -> Self {
+        let empty = BookDepth {
+            bids_: Vec::new(),
+            asks_: Vec::new(),
+        };
+        DepthHistory {
+            base_: empty.clone(),
+            base_seq_: 0,
+            updates_: VecDeque::new(),
+            published_: empty,
+            seq_: 0,
+        }
+    }
+
+    //history starting at a depth restored from a snapshot taken at p_seq
+    pub(crate) fn restored(p_depth: BookDepth, p_seq: u64) -> Self {
+        DepthHistory {
+            base_: p_depth.clone(),
+            base_seq_: p_seq,
+            updates_: VecDeque::new(),
+            published_: p_depth,
+            seq_: p_seq,
+        }
+    }
+
+    pub(crate) fn seq(&self) -> u64 {
+        self.seq_
+    }
+
+    //compares the levels of p_levels, side, price and what rests there now, with
+    //the published depth and returns the new updates
+    fn publish(
+        &mut self,
+        p_symbol: &String,
+        p_levels: Vec<(OrderSide, f32, Option<PriceLevel>)>,
+    ) -> Vec<DepthUpdate> {
+        let mut updates = Vec::new();
+        for (side, price, level) in p_levels {
+            let published = self.published_.side_mut(side);
+            let position = published
+                .partition_point(|published_level| is_better(side, published_level.price_, price));
+            let is_published = published
+                .get(position)
+                .is_some_and(|published_level| published_level.price_ == price);
+            let (action, level) = match (is_published, level) {
+                (false, None) => continue,
+                (false, Some(level)) => (DepthAction::New, level),
+                (true, None) => (DepthAction::Delete, published[position]),
+                (true, Some(level)) if level == published[position] => continue,
+                (true, Some(level)) => (DepthAction::Change, level),
+            };
+            self.seq_ += 1;
+            let update = DepthUpdate {
+                symbol_: p_symbol.to_owned(),
+                seq_: self.seq_,
+                side_: side,
+                action_: action,
+                position_: position,
+                level_: level,
+            };
+            //our own update at a position we just found can not go out of range
+            let _ = self.published_.apply(&update);
+            updates.push(update);
+        }
+        self.updates_.extend(updates.iter().cloned());
+
+        while self.updates_.len() > DEPTH_HISTORY_LEN {
+            if let Some(oldest) = self.updates_.pop_front() {
+                //applying our own updates to our own base can not go out of range
+                let _ = self.base_.apply(&oldest);
+                self.base_seq_ = oldest.seq_;
+            }
+        }
+        return updates;
+    }
+}
+
+//true when p_price comes before p_other_price on p_side
+fn is_better(p_side: OrderSide, p_price: f32, p_other_price: f32) -> bool {
+    match p_side {
+        OrderSide::Buy => p_price > p_other_price,
+        OrderSide::Sell => p_price < p_other_price,
+    }
+}
+
+impl OrderBook {
+    //marks the level p_update changes, an execution is found at the level of its
+    //order, the execution price may be another one in an auction
+    pub(crate) fn touch_level(&mut self, p_update: &OrderUpdate) {
+        let level = match p_update {
+            OrderUpdate::Add { side_, price_, .. }
+            | OrderUpdate::Modify { side_, price_, .. }
+            | OrderUpdate::Delete { side_, price_, .. } => Some((*side_, *price_)),
+            OrderUpdate::Execute { order_id_, .. } => self.order_index_.get(order_id_).copied(),
+        };
+        if let Some(level) = level {
+            if !self.touched_levels_.contains(&level) {
+                self.touched_levels_.push(level);
+            }
+        }
+    }
+
+    //the levels touched since the last call, bids then asks, best first, with
+    //what rests there now
+    fn take_touched_levels(&mut self) -> Vec<(OrderSide, f32, Option<PriceLevel>)> {
+        let mut touched_levels = std::mem::take(&mut self.touched_levels_);
+        touched_levels.sort_by(|(side, price), (other_side, other_price)| {
+            let side_order = (*side == OrderSide::Sell).cmp(&(*other_side == OrderSide::Sell));
+            let price_order = match side {
+                OrderSide::Buy => other_price.total_cmp(price),
+                OrderSide::Sell => price.total_cmp(other_price),
+            };
+            side_order.then(price_order)
+        });
+        touched_levels
+            .into_iter()
+            .map(|(side, price)| {
+                let levels = match side {
+                    OrderSide::Buy => &self.bids_,
+                    OrderSide::Sell => &self.asks_,
+                };
+                let level = levels
+                    .get(&Level::new(price, side))
+                    .map(|level| level.summary());
+                (side, price, level)
+            })
+            .collect()
+    }
+}
+
+impl MatchingEngine {
+    //publishes the depth changes of p_symbol since the last call
+    pub(crate) fn collect_depth_updates(&mut self, p_symbol: &String) {
+        let touched_levels = match self.order_book_by_symbol_.get_mut(p_symbol) {
+            None => return,
+            Some(order_book) => order_book.take_touched_levels(),
+        };
+        let updates = self
+            .depth_history_by_symbol_
+            .entry(p_symbol.to_owned())
+            .or_insert_with(DepthHistory::new)
+            .publish(p_symbol, touched_levels);
+        self.events_
+            .extend(updates.into_iter().map(EngineEvent::DepthUpdate));
+    }
+
+    //depth as published with the latest update
+    pub fn depth_snapshot(&self, p_symbol: &String) -> Option<DepthSnapshot> {
+        self.depth_history_by_symbol_
+            .get(p_symbol)
+            .map(|history| DepthSnapshot {
+                symbol_: p_symbol.to_owned(),
+                seq_: history.seq_,
+                depth_: history.published_.clone(),
+            })
+    }
+
+    //depth as it was right after update p_seq, as long as it is still retained
+    pub fn depth_snapshot_at(
+        &self,
+        p_symbol: &String,
+        p_seq: u64,
+    ) -> Result<DepthSnapshot, String> {
+        let history = match self.depth_history_by_symbol_.get(p_symbol) {
+            None => return Err(format!("No depth published for symbol {p_symbol}")),
+            Some(history) => history,
+        };
+        if p_seq < history.base_seq_ || p_seq > history.seq_ {
+            return Err(format!(
+                "Depth of {p_symbol} at {p_seq} is not available, retained {} to {}",
+                history.base_seq_, history.seq_
+            ));
+        }
+
+        let mut snapshot = DepthSnapshot {
+            symbol_: p_symbol.to_owned(),
+            seq_: history.base_seq_,
+            depth_: history.base_.clone(),
+        };
+        for update in history
+            .updates_
+            .iter()
+            .take_while(|update| update.seq_ <= p_seq)
+        {
+            snapshot.apply(update)?;
+        }
+        return Ok(snapshot);
+    }
+
+    //retained updates after p_seq, for a consumer that missed some of them
+    pub fn depth_updates_since(
+        &self,
+        p_symbol: &String,
+        p_seq: u64,
+    ) -> Result<Vec<DepthUpdate>, String> {
+        let history = match self.depth_history_by_symbol_.get(p_symbol) {
+            None => return Err(format!("No depth published for symbol {p_symbol}")),
+            Some(history) => history,
+        };
+        if p_seq < history.base_seq_ {
+            return Err(format!(
+                "Depth updates of {p_symbol} after {p_seq} are no longer retained, request a snapshot"
+            ));
+        }
+        return Ok(history
+            .updates_
+            .iter()
+            .filter(|update| update.seq_ > p_seq)
+            .cloned()
+            .collect());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::session::TradingState;
+    use crate::test_order as order;
+    use crate::{process_event, EventType};
+
+    fn depth_updates(p_engine: &mut MatchingEngine) -> Vec<DepthUpdate> {
+        p_engine
+            .drain_events()
+            .into_iter()
+            .filter_map(|event| match event {
+                EngineEvent::DepthUpdate(update) => Some(update),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn updates_for_touched_levels_only() {
+        let symbol = String::from("REL");
+        let mut engine = MatchingEngine::new();
+        let orders = [
+            order("1", OrderSide::Buy, 10, 99.0),
+            order("2", OrderSide::Buy, 10, 98.0),
+            order("3", OrderSide::Buy, 10, 97.0),
+            order("4", OrderSide::Sell, 10, 101.0),
+        ];
+        for mut order in orders {
+            process_event(EventType::New, &mut order, &mut engine).unwrap();
+        }
+        engine.drain_events();
+
+        let actions = |p_engine: &mut MatchingEngine| -> Vec<(OrderSide, DepthAction, usize)> {
+            depth_updates(p_engine)
+                .iter()
+                .map(|update| (update.side(), update.action(), update.position()))
+                .collect()
+        };
+        let mut better_bid = order("5", OrderSide::Buy, 5, 100.0);
+        process_event(EventType::New, &mut better_bid, &mut engine).unwrap();
+        assert_eq!(
+            actions(&mut engine),
+            vec![(OrderSide::Buy, DepthAction::New, 0)]
+        );
+        let mut cancel = order("2", OrderSide::Buy, 10, 98.0);
+        process_event(EventType::Cxl, &mut cancel, &mut engine).unwrap();
+        assert_eq!(
+            actions(&mut engine),
+            vec![(OrderSide::Buy, DepthAction::Delete, 2)]
+        );
+        let mut sell = order("6", OrderSide::Sell, 2, 100.0);
+        process_event(EventType::New, &mut sell, &mut engine).unwrap();
+        assert_eq!(
+            actions(&mut engine),
+            vec![(OrderSide::Buy, DepthAction::Change, 0)]
+        );
+        assert_eq!(
+            engine.depth_snapshot(&symbol).unwrap().depth(),
+            &engine.full_depth(&symbol).unwrap()
+        );
+    }
+
+    #[test]
+    fn auction_levels_follow_the_book() {
+        let symbol = String::from("REL");
+        let mut engine = MatchingEngine::new();
+        engine.transition(&symbol, TradingState::Halted).unwrap();
+        engine.transition(&symbol, TradingState::Closed).unwrap();
+        engine.transition(&symbol, TradingState::PreOpen).unwrap();
+        let orders = [
+            order("1", OrderSide::Buy, 100, 101.0),
+            order("2", OrderSide::Buy, 100, 100.0),
+            order("3", OrderSide::Sell, 150, 99.0),
+            order("4", OrderSide::Sell, 100, 102.0),
+        ];
+        for mut order in orders {
+            process_event(EventType::New, &mut order, &mut engine).unwrap();
+        }
+        //the orders execute at 100, away from the levels of 101 and 99
+        engine
+            .transition(&symbol, TradingState::Continuous)
+            .unwrap();
+
+        let mut snapshot = DepthSnapshot::empty(&symbol);
+        for update in depth_updates(&mut engine) {
+            snapshot.apply(&update).unwrap();
+        }
+        assert_eq!(snapshot.depth(), &engine.full_depth(&symbol).unwrap());
+        assert_eq!(
+            snapshot.depth().bids(),
+            &vec![PriceLevel::new(100.0, 50, 1)]
+        );
+        assert_eq!(
+            snapshot.depth().asks(),
+            &vec![PriceLevel::new(102.0, 100, 1)]
+        );
+    }
+
+    #[test]
+    fn late_joiner_syncs_from_snapshot() {
+        let symbol = String::from("REL");
+        let mut engine = MatchingEngine::new();
+        let orders = [
+            order("1", OrderSide::Buy, 100, 99.0),
+            order("2", OrderSide::Sell, 100, 101.0),
+            order("3", OrderSide::Buy, 50, 100.0),
+        ];
+        for mut order in orders {
+            process_event(EventType::New, &mut order, &mut engine).unwrap();
+        }
+        let mut snapshot = engine.depth_snapshot(&symbol).unwrap();
+        assert_eq!(snapshot.seq(), 3);
+        engine.drain_events();
+
+        let orders = [
+            order("4", OrderSide::Sell, 50, 100.0),
+            order("5", OrderSide::Sell, 30, 101.0),
+            order("6", OrderSide::Sell, 40, 99.0),
+        ];
+        for mut order in orders {
+            process_event(EventType::New, &mut order, &mut engine).unwrap();
+        }
+        for update in depth_updates(&mut engine) {
+            snapshot.apply(&update).unwrap();
+        }
+        assert_eq!(snapshot.depth(), &engine.full_depth(&symbol).unwrap());
+        assert_eq!(snapshot.depth().bids(), &vec![PriceLevel::new(99.0, 60, 1)]);
+        assert_eq!(
+            snapshot.depth().asks(),
+            &vec![PriceLevel::new(101.0, 130, 2)]
+        );
+
+        //replaying the retained history from an old snapshot gives the same depth
+        let mut replayed = engine.depth_snapshot_at(&symbol, 2).unwrap();
+        for update in engine.depth_updates_since(&symbol, 2).unwrap() {
+            replayed.apply(&update).unwrap();
+        }
+        assert_eq!(replayed, engine.depth_snapshot(&symbol).unwrap());
+        assert!(engine.depth_snapshot_at(&symbol, 100).is_err());
+    }
+}
@@ -0,0 +1,593 @@
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+//Market data distribution
+// Messages (usually ItchMessage::encode output) are sequenced and sent over UDP in
+// MoldUDP64 packets: session (10), sequence number of the first message (8) and
+// message count (2), followed by the messages each prefixed by its length (2). A
+// packet without messages is a heartbeat carrying the next sequence number, a
+// count of 0xFFFF ends the session. Every published message is kept so that a
+// receiver seeing a gap can ask the RetransmissionServer over TCP: the request is
+// a MoldUDP64 header giving the first sequence number and the message count, the
+// reply is a MoldUDP64 packet with as many of them as are available.
+
+pub const SESSION_LEN: usize = 10;
+const HEADER_LEN: usize = SESSION_LEN + 8 + 2;
+const END_OF_SESSION: u16 = 0xFFFF;
+//keeps packets under a typical ethernet MTU
+const MAX_PAYLOAD: usize = 1400;
+const MAX_RETRANSMISSION: u16 = 1000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MoldPacket {
+    session_: String,
+    sequence_: u64,
+    //empty for a heartbeat or end of session
+    messages_: Vec<Vec<u8>>,
+    end_of_session_: bool,
+}
+
+impl MoldPacket {
+    pub fn session(&self) -> &String {
+        &self.session_
+    }
+
+    pub fn sequence(&self) -> u64 {
+        self.sequence_
+    }
+
+    pub fn messages(&self) -> &Vec<Vec<u8>> {
+        &self.messages_
+    }
+
+    pub fn is_end_of_session(&self) -> bool {
+        self.end_of_session_
+    }
+
+    fn header(p_session: &str, p_sequence: u64, p_count: u16) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(MAX_PAYLOAD);
+        let mut session = [b' '; SESSION_LEN];
+        for (index, byte) in p_session.bytes().take(SESSION_LEN).enumerate() {
+            session[index] = byte;
+        }
+        buffer.extend_from_slice(&session);
+        buffer.extend_from_slice(&p_sequence.to_be_bytes());
+        buffer.extend_from_slice(&p_count.to_be_bytes());
+        return buffer;
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let count = match self.end_of_session_ {
+            true => END_OF_SESSION,
+            false => self.messages_.len() as u16,
+        };
+        let mut buffer = MoldPacket::header(&self.session_, self.sequence_, count);
+        for message in &self.messages_ {
+            buffer.extend_from_slice(&(message.len() as u16).to_be_bytes());
+            buffer.extend_from_slice(message);
+        }
+        return buffer;
+    }
+
+    //session, sequence number and message count of a packet or request header
+    fn decode_header(p_bytes: &[u8]) -> Result<(String, u64, u16), String> {
+        if p_bytes.len() < HEADER_LEN {
+            return Err(format!(
+                "MoldUDP64 header needs {} bytes, got {}",
+                HEADER_LEN,
+                p_bytes.len()
+            ));
+        }
+        let session = String::from_utf8_lossy(&p_bytes[..SESSION_LEN])
+            .trim_end()
+            .to_owned();
+        let sequence =
+            u64::from_be_bytes(p_bytes[SESSION_LEN..SESSION_LEN + 8].try_into().unwrap());
+        let count = u16::from_be_bytes(p_bytes[SESSION_LEN + 8..HEADER_LEN].try_into().unwrap());
+        return Ok((session, sequence, count));
+    }
+
+    pub fn decode(p_bytes: &[u8]) -> Result<MoldPacket, String> {
+        let (session, sequence, count) = MoldPacket::decode_header(p_bytes)?;
+        let mut packet = MoldPacket {
+            session_: session,
+            sequence_: sequence,
+            messages_: Vec::new(),
+            end_of_session_: count == END_OF_SESSION,
+        };
+        if packet.end_of_session_ {
+            return Ok(packet);
+        }
+
+        let mut position = HEADER_LEN;
+        for _ in 0..count {
+            if position + 2 > p_bytes.len() {
+                return Err(format!("MoldUDP64 packet {} truncated", sequence));
+            }
+            let message_len =
+                u16::from_be_bytes([p_bytes[position], p_bytes[position + 1]]) as usize;
+            position += 2;
+            if position + message_len > p_bytes.len() {
+                return Err(format!("MoldUDP64 packet {} truncated", sequence));
+            }
+            packet
+                .messages_
+                .push(p_bytes[position..position + message_len].to_vec());
+            position += message_len;
+        }
+        return Ok(packet);
+    }
+
+    //reads one packet from a stream, as sent by the RetransmissionServer
+    fn read_from(p_stream: &mut impl Read) -> Result<MoldPacket, String> {
+        let mut bytes = vec![0u8; HEADER_LEN];
+        p_stream
+            .read_exact(&mut bytes)
+            .map_err(|error| format!("Failed to read MoldUDP64 header: {}", error))?;
+        let (_, _, count) = MoldPacket::decode_header(&bytes)?;
+        if count != END_OF_SESSION {
+            for _ in 0..count {
+                let mut message_len = [0u8; 2];
+                p_stream
+                    .read_exact(&mut message_len)
+                    .map_err(|error| format!("Failed to read MoldUDP64 message: {}", error))?;
+                let mut message = vec![0u8; u16::from_be_bytes(message_len) as usize];
+                p_stream
+                    .read_exact(&mut message)
+                    .map_err(|error| format!("Failed to read MoldUDP64 message: {}", error))?;
+                bytes.extend_from_slice(&message_len);
+                bytes.extend_from_slice(&message);
+            }
+        }
+        return MoldPacket::decode(&bytes);
+    }
+}
+
+//every message published in the session, message with sequence number n at n - 1
+type MessageHistory = Arc<Mutex<Vec<Vec<u8>>>>;
+
+pub struct MarketDataPublisher {
+    socket_: UdpSocket,
+    destination_: SocketAddr,
+    session_: String,
+    history_: MessageHistory,
+}
+
+impl MarketDataPublisher {
+    //p_destination is a multicast group or, for a single receiver, a unicast address
+    pub fn new(p_session: &str, p_destination: SocketAddr) -> Result<Self, String> {
+        let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0))
+            .map_err(|error| format!("Failed to bind publisher socket: {}", error))?;
+        if p_destination.ip().is_multicast() {
+            socket
+                .set_multicast_loop_v4(true)
+                .map_err(|error| format!("Failed to enable multicast loop: {}", error))?;
+        }
+        return Ok(MarketDataPublisher {
+            socket_: socket,
+            destination_: p_destination,
+            session_: p_session.to_owned(),
+            history_: Arc::new(Mutex::new(Vec::new())),
+        });
+    }
+
+    pub fn session(&self) -> &String {
+        &self.session_
+    }
+
+    //sequence number the next published message will get
+    pub fn next_sequence(&self) -> u64 {
+        self.history_.lock().unwrap().len() as u64 + 1
+    }
+
+    //serves gap fills for this publisher's session on p_address
+    pub fn start_retransmission_server(
+        &self,
+        p_address: SocketAddr,
+    ) -> Result<RetransmissionServer, String> {
+        RetransmissionServer::start(p_address, &self.session_, self.history_.clone())
+    }
+
+    //sequences and sends p_messages, as few packets as fit, returns the sequence
+    //number of the first one
+    pub fn publish(&mut self, p_messages: &[Vec<u8>]) -> Result<u64, String> {
+        let first_sequence = self.next_sequence();
+        let mut packet = MoldPacket {
+            session_: self.session_.to_owned(),
+            sequence_: first_sequence,
+            messages_: Vec::new(),
+            end_of_session_: false,
+        };
+        let mut packet_len = HEADER_LEN;
+        for message in p_messages {
+            if packet_len + 2 + message.len() > MAX_PAYLOAD && !packet.messages_.is_empty() {
+                self.send(&packet)?;
+                packet.sequence_ += packet.messages_.len() as u64;
+                packet.messages_.clear();
+                packet_len = HEADER_LEN;
+            }
+            packet_len += 2 + message.len();
+            packet.messages_.push(message.to_owned());
+            self.history_.lock().unwrap().push(message.to_owned());
+        }
+        if !packet.messages_.is_empty() {
+            self.send(&packet)?;
+        }
+        return Ok(first_sequence);
+    }
+
+    //tells idle receivers the session is alive and which sequence number is next
+    pub fn heartbeat(&self) -> Result<(), String> {
+        self.send(&MoldPacket {
+            session_: self.session_.to_owned(),
+            sequence_: self.next_sequence(),
+            messages_: Vec::new(),
+            end_of_session_: false,
+        })
+    }
+
+    pub fn end_session(&self) -> Result<(), String> {
+        self.send(&MoldPacket {
+            session_: self.session_.to_owned(),
+            sequence_: self.next_sequence(),
+            messages_: Vec::new(),
+            end_of_session_: true,
+        })
+    }
+
+    fn send(&self, p_packet: &MoldPacket) -> Result<(), String> {
+        self.socket_
+            .send_to(&p_packet.encode(), self.destination_)
+            .map_err(|error| format!("Failed to send packet {}: {}", p_packet.sequence_, error))?;
+        return Ok(());
+    }
+}
+
+pub struct RetransmissionServer {
+    address_: SocketAddr,
+    stopped_: Arc<AtomicBool>,
+    handle_: Option<JoinHandle<()>>,
+}
+
+impl RetransmissionServer {
+    fn start(
+        p_address: SocketAddr,
+        p_session: &str,
+        p_history: MessageHistory,
+    ) -> Result<Self, String> {
+        let listener = TcpListener::bind(p_address)
+            .map_err(|error| format!("Failed to bind retransmission server: {}", error))?;
+        let address = listener
+            .local_addr()
+            .map_err(|error| format!("Failed to get retransmission server address: {}", error))?;
+        let stopped = Arc::new(AtomicBool::new(false));
+        let session = p_session.to_owned();
+        let thread_stopped = stopped.clone();
+        let handle = std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                if thread_stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    let session = session.to_owned();
+                    let history = p_history.clone();
+                    std::thread::spawn(move || {
+                        RetransmissionServer::serve(stream, &session, &history)
+                    });
+                }
+            }
+        });
+        return Ok(RetransmissionServer {
+            address_: address,
+            stopped_: stopped,
+            handle_: Some(handle),
+        });
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address_
+    }
+
+    //answers requests of one client until it disconnects
+    fn serve(mut p_stream: TcpStream, p_session: &str, p_history: &MessageHistory) {
+        let mut request = [0u8; HEADER_LEN];
+        while p_stream.read_exact(&mut request).is_ok() {
+            let (session, sequence, count) = match MoldPacket::decode_header(&request) {
+                Err(_) => return,
+                Ok(header) => header,
+            };
+            let messages = match session == p_session {
+                false => Vec::new(),
+                true => {
+                    let history = p_history.lock().unwrap();
+                    let first = (sequence.max(1) - 1) as usize;
+                    let last = (first + count.min(MAX_RETRANSMISSION) as usize).min(history.len());
+                    history
+                        .get(first..last)
+                        .map(|messages| messages.to_vec())
+                        .unwrap_or_default()
+                }
+            };
+            println!(
+                "Retransmitting {} messages from {} to {:?}",
+                messages.len(),
+                sequence,
+                p_stream.peer_addr()
+            );
+            let reply = MoldPacket {
+                session_: p_session.to_owned(),
+                sequence_: sequence,
+                messages_: messages,
+                end_of_session_: false,
+            };
+            if p_stream.write_all(&reply.encode()).is_err() {
+                return;
+            }
+        }
+    }
+
+    pub fn stop(&mut self) {
+        if let Some(handle) = self.handle_.take() {
+            self.stopped_.store(true, Ordering::SeqCst);
+            //wakes up the accept loop
+            let _ = TcpStream::connect(self.address_);
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for RetransmissionServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+//receives a session in sequence, filling gaps from the RetransmissionServer
+pub struct MarketDataReceiver {
+    socket_: UdpSocket,
+    retransmission_address_: SocketAddr,
+    retransmission_stream_: Option<TcpStream>,
+    session_: String,
+    next_sequence_: u64,
+    end_of_session_: bool,
+}
+
+impl MarketDataReceiver {
+    //binds p_address, joining its group when it is a multicast address
+    pub fn bind(
+        p_session: &str,
+        p_address: SocketAddr,
+        p_retransmission_address: SocketAddr,
+    ) -> Result<Self, String> {
+        let socket = match p_address.ip() {
+            IpAddr::V4(group) if group.is_multicast() => {
+                let socket = UdpSocket::bind(SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                    p_address.port(),
+                ))
+                .map_err(|error| format!("Failed to bind receiver socket: {}", error))?;
+                socket
+                    .join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)
+                    .map_err(|error| {
+                        format!("Failed to join multicast group {}: {}", group, error)
+                    })?;
+                socket
+            }
+            _ => UdpSocket::bind(p_address)
+                .map_err(|error| format!("Failed to bind receiver socket: {}", error))?,
+        };
+        return MarketDataReceiver::new(p_session, socket, p_retransmission_address);
+    }
+
+    pub fn new(
+        p_session: &str,
+        p_socket: UdpSocket,
+        p_retransmission_address: SocketAddr,
+    ) -> Result<Self, String> {
+        return Ok(MarketDataReceiver {
+            socket_: p_socket,
+            retransmission_address_: p_retransmission_address,
+            retransmission_stream_: None,
+            session_: p_session.to_owned(),
+            next_sequence_: 1,
+            end_of_session_: false,
+        });
+    }
+
+    pub fn local_address(&self) -> Result<SocketAddr, String> {
+        self.socket_
+            .local_addr()
+            .map_err(|error| format!("Failed to get receiver address: {}", error))
+    }
+
+    pub fn next_sequence(&self) -> u64 {
+        self.next_sequence_
+    }
+
+    pub fn is_end_of_session(&self) -> bool {
+        self.end_of_session_
+    }
+
+    pub fn set_read_timeout(&self, p_timeout: Option<Duration>) -> Result<(), String> {
+        self.socket_
+            .set_read_timeout(p_timeout)
+            .map_err(|error| format!("Failed to set read timeout: {}", error))
+    }
+
+    //waits for the next packet of the session and returns the messages it makes
+    //available in sequence, gap fills included, duplicates dropped
+    pub fn receive(&mut self) -> Result<Vec<Vec<u8>>, String> {
+        let mut buffer = vec![0u8; 65536];
+        loop {
+            let len = self
+                .socket_
+                .recv(&mut buffer)
+                .map_err(|error| format!("Failed to receive packet: {}", error))?;
+            let packet = MoldPacket::decode(&buffer[..len])?;
+            if packet.session_ != self.session_ {
+                continue;
+            }
+
+            let mut messages = Vec::new();
+            if packet.sequence_ > self.next_sequence_ {
+                let missing = packet.sequence_ - self.next_sequence_;
+                println!("Gap of {} messages from {}", missing, self.next_sequence_);
+                messages = self.recover(missing)?;
+            }
+            let already_seen = self.next_sequence_.saturating_sub(packet.sequence_) as usize;
+            for message in packet.messages_.into_iter().skip(already_seen) {
+                messages.push(message);
+                self.next_sequence_ += 1;
+            }
+            if packet.end_of_session_ {
+                self.end_of_session_ = true;
+            }
+            return Ok(messages);
+        }
+    }
+
+    //gets p_count messages from next_sequence_ from the retransmission server
+    fn recover(&mut self, p_count: u64) -> Result<Vec<Vec<u8>>, String> {
+        if self.retransmission_stream_.is_none() {
+            let stream = TcpStream::connect(self.retransmission_address_).map_err(|error| {
+                format!("Failed to connect to retransmission server: {}", error)
+            })?;
+            self.retransmission_stream_ = Some(stream);
+        }
+        let stream = self.retransmission_stream_.as_mut().unwrap();
+
+        let mut messages = Vec::new();
+        let mut remaining = p_count;
+        while remaining > 0 {
+            let count = remaining.min(MAX_RETRANSMISSION as u64) as u16;
+            let request = MoldPacket::header(&self.session_, self.next_sequence_, count);
+            stream
+                .write_all(&request)
+                .map_err(|error| format!("Failed to request retransmission: {}", error))?;
+            let reply = MoldPacket::read_from(stream)?;
+            if reply.messages_.is_empty() {
+                return Err(format!(
+                    "Retransmission server has no messages from {}",
+                    self.next_sequence_
+                ));
+            }
+            remaining -= reply.messages_.len() as u64;
+            self.next_sequence_ += reply.messages_.len() as u64;
+            messages.extend(reply.messages_);
+        }
+        return Ok(messages);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::itch::ItchMessage;
+
+    fn loopback() -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)
+    }
+
+    fn delete(p_order_ref: u64) -> Vec<u8> {
+        ItchMessage::OrderDelete {
+            stock_locate_: 1,
+            timestamp_: 0,
+            order_ref_: p_order_ref,
+        }
+        .encode()
+    }
+
+    #[test]
+    fn packet_round_trip() {
+        let packet = MoldPacket {
+            session_: String::from("SESSION1"),
+            sequence_: 42,
+            messages_: vec![delete(1), delete(2)],
+            end_of_session_: false,
+        };
+        let bytes = packet.encode();
+        assert_eq!(bytes.len(), HEADER_LEN + 2 * (2 + 19));
+        assert_eq!(MoldPacket::decode(&bytes), Ok(packet));
+        assert!(MoldPacket::decode(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn gap_filled_over_tcp() {
+        let receiver_socket = UdpSocket::bind(loopback()).unwrap();
+        receiver_socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut publisher =
+            MarketDataPublisher::new("SESSION1", receiver_socket.local_addr().unwrap()).unwrap();
+        let server = publisher.start_retransmission_server(loopback()).unwrap();
+
+        //the first packet is lost on the way
+        assert_eq!(publisher.publish(&[delete(1), delete(2)]).unwrap(), 1);
+        let mut lost = [0u8; 1500];
+        receiver_socket.recv(&mut lost).unwrap();
+
+        let mut receiver =
+            MarketDataReceiver::new("SESSION1", receiver_socket, server.address()).unwrap();
+        assert_eq!(publisher.publish(&[delete(3)]).unwrap(), 3);
+        let messages = receiver.receive().unwrap();
+        assert_eq!(messages, vec![delete(1), delete(2), delete(3)]);
+        assert_eq!(receiver.next_sequence(), 4);
+
+        //a heartbeat after a lost packet also reveals the gap
+        publisher.publish(&[delete(4)]).unwrap();
+        receiver.socket_.recv(&mut lost).unwrap();
+        publisher.heartbeat().unwrap();
+        assert_eq!(receiver.receive().unwrap(), vec![delete(4)]);
+
+        publisher.end_session().unwrap();
+        assert!(receiver.receive().unwrap().is_empty());
+        assert!(receiver.is_end_of_session());
+    }
+
+    #[test]
+    fn large_batches_span_packets() {
+        let receiver_socket = UdpSocket::bind(loopback()).unwrap();
+        receiver_socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut publisher =
+            MarketDataPublisher::new("SESSION2", receiver_socket.local_addr().unwrap()).unwrap();
+        let server = publisher.start_retransmission_server(loopback()).unwrap();
+        let mut receiver =
+            MarketDataReceiver::new("SESSION2", receiver_socket, server.address()).unwrap();
+
+        let messages: Vec<Vec<u8>> = (1..=200).map(delete).collect();
+        publisher.publish(&messages).unwrap();
+        let mut received = Vec::new();
+        while received.len() < messages.len() {
+            received.extend(receiver.receive().unwrap());
+        }
+        assert_eq!(received, messages);
+    }
+
+    #[test]
+    fn multicast_group_joined_over_loopback() {
+        //a free port for the group, the multicast loop of the publisher delivers the
+        //packets to the receiver joined on this host
+        let port = UdpSocket::bind(loopback())
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let group = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 255, 41, 7)), port);
+        let mut publisher = MarketDataPublisher::new("SESSION3", group).unwrap();
+        let server = publisher.start_retransmission_server(loopback()).unwrap();
+        let mut receiver = MarketDataReceiver::bind("SESSION3", group, server.address()).unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        assert_eq!(publisher.publish(&[delete(1)]).unwrap(), 1);
+        assert_eq!(receiver.receive().unwrap(), vec![delete(1)]);
+        assert_eq!(receiver.next_sequence(), 2);
+    }
+}
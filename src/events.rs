@@ -0,0 +1,80 @@
+use crate::bars::Bar;
+use crate::depth_feed::DepthUpdate;
+use crate::order_feed::OrderFeedMessage;
+use crate::session::TradingState;
+use crate::stp::StpMode;
+use crate::ticker::{Trade, TradeStatistics};
+
+//Engine events
+// Everything the engine wants to tell the outside world besides the direct
+// result of process_event is queued here and handed out by MatchingEngine::drain_events.
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum EngineEvent {
+    TradingStatus {
+        #[cfg_attr(feature = "serde", serde(rename = "symbol"))]
+        symbol_: String,
+        #[cfg_attr(feature = "serde", serde(rename = "previous_state"))]
+        previous_state_: TradingState,
+        #[cfg_attr(feature = "serde", serde(rename = "state"))]
+        state_: TradingState,
+        #[cfg_attr(feature = "serde", serde(rename = "reason"))]
+        reason_: String,
+    },
+    OrderQueued {
+        #[cfg_attr(feature = "serde", serde(rename = "symbol"))]
+        symbol_: String,
+        #[cfg_attr(feature = "serde", serde(rename = "order_id"))]
+        order_id_: String,
+    },
+    OrderRejected {
+        #[cfg_attr(feature = "serde", serde(rename = "symbol"))]
+        symbol_: String,
+        #[cfg_attr(feature = "serde", serde(rename = "order_id"))]
+        order_id_: String,
+        #[cfg_attr(feature = "serde", serde(rename = "reason"))]
+        reason_: String,
+    },
+    OrderCancelled {
+        #[cfg_attr(feature = "serde", serde(rename = "symbol"))]
+        symbol_: String,
+        #[cfg_attr(feature = "serde", serde(rename = "order_id"))]
+        order_id_: String,
+        #[cfg_attr(feature = "serde", serde(rename = "reason"))]
+        reason_: String,
+    },
+    PriceBand {
+        #[cfg_attr(feature = "serde", serde(rename = "symbol"))]
+        symbol_: String,
+        #[cfg_attr(feature = "serde", serde(rename = "lower_band"))]
+        lower_band_: f32,
+        #[cfg_attr(feature = "serde", serde(rename = "upper_band"))]
+        upper_band_: f32,
+    },
+    LimitState {
+        #[cfg_attr(feature = "serde", serde(rename = "symbol"))]
+        symbol_: String,
+        #[cfg_attr(feature = "serde", serde(rename = "entered"))]
+        entered_: bool,
+    },
+    SelfTradePrevented {
+        #[cfg_attr(feature = "serde", serde(rename = "symbol"))]
+        symbol_: String,
+        #[cfg_attr(feature = "serde", serde(rename = "aggressor_order_id"))]
+        aggressor_order_id_: String,
+        #[cfg_attr(feature = "serde", serde(rename = "resting_order_id"))]
+        resting_order_id_: String,
+        #[cfg_attr(feature = "serde", serde(rename = "mode"))]
+        mode_: StpMode,
+        #[cfg_attr(feature = "serde", serde(rename = "aggressor_cancelled_qty"))]
+        aggressor_cancelled_qty_: i32,
+        #[cfg_attr(feature = "serde", serde(rename = "resting_cancelled_qty"))]
+        resting_cancelled_qty_: i32,
+    },
+    OrderFeed(OrderFeedMessage),
+    DepthUpdate(DepthUpdate),
+    Trade(Trade),
+    TradeStatistics(TradeStatistics),
+    Bar(Bar),
+}
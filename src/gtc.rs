@@ -0,0 +1,338 @@
+use std::path::Path;
+
+use crate::command::EngineCommand;
+use crate::events::EngineEvent;
+use crate::journal::{crc32, Reader};
+use crate::session::TradingState;
+use crate::snapshot::{put_orders, put_u32};
+use crate::status::OrderState;
+use crate::{MatchingEngine, Order, TimeInForce};
+
+//Good-till-cancel orders
+// DAY orders live until the end of the trading day, GTC orders until they are
+// cancelled. At the end of the day purge_day_orders takes the DAY orders out of
+// the books and empties the closing books and the halt queues: an on-close order
+// does not outlive its close and a queued order never made it into a book, so
+// both expire whatever their time in force. export_gtc_orders then writes the GTC
+// orders left resting to a file, best price first and in queue order within a
+// level. The next day import_gtc_orders puts them back into the books
+// with their entry time and sequence, ahead of everything entered that day, while
+// the symbols are still before the opening auction. The purge and the import are
+// engine commands, the import carries the orders read from the file so neither a
+// replay nor a backup needs it.
+//
+// Layout: magic (8), format version (4), the orders as in a snapshot, then a
+// CRC-32 of everything before it (4).
+
+const GTC_MAGIC: &[u8; 8] = b"OMEGTC\0\0";
+const GTC_VERSION: u32 = 1;
+//reason of the cancels of DAY orders, the audit trail reports them as expired
+pub(crate) const END_OF_DAY: &str = "end of day";
+
+impl MatchingEngine {
+    //cancels every DAY order left at the end of the day and every order still in
+    //a closing book or a halt queue, returns how many. The orders which ended
+    //before are no longer followed
+    pub fn purge_day_orders(&mut self) -> Result<usize, String> {
+        return self.process_command(&EngineCommand::PurgeDayOrders, |engine| {
+            Ok(engine.expire_day_orders())
+        });
+    }
+
+    fn expire_day_orders(&mut self) -> usize {
+        let first_event = self.events_.len();
+        self.forget_ended_orders();
+        let mut purged = Vec::new();
+        for (symbol, order_book) in &mut self.order_book_by_symbol_ {
+            let day_orders: Vec<Order> = order_book
+                .bids_
+                .iter()
+                .chain(order_book.asks_.iter())
+                .flat_map(|level| level.orders_.iter())
+                .filter(|order| order.time_in_force_ == TimeInForce::Day)
+                .cloned()
+                .collect();
+            for order in day_orders {
+                order_book.remove_order_by_id(&order);
+                purged.push((symbol.to_owned(), order.id_));
+            }
+        }
+        for (symbol, closing_book) in &mut self.closing_book_by_symbol_ {
+            let on_close_orders = closing_book.orders_.drain(..);
+            purged.extend(on_close_orders.map(|order| (symbol.to_owned(), order.id_)));
+        }
+        for (symbol, session) in &mut self.session_by_symbol_ {
+            let queued_orders = session.queued_orders_.drain(..);
+            purged.extend(queued_orders.map(|order| (symbol.to_owned(), order.id_)));
+        }
+
+        println!("End of day, {} orders purged", purged.len());
+        for (symbol, order_id) in &purged {
+            self.end_order(order_id, OrderState::Expired);
+            self.events_.push(EngineEvent::OrderCancelled {
+                symbol_: symbol.to_owned(),
+                order_id_: order_id.to_owned(),
+                reason_: String::from(END_OF_DAY),
+            });
+        }
+        let now = self.clock_.now();
+        for symbol in self.known_symbols() {
+            self.collect_book_changes(&symbol, now);
+        }
+        self.audit_events(first_event);
+        return purged.len();
+    }
+
+    //GTC orders resting in the books, symbol by symbol, bids then asks in priority order
+    pub fn gtc_orders(&self) -> Vec<Order> {
+        self.order_book_by_symbol_
+            .values()
+            .flat_map(|order_book| order_book.bids_.iter().chain(order_book.asks_.iter()))
+            .flat_map(|level| level.orders_.iter())
+            .filter(|order| order.time_in_force_ == TimeInForce::Gtc)
+            .cloned()
+            .collect()
+    }
+
+    //writes the resting GTC orders to p_path, returns how many
+    pub fn export_gtc_orders(&self, p_path: &Path) -> Result<usize, String> {
+        let orders = self.gtc_orders();
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(GTC_MAGIC);
+        put_u32(&mut buffer, GTC_VERSION);
+        put_orders(&mut buffer, orders.iter());
+        let checksum = crc32(&buffer);
+        put_u32(&mut buffer, checksum);
+
+        let written_path = p_path.with_extension("partial");
+        std::fs::write(&written_path, &buffer)
+            .and_then(|_| std::fs::rename(&written_path, p_path))
+            .map_err(|error| {
+                format!("Failed to write GTC orders {}: {}", p_path.display(), error)
+            })?;
+        println!(
+            "{} GTC orders written to {}",
+            orders.len(),
+            p_path.display()
+        );
+        return Ok(orders.len());
+    }
+
+    //puts the GTC orders of p_path back into the books, every symbol they are for
+    //has to be in PreOpen or OpeningAuction. Returns how many
+    pub fn import_gtc_orders(&mut self, p_path: &Path) -> Result<usize, String> {
+        let orders = MatchingEngine::read_gtc_orders(p_path)?;
+        let loaded = self.load_gtc_orders(&orders)?;
+        println!("{} GTC orders loaded from {}", loaded, p_path.display());
+        return Ok(loaded);
+    }
+
+    //journaled with the orders, applying it again does not need the file
+    pub(crate) fn load_gtc_orders(&mut self, p_orders: &[Order]) -> Result<usize, String> {
+        let command = EngineCommand::ImportGtcOrders {
+            orders_: p_orders.to_vec(),
+        };
+        return self.process_command(&command, |engine| engine.add_gtc_orders(p_orders));
+    }
+
+    fn add_gtc_orders(&mut self, p_orders: &[Order]) -> Result<usize, String> {
+        //all or nothing
+        for order in p_orders {
+            let trading_state = self.trading_state(&order.symbol_);
+            if !matches!(
+                trading_state,
+                TradingState::PreOpen | TradingState::OpeningAuction
+            ) {
+                return Err(format!(
+                    "GTC order {} can not be loaded while {} is {:?}",
+                    order.id_, order.symbol_, trading_state
+                ));
+            }
+            let is_resting = self
+                .order_book_by_symbol_
+                .get(&order.symbol_)
+                .is_some_and(|order_book| order_book.get_level_match_from_id(order).is_some());
+            if is_resting {
+                return Err(format!(
+                    "GTC order {} is already resting in {}",
+                    order.id_, order.symbol_
+                ));
+            }
+        }
+
+        for mut order in p_orders.iter().cloned() {
+            let order_book = match self.get_book_by_symbol(&order.symbol_) {
+                Some(order_book) => order_book,
+                None => self.add_order_book(&order.symbol_).unwrap(),
+            };
+            order_book.add_order(&mut order);
+        }
+        self.track_live_orders();
+        let now = self.clock_.now();
+        for symbol in self.known_symbols() {
+            self.collect_book_changes(&symbol, now);
+        }
+        return Ok(p_orders.len());
+    }
+
+    //the GTC orders written to p_path by export_gtc_orders, for an import
+    //replicated through Primary::process_command
+    pub fn read_gtc_orders(p_path: &Path) -> Result<Vec<Order>, String> {
+        let bytes = std::fs::read(p_path).map_err(|error| {
+            format!("Failed to read GTC orders {}: {}", p_path.display(), error)
+        })?;
+        if bytes.len() < GTC_MAGIC.len() + 4 || &bytes[..GTC_MAGIC.len()] != GTC_MAGIC {
+            return Err(format!("{} is not a GTC order file", p_path.display()));
+        }
+        let (content, checksum) = bytes.split_at(bytes.len() - 4);
+        if crc32(content) != u32::from_be_bytes(checksum.try_into().unwrap()) {
+            return Err(format!("GTC order file {} is corrupt", p_path.display()));
+        }
+        let mut reader = Reader::new(&content[GTC_MAGIC.len()..]);
+        let version = reader.u32()?;
+        if version != GTC_VERSION {
+            return Err(format!(
+                "GTC order file {} has version {}, only version {} is supported",
+                p_path.display(),
+                version,
+                GTC_VERSION
+            ));
+        }
+        let orders = reader.orders()?;
+        if !reader.is_empty() {
+            return Err(format!(
+                "GTC order file {} has trailing data",
+                p_path.display()
+            ));
+        }
+        return Ok(orders);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::session::HaltOrderPolicy;
+    use crate::test_order;
+    use crate::{process_event, EventType, OrderSide, OrderType};
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+
+    fn order(
+        p_id: &str,
+        p_side: OrderSide,
+        p_qty: i32,
+        p_price: f32,
+        p_time_in_force: TimeInForce,
+    ) -> Order {
+        test_order(p_id, p_side, p_qty, p_price).with_time_in_force(p_time_in_force)
+    }
+
+    fn temp_path(p_name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("ome_{}_{}", std::process::id(), p_name));
+        let _ = std::fs::remove_file(&path);
+        return path;
+    }
+
+    fn resting_ids(p_engine: &MatchingEngine) -> Vec<String> {
+        p_engine
+            .order_book_by_symbol_
+            .values()
+            .flat_map(|order_book| order_book.bids_.iter().chain(order_book.asks_.iter()))
+            .flat_map(|level| level.orders_.iter())
+            .map(|order| order.id_.to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn gtc_orders_carried_to_next_day() {
+        let symbol = String::from("REL");
+        let path = temp_path("day.gtc");
+        let day = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let mut engine = MatchingEngine::new();
+        engine.set_clock(ManualClock::new(day));
+        let orders = [
+            order("1", OrderSide::Buy, 100, 99.0, TimeInForce::Gtc),
+            order("2", OrderSide::Buy, 100, 99.0, TimeInForce::Day),
+            order("3", OrderSide::Buy, 100, 99.0, TimeInForce::Gtc),
+            order("4", OrderSide::Buy, 100, 100.0, TimeInForce::Gtc),
+            order("5", OrderSide::Sell, 100, 101.0, TimeInForce::Day),
+            order("6", OrderSide::Sell, 30, 100.0, TimeInForce::Day),
+        ];
+        for mut order in orders {
+            process_event(EventType::New, &mut order, &mut engine).unwrap();
+        }
+        engine.drain_events();
+
+        assert_eq!(engine.purge_day_orders().unwrap(), 2);
+        let cancelled: Vec<String> = engine
+            .drain_events()
+            .into_iter()
+            .filter_map(|event| match event {
+                EngineEvent::OrderCancelled { order_id_, .. } => Some(order_id_),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(cancelled, vec!["2", "5"]);
+        assert_eq!(engine.export_gtc_orders(&path).unwrap(), 3);
+
+        //next day, the partially filled order keeps its leaves and its priority
+        let mut next_day = MatchingEngine::new();
+        next_day.set_clock(ManualClock::new(day + Duration::from_secs(86_400)));
+        let mut today = order("7", OrderSide::Buy, 50, 99.0, TimeInForce::Day);
+        next_day.transition(&symbol, TradingState::Closed).unwrap();
+        next_day.transition(&symbol, TradingState::PreOpen).unwrap();
+        process_event(EventType::New, &mut today, &mut next_day).unwrap();
+        assert_eq!(next_day.import_gtc_orders(&path).unwrap(), 3);
+        assert_eq!(resting_ids(&next_day), vec!["4", "1", "3", "7"]);
+        assert_eq!(next_day.gtc_orders()[0].qty_, 70);
+        assert!(next_day.import_gtc_orders(&path).is_err());
+
+        //only before the opening auction
+        let mut continuous = MatchingEngine::new();
+        assert!(continuous.import_gtc_orders(&path).is_err());
+        assert!(resting_ids(&continuous).is_empty());
+
+        std::fs::write(&path, b"OMEGTC\0\0garbage").unwrap();
+        assert!(next_day.import_gtc_orders(&path).is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn gtc_orders_outside_the_books_expire() {
+        let symbol = String::from("REL");
+        let path = temp_path("queued.gtc");
+        let mut engine = MatchingEngine::new();
+        engine.set_halt_order_policy(HaltOrderPolicy::Queue);
+        let mut resting = order("1", OrderSide::Buy, 100, 99.0, TimeInForce::Gtc);
+        let mut on_close =
+            order("2", OrderSide::Buy, 100, 0.0, TimeInForce::Gtc).with_type(OrderType::Moc);
+        process_event(EventType::New, &mut resting, &mut engine).unwrap();
+        process_event(EventType::New, &mut on_close, &mut engine).unwrap();
+        engine.halt(&symbol, "news pending").unwrap();
+        let mut queued = order("3", OrderSide::Sell, 100, 101.0, TimeInForce::Gtc);
+        process_event(EventType::New, &mut queued, &mut engine).unwrap();
+        engine.drain_events();
+
+        assert_eq!(engine.purge_day_orders().unwrap(), 2);
+        let expired: Vec<String> = engine
+            .drain_events()
+            .into_iter()
+            .filter_map(|event| match event {
+                EngineEvent::OrderCancelled {
+                    order_id_, reason_, ..
+                } if reason_ == END_OF_DAY => Some(order_id_),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(expired, vec!["2", "3"]);
+        for order_id in ["2", "3"] {
+            let status = engine.order_status(order_id).unwrap();
+            assert_eq!(status.state(), OrderState::Expired);
+        }
+        assert_eq!(engine.export_gtc_orders(&path).unwrap(), 1);
+        assert_eq!(engine.gtc_orders()[0].id_, "1");
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,271 @@
+use crate::command::EngineCommand;
+use crate::events::EngineEvent;
+use crate::session::TradingState;
+use crate::status::OrderState;
+use crate::{MatchingEngine, MatchingResult, Order};
+
+//Trading halts
+// A halt freezes the book of a symbol: nothing matches, cancels are still accepted
+// and new orders are rejected or queued depending on the HaltOrderPolicy. A queued
+// order can be cancelled or replaced until it is replayed.
+// Resuming goes either through an opening auction or straight back to continuous
+// trading, in which case the book is uncrossed first and queued orders are replayed.
+
+impl MatchingEngine {
+    pub fn halt(&mut self, p_symbol: &String, p_reason: &str) -> Result<(), String> {
+        let command = EngineCommand::Halt {
+            symbol_: p_symbol.to_owned(),
+            reason_: p_reason.to_owned(),
+        };
+        return self.process_command(&command, |engine| {
+            engine
+                .change_state(p_symbol, TradingState::Halted, p_reason)
+                .map(|_| ())
+        });
+    }
+
+    pub fn resume(
+        &mut self,
+        p_symbol: &String,
+        p_state: TradingState,
+    ) -> Result<Vec<MatchingResult>, String> {
+        let command = EngineCommand::Resume {
+            symbol_: p_symbol.to_owned(),
+            state_: p_state,
+        };
+        return self.process_command(&command, |engine| engine.resume_symbol(p_symbol, p_state));
+    }
+
+    fn resume_symbol(
+        &mut self,
+        p_symbol: &String,
+        p_state: TradingState,
+    ) -> Result<Vec<MatchingResult>, String> {
+        if self.trading_state(p_symbol) != TradingState::Halted {
+            return Err(format!("{p_symbol} is not halted, resume failed"));
+        }
+        if !matches!(
+            p_state,
+            TradingState::OpeningAuction | TradingState::Continuous
+        ) {
+            return Err(format!(
+                "{p_symbol} can only resume into an auction or continuous trading, not {:?}",
+                p_state
+            ));
+        }
+
+        let mut results = Vec::new();
+        if let Some(uncross_result) = self.change_state(p_symbol, p_state, "resume")? {
+            results.push(uncross_result);
+        }
+
+        let queued_orders = std::mem::take(&mut self.session_mut(p_symbol).queued_orders_);
+        for mut queued_order in queued_orders {
+            let event_count = self.events_.len();
+            let result = self.process_new_order(&mut queued_order);
+            match &result {
+                Ok(match_result) => {
+                    self.track_fills(Some(&queued_order.id_), match_result);
+                    self.audit_fills(&queued_order, match_result);
+                }
+                Err(_) => self.end_order(&queued_order.id_, OrderState::Rejected),
+            }
+            match result {
+                Ok(Some(match_result)) => results.push(match_result),
+                Ok(None) => {}
+                Err(reason) => self.events_.push(EngineEvent::OrderRejected {
+                    symbol_: p_symbol.to_owned(),
+                    order_id_: queued_order.id_.to_owned(),
+                    reason_: reason,
+                }),
+            }
+            self.audit_events(event_count);
+        }
+        return Ok(results);
+    }
+
+    //halts every symbol, symbols seen for the first time while halted start halted
+    pub fn halt_all(&mut self, p_reason: &str) -> Result<(), String> {
+        let command = EngineCommand::HaltAll {
+            reason_: p_reason.to_owned(),
+        };
+        return self.process_command(&command, |engine| engine.halt_known_symbols(p_reason));
+    }
+
+    fn halt_known_symbols(&mut self, p_reason: &str) -> Result<(), String> {
+        for symbol in self.known_symbols() {
+            if self
+                .trading_state(&symbol)
+                .can_transition_to(TradingState::Halted)
+            {
+                self.change_state(&symbol, TradingState::Halted, p_reason)?;
+            }
+        }
+        self.market_halted_ = true;
+        return Ok(());
+    }
+
+    //resumes every halted symbol, including the ones halted individually
+    pub fn resume_all(&mut self, p_state: TradingState) -> Result<Vec<MatchingResult>, String> {
+        let command = EngineCommand::ResumeAll { state_: p_state };
+        return self.process_command(&command, |engine| engine.resume_halted_symbols(p_state));
+    }
+
+    fn resume_halted_symbols(
+        &mut self,
+        p_state: TradingState,
+    ) -> Result<Vec<MatchingResult>, String> {
+        self.market_halted_ = false;
+        let mut results = Vec::new();
+        for symbol in self.known_symbols() {
+            if self.trading_state(&symbol) == TradingState::Halted {
+                results.append(&mut self.resume_symbol(&symbol, p_state)?);
+            }
+        }
+        return Ok(results);
+    }
+
+    pub(crate) fn queue_order(&mut self, p_order: &Order) {
+        println!("Symbol halted, queueing order id {:?}", p_order.id_);
+        self.session_mut(&p_order.symbol_)
+            .queued_orders_
+            .push(p_order.to_owned());
+        self.events_.push(EngineEvent::OrderQueued {
+            symbol_: p_order.symbol_.to_owned(),
+            order_id_: p_order.id_.to_owned(),
+        });
+    }
+
+    fn queued_position(&self, p_order: &Order) -> Option<usize> {
+        self.session_by_symbol_
+            .get(&p_order.symbol_)?
+            .queued_orders_
+            .iter()
+            .position(|order| order.id_ == p_order.id_)
+    }
+
+    //true when p_order refers to an order queued while its symbol is halted
+    pub(crate) fn is_queued(&self, p_order: &Order) -> bool {
+        self.queued_position(p_order).is_some()
+    }
+
+    //replace amends the queued order in place, it is replayed in its turn on resume
+    pub(crate) fn replace_queued(&mut self, p_order: &Order) {
+        if let Some(position) = self.queued_position(p_order) {
+            self.session_mut(&p_order.symbol_).queued_orders_[position] = p_order.to_owned();
+        }
+    }
+
+    pub(crate) fn cancel_queued(&mut self, p_order: &Order) {
+        if let Some(position) = self.queued_position(p_order) {
+            self.session_mut(&p_order.symbol_)
+                .queued_orders_
+                .remove(position);
+        }
+    }
+
+    pub(crate) fn known_symbols(&self) -> Vec<String> {
+        let mut symbols: Vec<String> = self
+            .order_book_by_symbol_
+            .keys()
+            .chain(self.session_by_symbol_.keys())
+            .cloned()
+            .collect();
+        symbols.sort();
+        symbols.dedup();
+        return symbols;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::session::HaltOrderPolicy;
+    use crate::test_order;
+    use crate::{process_event, EventType, OrderSide};
+
+    fn order(p_id: &str, p_side: OrderSide, p_price: f32) -> Order {
+        test_order(p_id, p_side, 100, p_price)
+    }
+
+    #[test]
+    fn halt_rejects_and_accepts_cancels() {
+        let mut engine = MatchingEngine::new();
+        let symbol = String::from("REL");
+        let mut buy = order("1", OrderSide::Buy, 100.0);
+        process_event(EventType::New, &mut buy, &mut engine).unwrap();
+        engine.drain_events();
+
+        engine.halt(&symbol, "news pending").unwrap();
+        let mut sell = order("2", OrderSide::Sell, 100.0);
+        assert!(process_event(EventType::New, &mut sell, &mut engine).is_err());
+        assert!(process_event(EventType::Cxl, &mut buy, &mut engine).is_ok());
+
+        let events = engine.drain_events();
+        assert_eq!(
+            events[..1],
+            [EngineEvent::TradingStatus {
+                symbol_: symbol.to_owned(),
+                previous_state_: TradingState::Continuous,
+                state_: TradingState::Halted,
+                reason_: String::from("news pending"),
+            }]
+        );
+    }
+
+    #[test]
+    fn queued_orders_replayed_on_resume() {
+        let mut engine = MatchingEngine::new();
+        engine.set_halt_order_policy(HaltOrderPolicy::Queue);
+        let symbol = String::from("REL");
+        let mut buy = order("1", OrderSide::Buy, 100.0);
+        process_event(EventType::New, &mut buy, &mut engine).unwrap();
+
+        engine.halt_all("regulatory halt").unwrap();
+        let mut sell = order("2", OrderSide::Sell, 100.0);
+        assert!(process_event(EventType::New, &mut sell, &mut engine)
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            engine.trading_state(&String::from("TCS")),
+            TradingState::Halted
+        );
+
+        let results = engine.resume_all(TradingState::Continuous).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].executed_qty_, 100);
+        assert_eq!(results[0].matched_order_ids_, vec!["1"]);
+        assert_eq!(engine.trading_state(&symbol), TradingState::Continuous);
+        assert!(engine.drain_events().contains(&EngineEvent::OrderQueued {
+            symbol_: symbol.to_owned(),
+            order_id_: String::from("2"),
+        }));
+    }
+
+    #[test]
+    fn queued_orders_cancelled_or_replaced_before_resume() {
+        let mut engine = MatchingEngine::new();
+        engine.set_halt_order_policy(HaltOrderPolicy::Queue);
+        let symbol = String::from("REL");
+        let mut buy = order("1", OrderSide::Buy, 100.0);
+        process_event(EventType::New, &mut buy, &mut engine).unwrap();
+
+        engine.halt(&symbol, "news pending").unwrap();
+        let mut cancelled = order("2", OrderSide::Sell, 100.0);
+        let mut replaced = order("3", OrderSide::Sell, 100.0);
+        for queued in [&mut cancelled, &mut replaced] {
+            assert!(process_event(EventType::New, queued, &mut engine)
+                .unwrap()
+                .is_none());
+        }
+        process_event(EventType::Cxl, &mut cancelled, &mut engine).unwrap();
+        let mut amended = test_order("3", OrderSide::Sell, 40, 100.0);
+        process_event(EventType::Rpl, &mut amended, &mut engine).unwrap();
+
+        let results = engine.resume(&symbol, TradingState::Continuous).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].executed_qty_, 40);
+        assert_eq!(engine.depth(&symbol, 1).unwrap().bids()[0].qty(), 60);
+        assert!(engine.session_by_symbol_[&symbol].queued_orders_.is_empty());
+    }
+}
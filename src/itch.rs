@@ -0,0 +1,1008 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::SystemTime;
+
+use crate::events::EngineEvent;
+use crate::market_data::{BookDepth, PriceLevel};
+use crate::order_feed::{OrderFeedMessage, OrderUpdate};
+use crate::session::time_of_day;
+use crate::OrderSide;
+
+//Binary market data
+// Fixed width, big endian messages modelled on NASDAQ TotalView-ITCH 5.0. Every
+// message starts with the common header: type (1), stock locate (2), tracking
+// number (2) and nanoseconds since midnight (6). Prices are unsigned with 4 implied
+// decimals, symbols are 8 bytes padded with spaces. Engine order ids are strings,
+// on the wire orders are known by an order reference number assigned by the
+// ItchEncoder, increasing in arrival order so it also gives the queue priority.
+
+const HEADER_LEN: usize = 11;
+const PRICE_SCALE: f32 = 10_000.0;
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+pub enum SystemEventCode {
+    StartOfMessages,
+    StartOfSystemHours,
+    StartOfMarketHours,
+    EndOfMarketHours,
+    EndOfSystemHours,
+    EndOfMessages,
+}
+
+impl SystemEventCode {
+    fn to_byte(self) -> u8 {
+        match self {
+            SystemEventCode::StartOfMessages => b'O',
+            SystemEventCode::StartOfSystemHours => b'S',
+            SystemEventCode::StartOfMarketHours => b'Q',
+            SystemEventCode::EndOfMarketHours => b'M',
+            SystemEventCode::EndOfSystemHours => b'E',
+            SystemEventCode::EndOfMessages => b'C',
+        }
+    }
+
+    fn from_byte(p_byte: u8) -> Result<Self, String> {
+        match p_byte {
+            b'O' => Ok(SystemEventCode::StartOfMessages),
+            b'S' => Ok(SystemEventCode::StartOfSystemHours),
+            b'Q' => Ok(SystemEventCode::StartOfMarketHours),
+            b'M' => Ok(SystemEventCode::EndOfMarketHours),
+            b'E' => Ok(SystemEventCode::EndOfSystemHours),
+            b'C' => Ok(SystemEventCode::EndOfMessages),
+            _ => Err(format!("Unknown system event code {}", p_byte)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ItchMessage {
+    //'S', 12 bytes
+    SystemEvent {
+        timestamp_: u64,
+        event_code_: SystemEventCode,
+    },
+    //'R', 19 bytes, announces the stock locate of a symbol
+    StockDirectory {
+        stock_locate_: u16,
+        timestamp_: u64,
+        symbol_: String,
+    },
+    //'A', 36 bytes
+    AddOrder {
+        stock_locate_: u16,
+        timestamp_: u64,
+        order_ref_: u64,
+        side_: OrderSide,
+        shares_: u32,
+        symbol_: String,
+        price_: u32,
+    },
+    //'E', 31 bytes
+    OrderExecuted {
+        stock_locate_: u16,
+        timestamp_: u64,
+        order_ref_: u64,
+        executed_shares_: u32,
+        match_number_: u64,
+    },
+    //'X', 23 bytes, partial cancel
+    OrderCancel {
+        stock_locate_: u16,
+        timestamp_: u64,
+        order_ref_: u64,
+        cancelled_shares_: u32,
+    },
+    //'D', 19 bytes
+    OrderDelete {
+        stock_locate_: u16,
+        timestamp_: u64,
+        order_ref_: u64,
+    },
+    //'U', 35 bytes, the new order loses the priority of the original one
+    OrderReplace {
+        stock_locate_: u16,
+        timestamp_: u64,
+        original_order_ref_: u64,
+        new_order_ref_: u64,
+        shares_: u32,
+        price_: u32,
+    },
+    //'P', 44 bytes, auction print not visible through the order messages
+    Trade {
+        stock_locate_: u16,
+        timestamp_: u64,
+        order_ref_: u64,
+        side_: OrderSide,
+        shares_: u32,
+        symbol_: String,
+        price_: u32,
+        match_number_: u64,
+    },
+}
+
+pub fn encode_price(p_price: f32) -> u32 {
+    (p_price * PRICE_SCALE).round() as u32
+}
+
+pub fn decode_price(p_price: u32) -> f32 {
+    p_price as f32 / PRICE_SCALE
+}
+
+fn side_to_byte(p_side: OrderSide) -> u8 {
+    match p_side {
+        OrderSide::Buy => b'B',
+        OrderSide::Sell => b'S',
+    }
+}
+
+fn side_from_byte(p_byte: u8) -> Result<OrderSide, String> {
+    match p_byte {
+        b'B' => Ok(OrderSide::Buy),
+        b'S' => Ok(OrderSide::Sell),
+        _ => Err(format!("Unknown side {}", p_byte)),
+    }
+}
+
+fn put_symbol(p_buffer: &mut Vec<u8>, p_symbol: &str) {
+    let mut symbol = [b' '; 8];
+    for (index, byte) in p_symbol.bytes().take(8).enumerate() {
+        symbol[index] = byte;
+    }
+    p_buffer.extend_from_slice(&symbol);
+}
+
+fn put_timestamp(p_buffer: &mut Vec<u8>, p_timestamp: u64) {
+    p_buffer.extend_from_slice(&p_timestamp.to_be_bytes()[2..]);
+}
+
+//reads fixed width fields from a message
+struct Reader<'a> {
+    bytes_: &'a [u8],
+    position_: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, p_len: usize) -> &'a [u8] {
+        let field = &self.bytes_[self.position_..self.position_ + p_len];
+        self.position_ += p_len;
+        return field;
+    }
+
+    fn u8(&mut self) -> u8 {
+        self.take(1)[0]
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_be_bytes(self.take(2).try_into().unwrap())
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_be_bytes(self.take(4).try_into().unwrap())
+    }
+
+    fn u64(&mut self) -> u64 {
+        u64::from_be_bytes(self.take(8).try_into().unwrap())
+    }
+
+    fn timestamp(&mut self) -> u64 {
+        let mut timestamp = [0u8; 8];
+        timestamp[2..].copy_from_slice(self.take(6));
+        return u64::from_be_bytes(timestamp);
+    }
+
+    fn symbol(&mut self) -> String {
+        String::from_utf8_lossy(self.take(8)).trim_end().to_owned()
+    }
+}
+
+impl ItchMessage {
+    pub fn message_type(&self) -> u8 {
+        match self {
+            ItchMessage::SystemEvent { .. } => b'S',
+            ItchMessage::StockDirectory { .. } => b'R',
+            ItchMessage::AddOrder { .. } => b'A',
+            ItchMessage::OrderExecuted { .. } => b'E',
+            ItchMessage::OrderCancel { .. } => b'X',
+            ItchMessage::OrderDelete { .. } => b'D',
+            ItchMessage::OrderReplace { .. } => b'U',
+            ItchMessage::Trade { .. } => b'P',
+        }
+    }
+
+    //wire length of a message of p_message_type
+    pub fn message_len(p_message_type: u8) -> Option<usize> {
+        match p_message_type {
+            b'S' => Some(HEADER_LEN + 1),
+            b'R' => Some(HEADER_LEN + 8),
+            b'A' => Some(HEADER_LEN + 25),
+            b'E' => Some(HEADER_LEN + 20),
+            b'X' => Some(HEADER_LEN + 12),
+            b'D' => Some(HEADER_LEN + 8),
+            b'U' => Some(HEADER_LEN + 24),
+            b'P' => Some(HEADER_LEN + 33),
+            _ => None,
+        }
+    }
+
+    fn header(&self) -> (u16, u64) {
+        match self {
+            ItchMessage::SystemEvent { timestamp_, .. } => (0, *timestamp_),
+            ItchMessage::StockDirectory {
+                stock_locate_,
+                timestamp_,
+                ..
+            }
+            | ItchMessage::AddOrder {
+                stock_locate_,
+                timestamp_,
+                ..
+            }
+            | ItchMessage::OrderExecuted {
+                stock_locate_,
+                timestamp_,
+                ..
+            }
+            | ItchMessage::OrderCancel {
+                stock_locate_,
+                timestamp_,
+                ..
+            }
+            | ItchMessage::OrderDelete {
+                stock_locate_,
+                timestamp_,
+                ..
+            }
+            | ItchMessage::OrderReplace {
+                stock_locate_,
+                timestamp_,
+                ..
+            }
+            | ItchMessage::Trade {
+                stock_locate_,
+                timestamp_,
+                ..
+            } => (*stock_locate_, *timestamp_),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let message_type = self.message_type();
+        let mut buffer = Vec::with_capacity(ItchMessage::message_len(message_type).unwrap());
+        let (stock_locate, timestamp) = self.header();
+        buffer.push(message_type);
+        buffer.extend_from_slice(&stock_locate.to_be_bytes());
+        //tracking number, unused
+        buffer.extend_from_slice(&0u16.to_be_bytes());
+        put_timestamp(&mut buffer, timestamp);
+
+        match self {
+            ItchMessage::SystemEvent { event_code_, .. } => buffer.push(event_code_.to_byte()),
+            ItchMessage::StockDirectory { symbol_, .. } => put_symbol(&mut buffer, symbol_),
+            ItchMessage::AddOrder {
+                order_ref_,
+                side_,
+                shares_,
+                symbol_,
+                price_,
+                ..
+            } => {
+                buffer.extend_from_slice(&order_ref_.to_be_bytes());
+                buffer.push(side_to_byte(*side_));
+                buffer.extend_from_slice(&shares_.to_be_bytes());
+                put_symbol(&mut buffer, symbol_);
+                buffer.extend_from_slice(&price_.to_be_bytes());
+            }
+            ItchMessage::OrderExecuted {
+                order_ref_,
+                executed_shares_,
+                match_number_,
+                ..
+            } => {
+                buffer.extend_from_slice(&order_ref_.to_be_bytes());
+                buffer.extend_from_slice(&executed_shares_.to_be_bytes());
+                buffer.extend_from_slice(&match_number_.to_be_bytes());
+            }
+            ItchMessage::OrderCancel {
+                order_ref_,
+                cancelled_shares_,
+                ..
+            } => {
+                buffer.extend_from_slice(&order_ref_.to_be_bytes());
+                buffer.extend_from_slice(&cancelled_shares_.to_be_bytes());
+            }
+            ItchMessage::OrderDelete { order_ref_, .. } => {
+                buffer.extend_from_slice(&order_ref_.to_be_bytes());
+            }
+            ItchMessage::OrderReplace {
+                original_order_ref_,
+                new_order_ref_,
+                shares_,
+                price_,
+                ..
+            } => {
+                buffer.extend_from_slice(&original_order_ref_.to_be_bytes());
+                buffer.extend_from_slice(&new_order_ref_.to_be_bytes());
+                buffer.extend_from_slice(&shares_.to_be_bytes());
+                buffer.extend_from_slice(&price_.to_be_bytes());
+            }
+            ItchMessage::Trade {
+                order_ref_,
+                side_,
+                shares_,
+                symbol_,
+                price_,
+                match_number_,
+                ..
+            } => {
+                buffer.extend_from_slice(&order_ref_.to_be_bytes());
+                buffer.push(side_to_byte(*side_));
+                buffer.extend_from_slice(&shares_.to_be_bytes());
+                put_symbol(&mut buffer, symbol_);
+                buffer.extend_from_slice(&price_.to_be_bytes());
+                buffer.extend_from_slice(&match_number_.to_be_bytes());
+            }
+        }
+        return buffer;
+    }
+
+    pub fn decode(p_bytes: &[u8]) -> Result<ItchMessage, String> {
+        let message_type = match p_bytes.first() {
+            None => return Err(String::from("Empty message")),
+            Some(message_type) => *message_type,
+        };
+        let message_len = match ItchMessage::message_len(message_type) {
+            None => return Err(format!("Unknown message type {}", message_type)),
+            Some(message_len) => message_len,
+        };
+        if p_bytes.len() != message_len {
+            return Err(format!(
+                "Message type {} has {} bytes, expected {}",
+                message_type as char,
+                p_bytes.len(),
+                message_len
+            ));
+        }
+
+        let mut reader = Reader {
+            bytes_: p_bytes,
+            position_: 1,
+        };
+        let stock_locate = reader.u16();
+        let _tracking_number = reader.u16();
+        let timestamp = reader.timestamp();
+        let message = match message_type {
+            b'S' => ItchMessage::SystemEvent {
+                timestamp_: timestamp,
+                event_code_: SystemEventCode::from_byte(reader.u8())?,
+            },
+            b'R' => ItchMessage::StockDirectory {
+                stock_locate_: stock_locate,
+                timestamp_: timestamp,
+                symbol_: reader.symbol(),
+            },
+            b'A' => ItchMessage::AddOrder {
+                stock_locate_: stock_locate,
+                timestamp_: timestamp,
+                order_ref_: reader.u64(),
+                side_: side_from_byte(reader.u8())?,
+                shares_: reader.u32(),
+                symbol_: reader.symbol(),
+                price_: reader.u32(),
+            },
+            b'E' => ItchMessage::OrderExecuted {
+                stock_locate_: stock_locate,
+                timestamp_: timestamp,
+                order_ref_: reader.u64(),
+                executed_shares_: reader.u32(),
+                match_number_: reader.u64(),
+            },
+            b'X' => ItchMessage::OrderCancel {
+                stock_locate_: stock_locate,
+                timestamp_: timestamp,
+                order_ref_: reader.u64(),
+                cancelled_shares_: reader.u32(),
+            },
+            b'D' => ItchMessage::OrderDelete {
+                stock_locate_: stock_locate,
+                timestamp_: timestamp,
+                order_ref_: reader.u64(),
+            },
+            b'U' => ItchMessage::OrderReplace {
+                stock_locate_: stock_locate,
+                timestamp_: timestamp,
+                original_order_ref_: reader.u64(),
+                new_order_ref_: reader.u64(),
+                shares_: reader.u32(),
+                price_: reader.u32(),
+            },
+            _ => ItchMessage::Trade {
+                stock_locate_: stock_locate,
+                timestamp_: timestamp,
+                order_ref_: reader.u64(),
+                side_: side_from_byte(reader.u8())?,
+                shares_: reader.u32(),
+                symbol_: reader.symbol(),
+                price_: reader.u32(),
+                match_number_: reader.u64(),
+            },
+        };
+        return Ok(message);
+    }
+}
+
+#[derive(Clone, Debug)]
+struct EncodedOrder {
+    order_ref_: u64,
+    shares_: u32,
+}
+
+//turns engine events into ItchMessages
+#[derive(Debug, Default)]
+pub struct ItchEncoder {
+    locate_by_symbol_: HashMap<String, u16>,
+    //(symbol, order id) of the orders resting on the wire
+    order_by_id_: HashMap<(String, String), EncodedOrder>,
+    last_order_ref_: u64,
+}
+
+impl ItchEncoder {
+    pub fn new() -> Self {
+        ItchEncoder::default()
+    }
+
+    pub fn system_event(&self, p_event_code: SystemEventCode, p_time: SystemTime) -> ItchMessage {
+        ItchMessage::SystemEvent {
+            timestamp_: time_of_day(p_time).as_nanos() as u64,
+            event_code_: p_event_code,
+        }
+    }
+
+    //order feed messages and auction trades of p_events, stamped with p_time. An
+    //auction print is only sent as a Trade when no order execution carries it.
+    //A Delete directly followed by an Add of the same order, as published by a
+    //replace, becomes a single OrderReplace
+    pub fn encode(&mut self, p_events: &[EngineEvent], p_time: SystemTime) -> Vec<ItchMessage> {
+        let timestamp = time_of_day(p_time).as_nanos() as u64;
+        let mut messages = Vec::new();
+        let mut event_index = 0;
+        while event_index < p_events.len() {
+            match &p_events[event_index] {
+                EngineEvent::OrderFeed(feed_message) => {
+                    let replaced_by = match p_events.get(event_index + 1) {
+                        Some(EngineEvent::OrderFeed(next_message)) => {
+                            ItchEncoder::as_replace(feed_message, next_message)
+                        }
+                        _ => None,
+                    };
+                    match replaced_by {
+                        Some((shares, price)) => {
+                            self.encode_replace(
+                                feed_message,
+                                shares,
+                                price,
+                                timestamp,
+                                &mut messages,
+                            );
+                            event_index += 1;
+                        }
+                        None => self.encode_feed(feed_message, timestamp, &mut messages),
+                    }
+                }
+                //the book changes of an uncross come before its print, which is
+                //left out when the executions of the orders already report it
+                EngineEvent::Trade(trade)
+                    if trade.maker_order_id().is_empty()
+                        && !ItchEncoder::is_executed(&messages, trade.trade_id()) =>
+                {
+                    let stock_locate = self.stock_locate(trade.symbol(), timestamp, &mut messages);
+                    messages.push(ItchMessage::Trade {
+                        stock_locate_: stock_locate,
+                        timestamp_: time_of_day(trade.time()).as_nanos() as u64,
+                        order_ref_: 0,
+                        side_: OrderSide::Buy,
+                        shares_: trade.qty() as u32,
+                        symbol_: trade.symbol().to_owned(),
+                        price_: encode_price(trade.price()),
+                        match_number_: trade.trade_id(),
+                    });
+                }
+                _ => {}
+            }
+            event_index += 1;
+        }
+        return messages;
+    }
+
+    //true when an OrderExecuted of p_messages has p_match_number
+    fn is_executed(p_messages: &[ItchMessage], p_match_number: u64) -> bool {
+        p_messages.iter().any(|message| {
+            matches!(
+                message,
+                ItchMessage::OrderExecuted { match_number_, .. } if *match_number_ == p_match_number
+            )
+        })
+    }
+
+    //(shares, price) of the new order when p_next re-adds the order p_message deletes
+    fn as_replace(p_message: &OrderFeedMessage, p_next: &OrderFeedMessage) -> Option<(i32, f32)> {
+        match (p_message.update(), p_next.update()) {
+            (
+                OrderUpdate::Delete { order_id_, .. },
+                OrderUpdate::Add {
+                    order_id_: new_order_id,
+                    qty_,
+                    price_,
+                    ..
+                },
+            ) if order_id_ == new_order_id && p_message.symbol() == p_next.symbol() => {
+                Some((*qty_, *price_))
+            }
+            _ => None,
+        }
+    }
+
+    fn stock_locate(
+        &mut self,
+        p_symbol: &String,
+        p_timestamp: u64,
+        p_messages: &mut Vec<ItchMessage>,
+    ) -> u16 {
+        if let Some(stock_locate) = self.locate_by_symbol_.get(p_symbol) {
+            return *stock_locate;
+        }
+        let stock_locate = self.locate_by_symbol_.len() as u16 + 1;
+        self.locate_by_symbol_
+            .insert(p_symbol.to_owned(), stock_locate);
+        p_messages.push(ItchMessage::StockDirectory {
+            stock_locate_: stock_locate,
+            timestamp_: p_timestamp,
+            symbol_: p_symbol.to_owned(),
+        });
+        return stock_locate;
+    }
+
+    fn next_order_ref(&mut self) -> u64 {
+        self.last_order_ref_ += 1;
+        self.last_order_ref_
+    }
+
+    fn encode_replace(
+        &mut self,
+        p_message: &OrderFeedMessage,
+        p_shares: i32,
+        p_price: f32,
+        p_timestamp: u64,
+        p_messages: &mut Vec<ItchMessage>,
+    ) {
+        let stock_locate = self.stock_locate(p_message.symbol(), p_timestamp, p_messages);
+        let order_id = match p_message.update() {
+            OrderUpdate::Delete { order_id_, .. } => order_id_,
+            _ => return,
+        };
+        let key = (p_message.symbol().to_owned(), order_id.to_owned());
+        let original_order_ref = match self.order_by_id_.get(&key) {
+            None => return,
+            Some(order) => order.order_ref_,
+        };
+        let new_order_ref = self.next_order_ref();
+        self.order_by_id_.insert(
+            key,
+            EncodedOrder {
+                order_ref_: new_order_ref,
+                shares_: p_shares as u32,
+            },
+        );
+        p_messages.push(ItchMessage::OrderReplace {
+            stock_locate_: stock_locate,
+            timestamp_: p_timestamp,
+            original_order_ref_: original_order_ref,
+            new_order_ref_: new_order_ref,
+            shares_: p_shares as u32,
+            price_: encode_price(p_price),
+        });
+    }
+
+    fn encode_feed(
+        &mut self,
+        p_message: &OrderFeedMessage,
+        p_timestamp: u64,
+        p_messages: &mut Vec<ItchMessage>,
+    ) {
+        let symbol = p_message.symbol();
+        let stock_locate = self.stock_locate(symbol, p_timestamp, p_messages);
+        match p_message.update() {
+            OrderUpdate::Add {
+                order_id_,
+                side_,
+                price_,
+                qty_,
+            } => {
+                let order_ref = self.next_order_ref();
+                self.order_by_id_.insert(
+                    (symbol.to_owned(), order_id_.to_owned()),
+                    EncodedOrder {
+                        order_ref_: order_ref,
+                        shares_: *qty_ as u32,
+                    },
+                );
+                p_messages.push(ItchMessage::AddOrder {
+                    stock_locate_: stock_locate,
+                    timestamp_: p_timestamp,
+                    order_ref_: order_ref,
+                    side_: *side_,
+                    shares_: *qty_ as u32,
+                    symbol_: symbol.to_owned(),
+                    price_: encode_price(*price_),
+                });
+            }
+            OrderUpdate::Modify {
+                order_id_, qty_, ..
+            } => {
+                let order = match self
+                    .order_by_id_
+                    .get_mut(&(symbol.to_owned(), order_id_.to_owned()))
+                {
+                    None => return,
+                    Some(order) => order,
+                };
+                let cancelled_shares = order.shares_ - *qty_ as u32;
+                order.shares_ = *qty_ as u32;
+                p_messages.push(ItchMessage::OrderCancel {
+                    stock_locate_: stock_locate,
+                    timestamp_: p_timestamp,
+                    order_ref_: order.order_ref_,
+                    cancelled_shares_: cancelled_shares,
+                });
+            }
+            OrderUpdate::Delete { order_id_, .. } => {
+                let order = match self
+                    .order_by_id_
+                    .remove(&(symbol.to_owned(), order_id_.to_owned()))
+                {
+                    None => return,
+                    Some(order) => order,
+                };
+                p_messages.push(ItchMessage::OrderDelete {
+                    stock_locate_: stock_locate,
+                    timestamp_: p_timestamp,
+                    order_ref_: order.order_ref_,
+                });
+            }
+            OrderUpdate::Execute {
+                order_id_,
+                qty_,
+                trade_id_,
+                ..
+            } => {
+                let key = (symbol.to_owned(), order_id_.to_owned());
+                let order = match self.order_by_id_.get_mut(&key) {
+                    None => return,
+                    Some(order) => order,
+                };
+                order.shares_ -= *qty_ as u32;
+                p_messages.push(ItchMessage::OrderExecuted {
+                    stock_locate_: stock_locate,
+                    timestamp_: p_timestamp,
+                    order_ref_: order.order_ref_,
+                    executed_shares_: *qty_ as u32,
+                    match_number_: *trade_id_,
+                });
+                if order.shares_ == 0 {
+                    self.order_by_id_.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct DecodedOrder {
+    stock_locate_: u16,
+    side_: OrderSide,
+    shares_: u32,
+    price_: u32,
+}
+
+//order books rebuilt from a stream of ItchMessages
+#[derive(Debug, Default)]
+pub struct ItchBook {
+    symbol_by_locate_: HashMap<u16, String>,
+    //by order reference, which is also the time priority
+    orders_: BTreeMap<u64, DecodedOrder>,
+    last_system_event_: Option<SystemEventCode>,
+}
+
+impl ItchBook {
+    pub fn new() -> Self {
+        ItchBook::default()
+    }
+
+    pub fn last_system_event(&self) -> Option<SystemEventCode> {
+        self.last_system_event_
+    }
+
+    pub fn apply(&mut self, p_message: &ItchMessage) -> Result<(), String> {
+        match p_message {
+            ItchMessage::SystemEvent { event_code_, .. } => {
+                self.last_system_event_ = Some(*event_code_);
+            }
+            ItchMessage::StockDirectory {
+                stock_locate_,
+                symbol_,
+                ..
+            } => {
+                self.symbol_by_locate_
+                    .insert(*stock_locate_, symbol_.to_owned());
+            }
+            ItchMessage::AddOrder {
+                stock_locate_,
+                order_ref_,
+                side_,
+                shares_,
+                price_,
+                ..
+            } => {
+                self.orders_.insert(
+                    *order_ref_,
+                    DecodedOrder {
+                        stock_locate_: *stock_locate_,
+                        side_: *side_,
+                        shares_: *shares_,
+                        price_: *price_,
+                    },
+                );
+            }
+            ItchMessage::OrderExecuted {
+                order_ref_,
+                executed_shares_,
+                ..
+            } => self.reduce(*order_ref_, *executed_shares_)?,
+            ItchMessage::OrderCancel {
+                order_ref_,
+                cancelled_shares_,
+                ..
+            } => self.reduce(*order_ref_, *cancelled_shares_)?,
+            ItchMessage::OrderDelete { order_ref_, .. } => {
+                self.remove(*order_ref_)?;
+            }
+            ItchMessage::OrderReplace {
+                original_order_ref_,
+                new_order_ref_,
+                shares_,
+                price_,
+                ..
+            } => {
+                let mut order = self.remove(*original_order_ref_)?;
+                order.shares_ = *shares_;
+                order.price_ = *price_;
+                self.orders_.insert(*new_order_ref_, order);
+            }
+            ItchMessage::Trade { .. } => {}
+        }
+        return Ok(());
+    }
+
+    fn remove(&mut self, p_order_ref: u64) -> Result<DecodedOrder, String> {
+        match self.orders_.remove(&p_order_ref) {
+            None => Err(format!("Unknown order reference {}", p_order_ref)),
+            Some(order) => Ok(order),
+        }
+    }
+
+    fn reduce(&mut self, p_order_ref: u64, p_shares: u32) -> Result<(), String> {
+        let order = match self.orders_.get_mut(&p_order_ref) {
+            None => return Err(format!("Unknown order reference {}", p_order_ref)),
+            Some(order) => order,
+        };
+        if p_shares > order.shares_ {
+            return Err(format!(
+                "Order reference {} has {} shares, cannot remove {}",
+                p_order_ref, order.shares_, p_shares
+            ));
+        }
+        order.shares_ -= p_shares;
+        if order.shares_ == 0 {
+            self.orders_.remove(&p_order_ref);
+        }
+        return Ok(());
+    }
+
+    fn locate(&self, p_symbol: &String) -> Option<u16> {
+        self.symbol_by_locate_
+            .iter()
+            .find(|(_, symbol)| *symbol == p_symbol)
+            .map(|(stock_locate, _)| *stock_locate)
+    }
+
+    //shares of the orders at p_price on p_side in priority order
+    pub fn queue(&self, p_symbol: &String, p_side: OrderSide, p_price: f32) -> Vec<u32> {
+        let stock_locate = self.locate(p_symbol);
+        let price = encode_price(p_price);
+        self.orders_
+            .values()
+            .filter(|order| Some(order.stock_locate_) == stock_locate)
+            .filter(|order| order.side_ == p_side && order.price_ == price)
+            .map(|order| order.shares_)
+            .collect()
+    }
+
+    //aggregated levels of p_symbol, best price first as in MatchingEngine::full_depth
+    pub fn depth(&self, p_symbol: &String) -> Option<BookDepth> {
+        let stock_locate = self.locate(p_symbol)?;
+        let mut bids: BTreeMap<u32, PriceLevel> = BTreeMap::new();
+        let mut asks: BTreeMap<u32, PriceLevel> = BTreeMap::new();
+        for order in self.orders_.values() {
+            if order.stock_locate_ != stock_locate {
+                continue;
+            }
+            let levels = match order.side_ {
+                OrderSide::Buy => &mut bids,
+                OrderSide::Sell => &mut asks,
+            };
+            let level = levels
+                .entry(order.price_)
+                .or_insert_with(|| PriceLevel::new(decode_price(order.price_), 0, 0));
+            level.qty_ += order.shares_ as i32;
+            level.order_count_ += 1;
+        }
+        return Some(BookDepth {
+            bids_: bids.into_values().rev().collect(),
+            asks_: asks.into_values().collect(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::session::TradingState;
+    use crate::test_order as order;
+    use crate::{process_event, EventType, MatchingEngine};
+
+    #[test]
+    fn messages_round_trip() {
+        let messages = [
+            ItchMessage::SystemEvent {
+                timestamp_: 34_200_000_000_000,
+                event_code_: SystemEventCode::StartOfMarketHours,
+            },
+            ItchMessage::StockDirectory {
+                stock_locate_: 1,
+                timestamp_: 1,
+                symbol_: String::from("REL"),
+            },
+            ItchMessage::AddOrder {
+                stock_locate_: 1,
+                timestamp_: 2,
+                order_ref_: 7,
+                side_: OrderSide::Sell,
+                shares_: 100,
+                symbol_: String::from("REL"),
+                price_: encode_price(100.25),
+            },
+            ItchMessage::OrderExecuted {
+                stock_locate_: 1,
+                timestamp_: 3,
+                order_ref_: 7,
+                executed_shares_: 40,
+                match_number_: 9,
+            },
+            ItchMessage::OrderCancel {
+                stock_locate_: 1,
+                timestamp_: 4,
+                order_ref_: 7,
+                cancelled_shares_: 10,
+            },
+            ItchMessage::OrderReplace {
+                stock_locate_: 1,
+                timestamp_: 5,
+                original_order_ref_: 7,
+                new_order_ref_: 8,
+                shares_: 30,
+                price_: encode_price(101.0),
+            },
+            ItchMessage::OrderDelete {
+                stock_locate_: 1,
+                timestamp_: 6,
+                order_ref_: 8,
+            },
+            ItchMessage::Trade {
+                stock_locate_: 1,
+                timestamp_: 7,
+                order_ref_: 0,
+                side_: OrderSide::Buy,
+                shares_: 500,
+                symbol_: String::from("REL"),
+                price_: encode_price(100.0),
+                match_number_: 10,
+            },
+        ];
+        for message in messages {
+            let bytes = message.encode();
+            assert_eq!(Some(bytes.len()), ItchMessage::message_len(bytes[0]));
+            assert_eq!(ItchMessage::decode(&bytes), Ok(message));
+        }
+        assert!(ItchMessage::decode(&[b'A', 0, 1]).is_err());
+        assert_eq!(decode_price(encode_price(100.25)), 100.25);
+    }
+
+    #[test]
+    fn decoded_book_matches_engine() {
+        let symbol = String::from("REL");
+        let mut engine = MatchingEngine::new();
+        let mut encoder = ItchEncoder::new();
+        let mut book = ItchBook::new();
+        let start = encoder.system_event(SystemEventCode::StartOfMessages, SystemTime::now());
+        book.apply(&ItchMessage::decode(&start.encode()).unwrap())
+            .unwrap();
+
+        let orders = [
+            (EventType::New, order("1", OrderSide::Buy, 100, 99.0)),
+            (EventType::New, order("2", OrderSide::Buy, 100, 99.0)),
+            (EventType::New, order("3", OrderSide::Buy, 60, 98.5)),
+            (EventType::New, order("4", OrderSide::Sell, 100, 101.0)),
+            (EventType::New, order("5", OrderSide::Sell, 130, 99.0)),
+            (EventType::Rpl, order("3", OrderSide::Buy, 80, 98.0)),
+            (EventType::New, order("6", OrderSide::Sell, 20, 101.0)),
+            (EventType::Cxl, order("4", OrderSide::Sell, 100, 101.0)),
+        ];
+        let mut message_types = Vec::new();
+        for (event_type, mut order) in orders {
+            process_event(event_type, &mut order, &mut engine).unwrap();
+            let events = engine.drain_events();
+            for message in encoder.encode(&events, SystemTime::now()) {
+                let bytes = message.encode();
+                message_types.push(bytes[0]);
+                book.apply(&ItchMessage::decode(&bytes).unwrap()).unwrap();
+            }
+        }
+
+        for message_type in [b'R', b'A', b'E', b'U', b'D'] {
+            assert!(message_types.contains(&message_type));
+        }
+        assert_eq!(
+            book.last_system_event(),
+            Some(SystemEventCode::StartOfMessages)
+        );
+        assert_eq!(book.depth(&symbol), engine.full_depth(&symbol));
+        assert_eq!(book.queue(&symbol, OrderSide::Buy, 99.0), vec![70]);
+        assert_eq!(book.queue(&symbol, OrderSide::Buy, 98.0), vec![80]);
+    }
+
+    #[test]
+    fn auction_print_reported_once() {
+        let symbol = String::from("REL");
+        let mut engine = MatchingEngine::new();
+        let mut encoder = ItchEncoder::new();
+        let mut book = ItchBook::new();
+        engine.transition(&symbol, TradingState::Halted).unwrap();
+        engine.transition(&symbol, TradingState::Closed).unwrap();
+        engine.transition(&symbol, TradingState::PreOpen).unwrap();
+        let orders = [
+            order("1", OrderSide::Buy, 100, 101.0),
+            order("2", OrderSide::Buy, 50, 100.0),
+            order("3", OrderSide::Sell, 120, 99.0),
+        ];
+        for mut order in orders {
+            process_event(EventType::New, &mut order, &mut engine).unwrap();
+        }
+        engine
+            .transition(&symbol, TradingState::Continuous)
+            .unwrap();
+
+        let messages = encoder.encode(&engine.drain_events(), SystemTime::now());
+        let mut executed_shares = 0;
+        for message in &messages {
+            assert!(!matches!(message, ItchMessage::Trade { .. }));
+            if let ItchMessage::OrderExecuted {
+                executed_shares_, ..
+            } = message
+            {
+                executed_shares += executed_shares_;
+            }
+            book.apply(message).unwrap();
+        }
+        //both sides of the 120 crossed
+        assert_eq!(executed_shares, 240);
+        assert_eq!(book.depth(&symbol), engine.full_depth(&symbol));
+    }
+}
@@ -0,0 +1,602 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::command::{put_command_input, put_input, put_order_input, EngineCommand, EngineInput};
+use crate::{EventType, MatchingEngine, Order, OrderSide, OrderType, TimeInForce};
+
+//Write-ahead journal
+// Every input, each process_event call and each engine command, is appended to the
+// journal and synced to disk before the engine applies it, rejected ones included,
+// so replaying the journal into an engine set up the same way repeats exactly what
+// the live engine did. A record is the sequence number (8), the payload length (4),
+// the input as laid out in command.rs and a CRC-32 of all of them (4), integers
+// big endian. A record cut short by a crash while it was being
+// written is dropped when the journal is opened again, a damaged record followed
+// by more records means the file is corrupt.
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct JournalRecord {
+    #[cfg_attr(feature = "serde", serde(rename = "seq"))]
+    seq_: u64,
+    #[cfg_attr(feature = "serde", serde(rename = "input"))]
+    input_: EngineInput,
+}
+
+impl JournalRecord {
+    pub fn seq(&self) -> u64 {
+        self.seq_
+    }
+
+    pub fn input(&self) -> &EngineInput {
+        &self.input_
+    }
+}
+
+#[derive(Debug)]
+pub struct Journal {
+    path_: PathBuf,
+    file_: File,
+    last_seq_: u64,
+}
+
+//CRC-32 (IEEE 802.3)
+pub(crate) fn crc32(p_bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in p_bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    return !crc;
+}
+
+//reads the fields written by the put_ functions
+pub(crate) struct Reader<'a> {
+    bytes_: &'a [u8],
+    position_: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(p_bytes: &'a [u8]) -> Self {
+        Reader {
+            bytes_: p_bytes,
+            position_: 0,
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.position_ == self.bytes_.len()
+    }
+
+    pub(crate) fn take(&mut self, p_len: usize) -> Result<&'a [u8], String> {
+        if self.position_ + p_len > self.bytes_.len() {
+            return Err(format!(
+                "Unexpected end of data reading {} bytes at {}",
+                p_len, self.position_
+            ));
+        }
+        let field = &self.bytes_[self.position_..self.position_ + p_len];
+        self.position_ += p_len;
+        return Ok(field);
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn string(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len)?;
+        return String::from_utf8(bytes.to_vec())
+            .map_err(|error| format!("Invalid string: {}", error));
+    }
+
+    pub(crate) fn time(&mut self) -> Result<SystemTime, String> {
+        let seconds = self.u64()?;
+        let nanos = self.u32()?;
+        return Ok(SystemTime::UNIX_EPOCH + Duration::new(seconds, nanos));
+    }
+
+    pub(crate) fn order(&mut self) -> Result<Order, String> {
+        let id = self.string()?;
+        let symbol = self.string()?;
+        let qty = self.u32()? as i32;
+        let price = f32::from_bits(self.u32()?);
+        let entry_time = self.time()?;
+        let side = match self.u8()? {
+            0 => OrderSide::Buy,
+            1 => OrderSide::Sell,
+            side => return Err(format!("Unknown order side {}", side)),
+        };
+        let order_type = match self.u8()? {
+            0 => OrderType::Mkt,
+            1 => OrderType::Limit,
+            2 => OrderType::Moc,
+            3 => OrderType::Loc,
+            order_type => return Err(format!("Unknown order type {}", order_type)),
+        };
+        let time_in_force = match self.u8()? {
+            0 => TimeInForce::Day,
+            1 => TimeInForce::Gtc,
+            time_in_force => return Err(format!("Unknown time in force {}", time_in_force)),
+        };
+        let client_id = self.string()?;
+        let stp_group = match self.u8()? {
+            0 => None,
+            _ => Some(self.string()?),
+        };
+        return Ok(Order {
+            id_: id,
+            symbol_: symbol,
+            qty_: qty,
+            price_: price,
+            entry_time_: entry_time,
+            seq_: 0,
+            side_: side,
+            type_: order_type,
+            time_in_force_: time_in_force,
+            client_id_: client_id,
+            stp_group_: stp_group,
+        });
+    }
+}
+
+pub(crate) fn put_string(p_buffer: &mut Vec<u8>, p_string: &str) {
+    p_buffer.extend_from_slice(&(p_string.len() as u32).to_be_bytes());
+    p_buffer.extend_from_slice(p_string.as_bytes());
+}
+
+pub(crate) fn put_time(p_buffer: &mut Vec<u8>, p_time: SystemTime) {
+    let since_epoch = p_time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    p_buffer.extend_from_slice(&since_epoch.as_secs().to_be_bytes());
+    p_buffer.extend_from_slice(&since_epoch.subsec_nanos().to_be_bytes());
+}
+
+pub(crate) fn put_order(p_buffer: &mut Vec<u8>, p_order: &Order) {
+    put_string(p_buffer, &p_order.id_);
+    put_string(p_buffer, &p_order.symbol_);
+    p_buffer.extend_from_slice(&(p_order.qty_ as u32).to_be_bytes());
+    p_buffer.extend_from_slice(&p_order.price_.to_bits().to_be_bytes());
+    put_time(p_buffer, p_order.entry_time_);
+    p_buffer.push(match p_order.side_ {
+        OrderSide::Buy => 0,
+        OrderSide::Sell => 1,
+    });
+    p_buffer.push(match p_order.type_ {
+        OrderType::Mkt => 0,
+        OrderType::Limit => 1,
+        OrderType::Moc => 2,
+        OrderType::Loc => 3,
+    });
+    p_buffer.push(match p_order.time_in_force_ {
+        TimeInForce::Day => 0,
+        TimeInForce::Gtc => 1,
+    });
+    put_string(p_buffer, &p_order.client_id_);
+    match &p_order.stp_group_ {
+        None => p_buffer.push(0),
+        Some(stp_group) => {
+            p_buffer.push(1);
+            put_string(p_buffer, stp_group);
+        }
+    }
+}
+
+pub(crate) fn event_type_to_byte(p_event_type: EventType) -> u8 {
+    match p_event_type {
+        EventType::New => 0,
+        EventType::Rpl => 1,
+        EventType::Cxl => 2,
+    }
+}
+
+pub(crate) fn event_type_from_byte(p_byte: u8) -> Result<EventType, String> {
+    match p_byte {
+        0 => Ok(EventType::New),
+        1 => Ok(EventType::Rpl),
+        2 => Ok(EventType::Cxl),
+        _ => Err(format!("Unknown event type {}", p_byte)),
+    }
+}
+
+//complete records of p_bytes and the length they span, a torn last record is left out
+fn parse_records(p_bytes: &[u8]) -> Result<(Vec<JournalRecord>, usize), String> {
+    let mut records: Vec<JournalRecord> = Vec::new();
+    let mut reader = Reader::new(p_bytes);
+    let mut valid_len = 0;
+    while !reader.is_empty() {
+        let record_start = reader.position_;
+        let header = match reader.take(12) {
+            Err(_) => break,
+            Ok(header) => header,
+        };
+        let seq = u64::from_be_bytes(header[..8].try_into().unwrap());
+        let payload_len = u32::from_be_bytes(header[8..].try_into().unwrap()) as usize;
+        let (payload, checksum) = match (reader.take(payload_len), reader.u32()) {
+            (Ok(payload), Ok(checksum)) => (payload, checksum),
+            _ => break,
+        };
+        if crc32(&p_bytes[record_start..record_start + 12 + payload_len]) != checksum {
+            if reader.is_empty() {
+                break;
+            }
+            return Err(format!(
+                "Journal record {} at offset {} is corrupt",
+                seq, record_start
+            ));
+        }
+        //a compacted journal does not start at 1
+        let expected_seq = records.last().map_or(seq, |record| record.seq_ + 1);
+        if seq != expected_seq {
+            return Err(format!(
+                "Journal record {} found where {} was expected",
+                seq, expected_seq
+            ));
+        }
+
+        let input = Reader::new(payload).input()?;
+        records.push(JournalRecord {
+            seq_: seq,
+            input_: input,
+        });
+        valid_len = reader.position_;
+    }
+    return Ok((records, valid_len));
+}
+
+impl Journal {
+    //opens or creates the journal at p_path, appending after its last complete record
+    pub fn open(p_path: &Path) -> Result<Journal, String> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(p_path)
+            .map_err(|error| format!("Failed to open journal {}: {}", p_path.display(), error))?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)
+            .map_err(|error| format!("Failed to read journal {}: {}", p_path.display(), error))?;
+        let (records, valid_len) = parse_records(&bytes)?;
+        if valid_len < bytes.len() {
+            println!(
+                "Dropping {} bytes of torn record at the end of journal {}",
+                bytes.len() - valid_len,
+                p_path.display()
+            );
+            file.set_len(valid_len as u64).map_err(|error| {
+                format!("Failed to truncate journal {}: {}", p_path.display(), error)
+            })?;
+        }
+        return Ok(Journal {
+            path_: p_path.to_path_buf(),
+            file_: file,
+            last_seq_: records.last().map_or(0, |record| record.seq_),
+        });
+    }
+
+    //all complete records of the journal at p_path in sequence
+    pub fn read(p_path: &Path) -> Result<Vec<JournalRecord>, String> {
+        let bytes = std::fs::read(p_path)
+            .map_err(|error| format!("Failed to read journal {}: {}", p_path.display(), error))?;
+        let (records, _) = parse_records(&bytes)?;
+        return Ok(records);
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path_
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq_
+    }
+
+    //writes and syncs the record of an order input, returns its sequence number
+    pub fn append(&mut self, p_event_type: EventType, p_order: &Order) -> Result<u64, String> {
+        let mut payload = Vec::new();
+        put_order_input(&mut payload, p_event_type, p_order);
+        return self.write_record(&payload);
+    }
+
+    //writes and syncs the record of p_command accepted at p_time, returns its sequence number
+    pub fn append_command(
+        &mut self,
+        p_time: SystemTime,
+        p_command: &EngineCommand,
+    ) -> Result<u64, String> {
+        let mut payload = Vec::new();
+        put_command_input(&mut payload, p_time, p_command);
+        return self.write_record(&payload);
+    }
+
+    fn write_record(&mut self, p_payload: &[u8]) -> Result<u64, String> {
+        let seq = self.last_seq_ + 1;
+        let record = encode_record(seq, p_payload);
+        self.file_
+            .write_all(&record)
+            .and_then(|_| self.file_.sync_data())
+            .map_err(|error| format!("Failed to write journal record {}: {}", seq, error))?;
+        self.last_seq_ = seq;
+        return Ok(seq);
+    }
+
+    //drops the records up to p_seq, covered by a snapshot. The last record is
+    //always kept so the journal still knows its sequence number
+    pub fn compact(&mut self, p_seq: u64) -> Result<(), String> {
+        let records = Journal::read(&self.path_)?;
+        let keep_from = records
+            .iter()
+            .position(|record| record.seq_ > p_seq)
+            .unwrap_or(records.len().saturating_sub(1));
+        let mut bytes = Vec::new();
+        for record in &records[keep_from..] {
+            let mut payload = Vec::new();
+            put_input(&mut payload, &record.input_);
+            bytes.extend(encode_record(record.seq_, &payload));
+        }
+
+        let compacted_path = self.path_.with_extension("compact");
+        std::fs::write(&compacted_path, &bytes)
+            .and_then(|_| std::fs::rename(&compacted_path, &self.path_))
+            .map_err(|error| {
+                format!(
+                    "Failed to compact journal {}: {}",
+                    self.path_.display(),
+                    error
+                )
+            })?;
+        *self = Journal::open(&self.path_)?;
+        return Ok(());
+    }
+}
+
+fn encode_record(p_seq: u64, p_payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(p_payload.len() + 16);
+    record.extend_from_slice(&p_seq.to_be_bytes());
+    record.extend_from_slice(&(p_payload.len() as u32).to_be_bytes());
+    record.extend_from_slice(p_payload);
+    let checksum = crc32(&record);
+    record.extend_from_slice(&checksum.to_be_bytes());
+    return record;
+}
+
+#[cfg(test)]
+impl Journal {
+    //a journal at p_path every append fails on, its file is only open for reading
+    pub(crate) fn read_only(p_path: &Path) -> Journal {
+        Journal {
+            path_: p_path.to_path_buf(),
+            file_: File::open(p_path).unwrap(),
+            last_seq_: 0,
+        }
+    }
+}
+
+impl MatchingEngine {
+    //from now on every input is journaled before being applied
+    pub fn attach_journal(&mut self, p_journal: Journal) {
+        self.journal_ = Some(p_journal);
+    }
+
+    pub fn detach_journal(&mut self) -> Option<Journal> {
+        self.journal_.take()
+    }
+
+    pub fn journal(&self) -> Option<&Journal> {
+        self.journal_.as_ref()
+    }
+
+    pub(crate) fn journal_event(
+        &mut self,
+        p_event_type: EventType,
+        p_order: &Order,
+    ) -> Result<(), String> {
+        if let Some(journal) = &mut self.journal_ {
+            journal.append(p_event_type, p_order)?;
+        }
+        return Ok(());
+    }
+
+    pub(crate) fn journal_command(
+        &mut self,
+        p_time: SystemTime,
+        p_command: &EngineCommand,
+    ) -> Result<(), String> {
+        if let Some(journal) = &mut self.journal_ {
+            journal.append_command(p_time, p_command)?;
+        }
+        return Ok(());
+    }
+
+    //replays p_records at their acceptance time, results are the ones the live
+    //engine got already, rejections included. An attached journal records them again
+    pub fn replay(&mut self, p_records: &[JournalRecord]) {
+        for record in p_records {
+            if let Err(error_msg) = self.apply_input(&record.input_) {
+                println!(
+                    "Replayed journal record {} failed again: {}",
+                    record.seq_, error_msg
+                );
+            }
+        }
+    }
+
+    //engine rebuilt from the journal at p_path, with the journal attached again
+    pub fn recover(p_path: &Path) -> Result<MatchingEngine, String> {
+        let records = Journal::read(p_path)?;
+        let mut engine = MatchingEngine::new();
+        engine.replay(&records);
+        engine.attach_journal(Journal::open(p_path)?);
+        return Ok(engine);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::session::{HaltOrderPolicy, TradingState};
+    use crate::test_order;
+    use crate::process_event;
+
+    fn order(p_id: &str, p_side: OrderSide, p_qty: i32, p_price: f32) -> Order {
+        test_order(p_id, p_side, p_qty, p_price)
+            .with_client("C1")
+            .with_stp_group("G1")
+    }
+
+    fn journal_path(p_name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("journal_{}_{}.bin", std::process::id(), p_name));
+        let _ = std::fs::remove_file(&path);
+        return path;
+    }
+
+    #[test]
+    fn recovered_engine_matches() {
+        let path = journal_path("recover");
+        let symbol = String::from("REL");
+        let mut engine = MatchingEngine::new();
+        engine.attach_journal(Journal::open(&path).unwrap());
+        let events = [
+            (EventType::New, order("1", OrderSide::Buy, 100, 99.0)),
+            (EventType::New, order("2", OrderSide::Buy, 100, 99.0)),
+            (EventType::New, order("3", OrderSide::Sell, 50, 101.0)),
+            (EventType::New, order("4", OrderSide::Sell, 120, 99.0)),
+            (EventType::Rpl, order("3", OrderSide::Sell, 70, 100.0)),
+            //rejected, journaled all the same
+            (EventType::Cxl, order("9", OrderSide::Sell, 10, 100.0)),
+            (EventType::Cxl, order("2", OrderSide::Buy, 80, 99.0)),
+        ];
+        for (event_type, mut order) in events {
+            let _ = process_event(event_type, &mut order, &mut engine);
+        }
+        assert_eq!(engine.journal().unwrap().last_seq(), 7);
+
+        let mut recovered = MatchingEngine::recover(&path).unwrap();
+        assert_eq!(
+            format!("{:?}", recovered.order_book_by_symbol_[&symbol]),
+            format!("{:?}", engine.order_book_by_symbol_[&symbol])
+        );
+        assert_eq!(
+            recovered.trade_statistics(&symbol),
+            engine.trade_statistics(&symbol)
+        );
+
+        //new events continue the sequence
+        let mut buy = order("5", OrderSide::Buy, 10, 98.0);
+        process_event(EventType::New, &mut buy, &mut recovered).unwrap();
+        assert_eq!(recovered.journal().unwrap().last_seq(), 8);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn session_changes_replayed() {
+        let path = journal_path("session");
+        let symbol = String::from("REL");
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let clock = ManualClock::new(start);
+        let queueing_engine = || {
+            let mut engine = MatchingEngine::new();
+            engine.set_halt_order_policy(HaltOrderPolicy::Queue);
+            engine
+        };
+        let mut engine = queueing_engine();
+        engine.set_clock(clock.clone());
+        engine.attach_journal(Journal::open(&path).unwrap());
+
+        //crossing orders queued while halted
+        engine.halt(&symbol, "news pending").unwrap();
+        for (id, side) in [("1", OrderSide::Buy), ("2", OrderSide::Sell)] {
+            let mut order = test_order(id, side, 100, 100.0);
+            let result = process_event(EventType::New, &mut order, &mut engine).unwrap();
+            assert!(result.is_none());
+        }
+        let mut replayed = queueing_engine();
+        replayed.replay(&Journal::read(&path).unwrap());
+        assert!(replayed.trade_statistics(&symbol).is_none());
+        assert_eq!(replayed.trading_state(&symbol), TradingState::Halted);
+        assert_eq!(replayed.state_hash(), engine.state_hash());
+
+        //then uncrossed at the time the transition was accepted at
+        clock.advance(Duration::from_secs(5));
+        engine
+            .resume(&symbol, TradingState::OpeningAuction)
+            .unwrap();
+        clock.advance(Duration::from_secs(5));
+        let uncross_result = engine
+            .transition(&symbol, TradingState::Continuous)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            uncross_result.fills_[0].time(),
+            start + Duration::from_secs(10)
+        );
+        engine.purge_day_orders().unwrap();
+        assert_eq!(engine.journal().unwrap().last_seq(), 6);
+
+        let mut replayed = queueing_engine();
+        replayed.replay(&Journal::read(&path).unwrap());
+        assert_eq!(replayed.input_seq(), engine.input_seq());
+        assert_eq!(replayed.state_hash(), engine.state_hash());
+        assert_eq!(replayed.drain_events(), engine.drain_events());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn torn_and_corrupt_records() {
+        let path = journal_path("torn");
+        let mut journal = Journal::open(&path).unwrap();
+        for id in ["1", "2", "3"] {
+            journal
+                .append(EventType::New, &order(id, OrderSide::Buy, 10, 99.0))
+                .unwrap();
+        }
+        drop(journal);
+        let bytes = std::fs::read(&path).unwrap();
+
+        //crash in the middle of the last record
+        std::fs::write(&path, &bytes[..bytes.len() - 5]).unwrap();
+        assert_eq!(Journal::read(&path).unwrap().len(), 2);
+        let journal = Journal::open(&path).unwrap();
+        assert_eq!(journal.last_seq(), 2);
+        drop(journal);
+        let record_len = bytes.len() / 3;
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len() as usize,
+            2 * record_len
+        );
+
+        //damaged first record
+        let mut corrupt = bytes.clone();
+        corrupt[20] ^= 0xFF;
+        std::fs::write(&path, &corrupt).unwrap();
+        assert!(Journal::read(&path).is_err());
+        assert!(Journal::open(&path).is_err());
+
+        let records = {
+            std::fs::write(&path, &bytes).unwrap();
+            Journal::read(&path).unwrap()
+        };
+        assert!(matches!(
+            records[2].input(),
+            EngineInput::Order { order_, .. } if order_.stp_group_ == Some(String::from("G1"))
+        ));
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        let _ = std::fs::remove_file(&path);
+    }
+}
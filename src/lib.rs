@@ -1,35 +1,103 @@
 use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::collections::BTreeSet;
-use std::collections::HashMap;
 use std::time::SystemTime;
 
+pub mod analytics;
+pub mod audit;
+mod auction;
+pub mod bars;
+pub mod clock;
+mod closing;
+pub mod command;
+pub mod conflation;
+pub mod depth_feed;
+pub mod distribution;
+pub mod events;
+mod gtc;
+mod halt;
+pub mod itch;
+pub mod journal;
+pub mod luld;
+pub mod market_data;
+pub mod order_feed;
+mod replay;
+pub mod replication;
+pub mod sequencer;
+pub mod session;
+mod snapshot;
+pub mod status;
+pub mod stp;
+pub mod ticker;
+
+use analytics::SpreadTracker;
+use audit::AuditTrail;
+use bars::BarAggregator;
+use clock::{Clock, SystemClock};
+use closing::ClosingBook;
+use command::EngineCommand;
+use depth_feed::DepthHistory;
+use events::EngineEvent;
+use journal::Journal;
+use luld::PriceBand;
+use order_feed::{Fill, OrderFeedMessage, OrderUpdate};
+use session::{HaltOrderPolicy, TradingSchedule, TradingSession, TradingState};
+use status::OrderProgress;
+use stp::StpMode;
+use ticker::TradeStatistics;
+
 //Order
 // TODO:: Find a way to attach these enums to the Order struct only and not a global enums
 // TODO:: Fix the string types in this project, currently all of them are owned strings
 
 #[derive(Clone, Debug, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum OrderSide {
     Buy,
     Sell,
 }
 
 #[derive(Clone, Debug, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum OrderType {
     Mkt,
     Limit,
+    Moc,
+    Loc,
+}
+
+// DAY orders are purged at the end of the day, GTC ones rest until cancelled
+#[derive(Clone, Debug, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TimeInForce {
+    #[default]
+    Day,
+    Gtc,
 }
 
 #[derive(Clone, Debug, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum EventType {
     New,
     Rpl,
     Cxl,
 }
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MatchingResult {
+    #[cfg_attr(feature = "serde", serde(rename = "matched_order_ids"))]
     matched_order_ids_: Vec<String>,
+    #[cfg_attr(feature = "serde", serde(rename = "executed_qty"))]
     executed_qty_: i32,
+    #[cfg_attr(feature = "serde", serde(rename = "executed_price"))]
     executed_price_: f32,
+    // qty of the incoming order cancelled by self-trade prevention
+    #[cfg_attr(feature = "serde", serde(rename = "aggressor_cancelled_qty"))]
+    aggressor_cancelled_qty_: i32,
+    #[cfg_attr(feature = "serde", serde(rename = "self_trade_events"))]
+    self_trade_events_: Vec<EngineEvent>,
+    #[cfg_attr(feature = "serde", serde(rename = "fills"))]
+    fills_: Vec<Fill>,
 }
 
 impl MatchingResult {
@@ -38,6 +106,9 @@ impl MatchingResult {
             matched_order_ids_: Vec::new(),
             executed_qty_: 0,
             executed_price_: 0.0,
+            aggressor_cancelled_qty_: 0,
+            self_trade_events_: Vec::new(),
+            fills_: Vec::new(),
         }
     }
 }
@@ -65,14 +136,32 @@ impl Ord for MatchingResult {
 impl Eq for MatchingResult {}
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Order {
+    #[cfg_attr(feature = "serde", serde(rename = "id"))]
     id_: String,
+    #[cfg_attr(feature = "serde", serde(rename = "symbol"))]
     symbol_: String,
+    #[cfg_attr(feature = "serde", serde(rename = "qty"))]
     qty_: i32,
+    #[cfg_attr(feature = "serde", serde(rename = "price"))]
     price_: f32,
+    #[cfg_attr(feature = "serde", serde(rename = "entry_time"))]
     entry_time_: SystemTime,
+    // engine input sequence the order arrived with, breaks entry time ties
+    #[cfg_attr(feature = "serde", serde(rename = "seq", default))]
+    seq_: u64,
+    #[cfg_attr(feature = "serde", serde(rename = "side"))]
     side_: OrderSide,
+    #[cfg_attr(feature = "serde", serde(rename = "type"))]
     type_: OrderType,
+    #[cfg_attr(feature = "serde", serde(rename = "time_in_force", default))]
+    time_in_force_: TimeInForce,
+    #[cfg_attr(feature = "serde", serde(rename = "client_id"))]
+    client_id_: String,
+    // orders sharing a group never trade with each other, defaults to the client id
+    #[cfg_attr(feature = "serde", serde(rename = "stp_group"))]
+    stp_group_: Option<String>,
 }
 
 impl PartialOrd for Order {
@@ -92,6 +181,7 @@ impl Ord for Order {
         self.entry_time_
             .partial_cmp(&other.entry_time_)
             .unwrap_or(Ordering::Equal)
+            .then(self.seq_.cmp(&other.seq_))
     }
 }
 
@@ -139,6 +229,14 @@ impl Level {
         }
     }
 
+    fn new(p_price: f32, p_side: OrderSide) -> Self {
+        Level {
+            price_: p_price,
+            orders_: BTreeSet::new(),
+            side_: p_side,
+        }
+    }
+
     fn from_order(p_order: &Order) -> Self {
         let new_level = Level {
             price_: p_order.price_,
@@ -163,7 +261,11 @@ impl Level {
         return self.orders_.remove(p_remove_order);
     }
 
-    fn match_order(&mut self, p_order: &mut Order) -> Result<Option<MatchingResult>, String> {
+    fn match_order(
+        &mut self,
+        p_order: &mut Order,
+        p_stp_mode: StpMode,
+    ) -> Result<Option<MatchingResult>, String> {
         //match the qty
         //step 1: get copy of first order
         //step 2: if p_order qty is == first order then remove that order and return total qty of current order as Ok
@@ -184,18 +286,36 @@ impl Level {
                 }
 
                 Some(first_order) => {
+                    if p_order.is_self_trade(first_order) {
+                        remaining_qty =
+                            self.prevent_self_trade(p_order, remaining_qty, p_stp_mode, &mut result);
+                        continue;
+                    }
+
                     result.matched_order_ids_.push(first_order.id_.to_owned());
                     let mut copy_of_first_order = (*first_order).clone();
                     println!("match found order:\n\t {:?}", copy_of_first_order);
 
                     if remaining_qty == copy_of_first_order.qty_ {
                         //remove order and return exec qty
+                        result.fills_.push(Fill::new(
+                            &copy_of_first_order.id_,
+                            self.side_,
+                            copy_of_first_order.price_,
+                            remaining_qty,
+                        ));
                         copy_of_first_order.qty_ = 0;
-                        executed_qty = remaining_qty;
+                        executed_qty += remaining_qty;
+                        avg_matched_price += copy_of_first_order.price_ * remaining_qty as f32;
                         remaining_qty = 0;
-                        avg_matched_price += copy_of_first_order.price_ * executed_qty as f32;
                         self.orders_.pop_first();
                     } else if remaining_qty < copy_of_first_order.qty_ {
+                        result.fills_.push(Fill::new(
+                            &copy_of_first_order.id_,
+                            self.side_,
+                            copy_of_first_order.price_,
+                            remaining_qty,
+                        ));
                         executed_qty += remaining_qty;
                         copy_of_first_order.qty_ -= remaining_qty;
                         avg_matched_price += copy_of_first_order.price_ * remaining_qty as f32;
@@ -205,6 +325,12 @@ impl Level {
                         println!("Orders in level after this match:\n\t {:?}", self.orders_);
                     } else if remaining_qty > copy_of_first_order.qty_ {
                         let being_executed = copy_of_first_order.qty_;
+                        result.fills_.push(Fill::new(
+                            &copy_of_first_order.id_,
+                            self.side_,
+                            copy_of_first_order.price_,
+                            being_executed,
+                        ));
                         copy_of_first_order.qty_ -= 0;
                         executed_qty += being_executed;
                         remaining_qty -= being_executed;
@@ -227,12 +353,45 @@ impl Level {
 
 #[derive(Debug)]
 struct OrderBook {
+    symbol_: String,
     bids_: BTreeSet<Level>,
     asks_: BTreeSet<Level>,
+    // side and price of every resting order by id
+    order_index_: BTreeMap<String, (OrderSide, f32)>,
+    // level 3 messages not yet collected by the engine
+    feed_: Vec<OrderFeedMessage>,
+    feed_seq_: u64,
+    trade_seq_: u64,
+    // side and price of the levels changed since the depth feed last looked
+    touched_levels_: Vec<(OrderSide, f32)>,
 }
 
 impl OrderBook {
+    fn new(p_symbol: &String) -> Self {
+        OrderBook {
+            symbol_: p_symbol.to_owned(),
+            bids_: BTreeSet::new(),
+            asks_: BTreeSet::new(),
+            order_index_: BTreeMap::new(),
+            feed_: Vec::new(),
+            feed_seq_: 0,
+            trade_seq_: 0,
+            touched_levels_: Vec::new(),
+        }
+    }
+
+    fn publish_add(&mut self, p_order: &Order) {
+        self.publish(OrderUpdate::Add {
+            order_id_: p_order.id_.to_owned(),
+            side_: p_order.side_,
+            price_: p_order.price_,
+            qty_: p_order.qty_,
+        });
+    }
+
     fn add_first_order(&mut self, p_order: &mut Order) -> Result<Option<MatchingResult>, String> {
+        self.publish_add(p_order);
+        self.index_order(p_order);
         match p_order.side_ {
             OrderSide::Buy => {
                 self.bids_.insert(Level::from_first_order(p_order));
@@ -246,53 +405,49 @@ impl OrderBook {
         }
     }
 
+    fn best_bid_price(&self) -> Option<f32> {
+        self.bids_.first().map(|level| level.price_)
+    }
+
+    fn best_ask_price(&self) -> Option<f32> {
+        self.asks_.first().map(|level| level.price_)
+    }
+
     fn get_level_match(&self, p_input_order: &Order) -> Option<&Level> {
         match p_input_order.side_ {
             OrderSide::Buy => match p_input_order.type_ {
-                OrderType::Mkt => {
+                OrderType::Mkt | OrderType::Moc => {
                     return self.asks_.first();
                 }
-                OrderType::Limit => {
-                    return self.asks_.get(&Level::from_order(p_input_order));
+                OrderType::Limit | OrderType::Loc => {
+                    //probe with the side of the searched set, its ordering depends on it
+                    return self.asks_.get(&Level::new(p_input_order.price_, OrderSide::Sell));
                 }
             },
             OrderSide::Sell => match p_input_order.type_ {
-                OrderType::Mkt => {
+                OrderType::Mkt | OrderType::Moc => {
                     return self.bids_.first();
                 }
-                OrderType::Limit => {
-                    return self.bids_.get(&Level::from_order(p_input_order));
+                OrderType::Limit | OrderType::Loc => {
+                    return self.bids_.get(&Level::new(p_input_order.price_, OrderSide::Buy));
                 }
             },
         }
     }
 
     fn get_level_match_from_id(&self, p_order: &Order) -> Option<(&Level, &Order)> {
-        match p_order.side_ {
-            OrderSide::Buy => {
-                for level in &self.bids_ {
-                    for order in &level.orders_ {
-                        if order.id_ == p_order.id_ {
-                            return Some((level, order));
-                        }
-                    }
-                }
-            }
-
-            OrderSide::Sell => {
-                for level in &self.asks_ {
-                    for order in &level.orders_ {
-                        if order.id_ == p_order.id_ {
-                            return Some((level, order));
-                        }
-                    }
-                }
-            }
+        let (level, order) = self.find_order(&p_order.id_)?;
+        if level.side_ != p_order.side_ {
+            return None;
         }
-        return None;
+        return Some((level, order));
     }
 
-    fn match_order(&mut self, p_order: &mut Order) -> Result<Option<MatchingResult>, String> {
+    fn match_order(
+        &mut self,
+        p_order: &mut Order,
+        p_stp_mode: StpMode,
+    ) -> Result<Option<MatchingResult>, String> {
         let found_level = self.get_level_match(p_order);
         match found_level {
             None => {
@@ -301,10 +456,16 @@ impl OrderBook {
             Some(matched_level) => {
                 println!("Matched to {:?}", matched_level);
                 let mut copy_of_matched_level = (*matched_level).clone();
-                let match_result = copy_of_matched_level.match_order(p_order)?;
+                let mut match_result = copy_of_matched_level.match_order(p_order, p_stp_mode)?;
 
-                if match_result.is_none() {
-                    return Ok(None);
+                match &mut match_result {
+                    None => {
+                        return Ok(None);
+                    }
+                    Some(result) => {
+                        self.publish_executions(result, None);
+                        self.publish_self_trades(&copy_of_matched_level, result);
+                    }
                 }
 
                 match p_order.side_ {
@@ -323,6 +484,9 @@ impl OrderBook {
                         }
                     }
                 }
+                if let Some(result) = &match_result {
+                    self.unindex_executed(result);
+                }
                 println!("After match {:?}", self);
                 return Ok(match_result);
             }
@@ -330,7 +494,9 @@ impl OrderBook {
     }
 
     fn add_order(&mut self, p_order: &mut Order) {
-        let mut temp_level = Level::from_order(&p_order);
+        self.publish_add(p_order);
+        self.index_order(p_order);
+        let mut temp_level = Level::from_order(p_order);
         match p_order.side_ {
             OrderSide::Buy => {
                 let found_level = self.bids_.get(&temp_level);
@@ -379,6 +545,12 @@ impl OrderBook {
                 let mut copy_of_found_level = (*matched_level).clone();
                 let copy_of_found_order = (*matched_order).clone();
                 if copy_of_found_level.remove_order(&copy_of_found_order) {
+                    self.order_index_.remove(&copy_of_found_order.id_);
+                    self.publish(OrderUpdate::Delete {
+                        order_id_: copy_of_found_order.id_,
+                        side_: copy_of_found_level.side_,
+                        price_: copy_of_found_level.price_,
+                    });
                     match p_order.side_ {
                         OrderSide::Buy => {
                             if copy_of_found_level.orders_.is_empty() {
@@ -408,14 +580,122 @@ impl OrderBook {
 
 #[derive(Debug)]
 pub struct MatchingEngine {
-    order_book_by_symbol_: HashMap<String, OrderBook>,
+    order_book_by_symbol_: BTreeMap<String, OrderBook>,
+    session_by_symbol_: BTreeMap<String, TradingSession>,
+    halt_order_policy_: HaltOrderPolicy,
+    market_halted_: bool,
+    price_band_by_symbol_: BTreeMap<String, PriceBand>,
+    closing_book_by_symbol_: BTreeMap<String, ClosingBook>,
+    stp_mode_: StpMode,
+    depth_history_by_symbol_: BTreeMap<String, DepthHistory>,
+    statistics_by_symbol_: BTreeMap<String, TradeStatistics>,
+    bars_by_symbol_: BTreeMap<String, Vec<BarAggregator>>,
+    spread_by_symbol_: BTreeMap<String, SpreadTracker>,
+    journal_: Option<Journal>,
+    audit_trail_: Option<AuditTrail>,
+    // every order seen so far by id, rejected ones included
+    order_progress_: BTreeMap<String, OrderProgress>,
+    // inputs processed so far and the hash of everything they produced
+    input_seq_: u64,
+    state_hash_: u64,
+    clock_: Box<dyn Clock>,
+    events_: Vec<EngineEvent>,
+}
+
+impl Default for MatchingEngine {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MatchingEngine {
-    pub fn process_new_order(
+    pub fn new() -> Self {
+        MatchingEngine {
+            order_book_by_symbol_: BTreeMap::new(),
+            session_by_symbol_: BTreeMap::new(),
+            halt_order_policy_: HaltOrderPolicy::Reject,
+            market_halted_: false,
+            price_band_by_symbol_: BTreeMap::new(),
+            closing_book_by_symbol_: BTreeMap::new(),
+            stp_mode_: StpMode::CancelNewest,
+            depth_history_by_symbol_: BTreeMap::new(),
+            statistics_by_symbol_: BTreeMap::new(),
+            bars_by_symbol_: BTreeMap::new(),
+            spread_by_symbol_: BTreeMap::new(),
+            journal_: None,
+            audit_trail_: None,
+            order_progress_: BTreeMap::new(),
+            input_seq_: 0,
+            state_hash_: replay::INITIAL_STATE_HASH,
+            clock_: Box::new(SystemClock),
+            events_: Vec::new(),
+        }
+    }
+
+    //the order paths behind apply_event, orders from outside go through process_event
+    //to be journaled, numbered and audited
+    pub(crate) fn process_new_order(
+        &mut self,
+        p_order: &mut Order,
+    ) -> Result<Option<MatchingResult>, String> {
+        self.apply_price_band(p_order)?;
+        let match_result = self.execute_new_order(p_order);
+        self.collect_book_changes(&p_order.symbol_, p_order.entry_time_);
+        let mut match_result = match_result?;
+        MatchingEngine::stamp_fills(&mut match_result, p_order.entry_time_);
+        self.record_self_trades(&match_result);
+        self.record_trades(
+            &p_order.symbol_,
+            Some(p_order),
+            &match_result,
+            p_order.entry_time_,
+        );
+        self.update_price_band(&p_order.symbol_, &match_result, p_order.entry_time_)?;
+        return Ok(match_result);
+    }
+
+    pub(crate) fn process_rpl_order(
         &mut self,
         p_order: &mut Order,
     ) -> Result<Option<MatchingResult>, String> {
+        self.apply_price_band(p_order)?;
+        let match_result = self.execute_rpl_order(p_order);
+        self.collect_book_changes(&p_order.symbol_, p_order.entry_time_);
+        let mut match_result = match_result?;
+        MatchingEngine::stamp_fills(&mut match_result, p_order.entry_time_);
+        self.record_self_trades(&match_result);
+        self.record_trades(
+            &p_order.symbol_,
+            Some(p_order),
+            &match_result,
+            p_order.entry_time_,
+        );
+        self.update_price_band(&p_order.symbol_, &match_result, p_order.entry_time_)?;
+        return Ok(match_result);
+    }
+
+    fn execute_new_order(
+        &mut self,
+        p_order: &mut Order,
+    ) -> Result<Option<MatchingResult>, String> {
+        if self.is_live_order(&p_order.symbol_, &p_order.id_) {
+            return Err(format!("Order {} is already live", p_order.id_));
+        }
+        if p_order.is_on_close() {
+            self.accept_on_close(p_order)?;
+            return Ok(None);
+        }
+
+        let stp_mode = self.stp_mode_;
+        let trading_state = self.trading_state(&p_order.symbol_);
+        if trading_state == TradingState::Halted
+            && self.halt_order_policy_ == HaltOrderPolicy::Queue
+        {
+            self.queue_order(p_order);
+            return Ok(None);
+        }
+        trading_state.validate(EventType::New, p_order)?;
+
         let order_book_or_error = self.get_book_by_symbol(&p_order.symbol_);
         match order_book_or_error {
             None => {
@@ -428,7 +708,12 @@ impl MatchingEngine {
             }
 
             Some(order_book) => {
-                let matching_result_or_none = order_book.match_order(p_order)?;
+                if !trading_state.is_matching() {
+                    order_book.add_order(p_order);
+                    return Ok(None);
+                }
+
+                let matching_result_or_none = order_book.match_order(p_order, stp_mode)?;
                 match matching_result_or_none {
                     None => {
                         order_book.add_order(p_order);
@@ -436,7 +721,8 @@ impl MatchingEngine {
                         return Ok(None);
                     }
                     Some(match_result) => {
-                        p_order.qty_ -= match_result.executed_qty_;
+                        p_order.qty_ -=
+                            match_result.executed_qty_ + match_result.aggressor_cancelled_qty_;
                         if p_order.qty_ > 0 {
                             order_book.add_order(p_order);
                         }
@@ -451,10 +737,29 @@ impl MatchingEngine {
         }
     }
 
-    pub fn process_rpl_order(
+    fn execute_rpl_order(
         &mut self,
         p_order: &mut Order,
     ) -> Result<Option<MatchingResult>, String> {
+        if self.is_resting_on_close(p_order) {
+            self.replace_on_close(p_order)?;
+            return Ok(None);
+        }
+        if p_order.is_on_close() {
+            return Err(format!(
+                "Order {} can not be replaced by a {:?} order",
+                p_order.id_, p_order.type_
+            ));
+        }
+        if self.is_queued(p_order) {
+            self.replace_queued(p_order);
+            return Ok(None);
+        }
+
+        let stp_mode = self.stp_mode_;
+        let trading_state = self.trading_state(&p_order.symbol_);
+        trading_state.validate(EventType::Rpl, p_order)?;
+
         let order_book_or_error = self.get_book_by_symbol(&p_order.symbol_);
         match order_book_or_error {
             None => {
@@ -471,7 +776,12 @@ impl MatchingEngine {
                     ));
                 }
 
-                let matching_result_or_none = order_book.match_order(p_order)?;
+                if !trading_state.is_matching() {
+                    order_book.add_order(p_order);
+                    return Ok(None);
+                }
+
+                let matching_result_or_none = order_book.match_order(p_order, stp_mode)?;
                 match matching_result_or_none {
                     None => {
                         order_book.add_order(p_order);
@@ -479,7 +789,8 @@ impl MatchingEngine {
                         return Ok(None);
                     }
                     Some(match_result) => {
-                        p_order.qty_ -= match_result.executed_qty_;
+                        p_order.qty_ -=
+                            match_result.executed_qty_ + match_result.aggressor_cancelled_qty_;
                         if p_order.qty_ > 0 {
                             order_book.add_order(p_order);
                         }
@@ -494,10 +805,30 @@ impl MatchingEngine {
         }
     }
 
-    pub fn process_cxl_order(
+    pub(crate) fn process_cxl_order(
         &mut self,
         p_order: &mut Order,
     ) -> Result<Option<MatchingResult>, String> {
+        let cancel_result = self.execute_cxl_order(p_order);
+        self.collect_book_changes(&p_order.symbol_, p_order.entry_time_);
+        return cancel_result;
+    }
+
+    fn execute_cxl_order(
+        &mut self,
+        p_order: &mut Order,
+    ) -> Result<Option<MatchingResult>, String> {
+        if self.is_resting_on_close(p_order) {
+            self.cancel_on_close(p_order)?;
+            return Ok(None);
+        }
+        if self.is_queued(p_order) {
+            self.cancel_queued(p_order);
+            return Ok(None);
+        }
+        self.trading_state(&p_order.symbol_)
+            .validate(EventType::Cxl, p_order)?;
+
         let order_book_or_error = self.get_book_by_symbol(&p_order.symbol_);
         match order_book_or_error {
             None => {
@@ -519,6 +850,144 @@ impl MatchingEngine {
         }
     }
 
+    pub fn trading_state(&self, p_symbol: &String) -> TradingState {
+        match self.session_by_symbol_.get(p_symbol) {
+            None if self.market_halted_ => TradingState::Halted,
+            None => TradingState::Continuous,
+            Some(session) => session.state_,
+        }
+    }
+
+    pub fn set_schedule(&mut self, p_symbol: &String, p_schedule: TradingSchedule) {
+        self.session_mut(p_symbol).schedule_ = Some(p_schedule);
+    }
+
+    pub fn set_stp_mode(&mut self, p_stp_mode: StpMode) {
+        self.stp_mode_ = p_stp_mode;
+    }
+
+    fn record_self_trades(&mut self, p_result: &Option<MatchingResult>) {
+        if let Some(match_result) = p_result {
+            self.events_
+                .extend(match_result.self_trade_events_.iter().cloned());
+        }
+    }
+
+    pub fn set_halt_order_policy(&mut self, p_policy: HaltOrderPolicy) {
+        self.halt_order_policy_ = p_policy;
+    }
+
+    pub fn drain_events(&mut self) -> Vec<EngineEvent> {
+        std::mem::take(&mut self.events_)
+    }
+
+    //manual transition, uncrosses the book when a call period ends
+    pub fn transition(
+        &mut self,
+        p_symbol: &String,
+        p_state: TradingState,
+    ) -> Result<Option<MatchingResult>, String> {
+        let command = EngineCommand::Transition {
+            symbol_: p_symbol.to_owned(),
+            state_: p_state,
+        };
+        return self.process_command(&command, |engine| {
+            engine.change_state(p_symbol, p_state, "manual")
+        });
+    }
+
+    //moves every scheduled symbol into the state its schedule expects at p_now,
+    //halted symbols are left alone until they are resumed manually
+    pub fn apply_schedule(&mut self, p_now: SystemTime) -> Result<Vec<MatchingResult>, String> {
+        let command = EngineCommand::ApplySchedule { now_: p_now };
+        return self.process_command(&command, |engine| engine.enter_scheduled_states(p_now));
+    }
+
+    fn enter_scheduled_states(
+        &mut self,
+        p_now: SystemTime,
+    ) -> Result<Vec<MatchingResult>, String> {
+        self.advance_time(p_now);
+        let mut due_transitions = Vec::new();
+        for (symbol, session) in &self.session_by_symbol_ {
+            if session.state_ == TradingState::Halted {
+                continue;
+            }
+            if let Some(schedule) = &session.schedule_ {
+                if let Some(scheduled_state) = schedule.state_at(p_now) {
+                    if session.state_.can_transition_to(scheduled_state) {
+                        due_transitions.push((symbol.to_owned(), scheduled_state));
+                    }
+                }
+            }
+        }
+
+        let mut uncross_results = Vec::new();
+        for (symbol, scheduled_state) in due_transitions {
+            if let Some(uncross_result) =
+                self.change_state(&symbol, scheduled_state, "schedule")?
+            {
+                uncross_results.push(uncross_result);
+            }
+        }
+        return Ok(uncross_results);
+    }
+
+    fn change_state(
+        &mut self,
+        p_symbol: &String,
+        p_state: TradingState,
+        p_reason: &str,
+    ) -> Result<Option<MatchingResult>, String> {
+        let current_state = self.trading_state(p_symbol);
+        let first_event = self.events_.len();
+        if !current_state.can_transition_to(p_state) {
+            return Err(format!(
+                "Transition of {p_symbol} from {:?} to {:?} is not allowed",
+                current_state, p_state
+            ));
+        }
+
+        self.session_mut(p_symbol).state_ = p_state;
+        println!("{p_symbol} moved from {:?} to {:?}", current_state, p_state);
+        self.events_.push(EngineEvent::TradingStatus {
+            symbol_: p_symbol.to_owned(),
+            previous_state_: current_state,
+            state_: p_state,
+            reason_: p_reason.to_owned(),
+        });
+
+        let mut uncross_result = None;
+        if current_state == TradingState::ClosingAuction && p_state == TradingState::Closed {
+            uncross_result = self.uncross_closing(p_symbol);
+        } else if current_state.uncrosses_into(p_state) {
+            if let Some(order_book) = self.get_book_by_symbol(p_symbol) {
+                uncross_result = order_book.uncross();
+            }
+        }
+        let now = self.clock_.now();
+        MatchingEngine::stamp_fills(&mut uncross_result, now);
+        self.collect_book_changes(p_symbol, now);
+        self.record_trades(p_symbol, None, &uncross_result, now);
+        self.track_fills(None, &uncross_result);
+        self.audit_events(first_event);
+        return Ok(uncross_result);
+    }
+
+    //publishes what changed in the book of p_symbol at p_time, order by order and per level
+    fn collect_book_changes(&mut self, p_symbol: &String, p_time: SystemTime) {
+        self.collect_order_feed(p_symbol);
+        self.collect_depth_updates(p_symbol);
+        self.update_spread(p_symbol, p_time);
+    }
+
+    fn session_mut(&mut self, p_symbol: &String) -> &mut TradingSession {
+        let initial_state = self.trading_state(p_symbol);
+        self.session_by_symbol_
+            .entry(p_symbol.to_owned())
+            .or_insert_with(|| TradingSession::new(initial_state))
+    }
+
     pub fn contains(&self, p_symbol: &String) -> bool {
         self.order_book_by_symbol_.contains_key(p_symbol)
     }
@@ -531,13 +1000,8 @@ impl MatchingEngine {
     }
 
     fn add_order_book(&mut self, p_symbol: &String) -> Option<&mut OrderBook> {
-        let new_order_book = OrderBook {
-            bids_: BTreeSet::new(),
-            asks_: BTreeSet::new(),
-        };
-
         self.order_book_by_symbol_
-            .insert(p_symbol.to_owned(), new_order_book);
+            .insert(p_symbol.to_owned(), OrderBook::new(p_symbol));
         return self.order_book_by_symbol_.get_mut(p_symbol);
     }
 }
@@ -547,21 +1011,91 @@ pub fn process_event(
     p_order: &mut Order,
     p_order_book_collection: &mut MatchingEngine,
 ) -> Result<Option<MatchingResult>, String> {
-    match p_event_type {
+    p_order_book_collection.stamp_acceptance(p_order);
+    return apply_event(p_event_type, p_order, p_order_book_collection);
+}
+
+//applies an input already stamped with its acceptance time
+pub(crate) fn apply_event(
+    p_event_type: EventType,
+    p_order: &mut Order,
+    p_order_book_collection: &mut MatchingEngine,
+) -> Result<Option<MatchingResult>, String> {
+    p_order_book_collection.journal_event(p_event_type, p_order)?;
+    let first_event = p_order_book_collection.accept_input(p_event_type, p_order);
+    p_order_book_collection.audit_input(p_event_type, p_order);
+    let received_order = p_order.clone();
+    let result = match p_event_type {
         EventType::New => {
             println!("\nNew Order, received:\n\t {:?}", p_order);
-            return p_order_book_collection.process_new_order(p_order);
+            p_order_book_collection.process_new_order(p_order)
         }
 
         EventType::Rpl => {
             println!("\nReplace Order, received:\n\t {:?}", p_order);
-            return p_order_book_collection.process_rpl_order(p_order);
+            p_order_book_collection.process_rpl_order(p_order)
         }
 
         EventType::Cxl => {
             println!("\nCancel Order, received:\n\t {:?}", p_order);
-            return p_order_book_collection.process_cxl_order(p_order);
+            p_order_book_collection.process_cxl_order(p_order)
         }
+    };
+    p_order_book_collection.hash_outputs(first_event, &result);
+    p_order_book_collection.track_order(p_event_type, &received_order, &result);
+    p_order_book_collection.audit_result(p_event_type, &received_order, &result, first_event);
+    return result;
+}
+
+//limit DAY order of REL entered now for the module tests,
+//the with_ methods change what a test needs
+#[cfg(test)]
+pub(crate) fn test_order(p_id: &str, p_side: OrderSide, p_qty: i32, p_price: f32) -> Order {
+    Order {
+        id_: String::from(p_id),
+        symbol_: String::from("REL"),
+        qty_: p_qty,
+        price_: p_price,
+        entry_time_: SystemTime::now(),
+        seq_: 0,
+        side_: p_side,
+        type_: OrderType::Limit,
+        time_in_force_: TimeInForce::Day,
+        client_id_: String::new(),
+        stp_group_: None,
+    }
+}
+
+#[cfg(test)]
+impl Order {
+    pub(crate) fn with_symbol(mut self, p_symbol: &str) -> Self {
+        self.symbol_ = String::from(p_symbol);
+        self
+    }
+
+    pub(crate) fn with_type(mut self, p_type: OrderType) -> Self {
+        self.type_ = p_type;
+        self
+    }
+
+    pub(crate) fn with_time(mut self, p_time: SystemTime) -> Self {
+        self.entry_time_ = p_time;
+        self
+    }
+
+    pub(crate) fn with_time_in_force(mut self, p_time_in_force: TimeInForce) -> Self {
+        self.time_in_force_ = p_time_in_force;
+        self
+    }
+
+    pub(crate) fn with_client(mut self, p_client_id: &str) -> Self {
+        self.client_id_ = String::from(p_client_id);
+        self
+    }
+
+    pub(crate) fn with_stp_group(mut self, p_stp_group: &str) -> Self {
+        self.stp_group_ = Some(String::from(p_stp_group));
+        self
     }
 }
 
@@ -617,9 +1151,7 @@ mod test {
 
     #[test]
     fn create_first_order() {
-        let mut order_book_collection = MatchingEngine {
-            order_book_by_symbol_: HashMap::new(),
-        };
+        let mut order_book_collection = MatchingEngine::new();
 
         let mut order = Order {
             id_: String::from("1"),
@@ -628,7 +1160,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Buy,
             type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
         validate_result(&result, 0, 0.0, None);
@@ -636,9 +1172,7 @@ mod test {
 
     #[test]
     fn qty_match_simple_order() {
-        let mut order_book_collection = MatchingEngine {
-            order_book_by_symbol_: HashMap::new(),
-        };
+        let mut order_book_collection = MatchingEngine::new();
 
         let mut matched_order_ids = Vec::new();
         let mut order = Order {
@@ -648,7 +1182,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Buy,
             type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
         validate_result(&result, 0, 0.0, None);
@@ -660,7 +1198,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Sell,
             type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         matched_order_ids.push("1".to_string());
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
@@ -673,7 +1215,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Sell,
             type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
         validate_result(&result, 0, 0.0, None);
@@ -685,7 +1231,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Buy,
             type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         matched_order_ids.clear();
         matched_order_ids.push("3".to_string());
@@ -695,9 +1245,7 @@ mod test {
 
     #[test]
     fn qty_macth_test_partial_match() {
-        let mut order_book_collection = MatchingEngine {
-            order_book_by_symbol_: HashMap::new(),
-        };
+        let mut order_book_collection = MatchingEngine::new();
         let mut matched_order_ids = Vec::new();
 
         let mut order = Order {
@@ -707,7 +1255,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Buy,
             type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
         //200 added to book, exected 0;
@@ -720,7 +1272,11 @@ mod test {
             qty_: 100,
             side_: OrderSide::Sell,
             type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
         //100 partially executed, 100 buy left in book
@@ -735,7 +1291,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Sell,
             type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
         //100 executed, 100 sell id 3 left in book
@@ -748,7 +1308,11 @@ mod test {
             qty_: 100,
             side_: OrderSide::Buy,
             type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
         //100 executed, nothing left in book
@@ -763,7 +1327,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Buy,
             type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
         //200 buy added in book, nothing executed
@@ -777,7 +1345,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Sell,
             type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
         //200 buy sell matched, nothin left in book
@@ -787,9 +1359,7 @@ mod test {
 
     #[test]
     fn mkt_order_match_simple() {
-        let mut order_book_collection = MatchingEngine {
-            order_book_by_symbol_: HashMap::new(),
-        };
+        let mut order_book_collection = MatchingEngine::new();
         let mut matched_order_ids = Vec::new();
 
         let mut order = Order {
@@ -799,7 +1369,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Buy,
             type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
         //200@100 buy added to book
@@ -812,7 +1386,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Sell,
             type_: OrderType::Mkt,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
         //mkt matched
@@ -826,7 +1404,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Buy,
             type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
         //200@100 buy added to book
@@ -839,7 +1421,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Buy,
             type_: OrderType::Mkt,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
         //another 200@100 added into book but not matched
@@ -848,9 +1434,7 @@ mod test {
 
     #[test]
     fn mkt_order_match_time() {
-        let mut order_book_collection = MatchingEngine {
-            order_book_by_symbol_: HashMap::new(),
-        };
+        let mut order_book_collection = MatchingEngine::new();
         let mut matched_order_ids = Vec::new();
         let mut order = Order {
             id_: String::from("1"),
@@ -859,7 +1443,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Buy,
             type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
         //200@100 buy added to book
@@ -872,7 +1460,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Buy,
             type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
         //Another 200@100 buy added to book
@@ -885,7 +1477,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Sell,
             type_: OrderType::Mkt,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
         //mkt matched 200@100
@@ -899,7 +1495,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Sell,
             type_: OrderType::Mkt,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
         //mkt matched 200@100
@@ -910,9 +1510,7 @@ mod test {
 
     #[test]
     fn mkt_order_match_price() {
-        let mut order_book_collection = MatchingEngine {
-            order_book_by_symbol_: HashMap::new(),
-        };
+        let mut order_book_collection = MatchingEngine::new();
         let mut matched_order_ids = Vec::new();
         let mut order = Order {
             id_: String::from("1"),
@@ -921,7 +1519,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Buy,
             type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
         //200@101 buy added to book
@@ -934,7 +1536,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Buy,
             type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
         //Another 200@100 buy added to book
@@ -947,7 +1553,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Sell,
             type_: OrderType::Mkt,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
         //mkt matched to best price which is 100 at this time
@@ -961,7 +1571,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Sell,
             type_: OrderType::Mkt,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
         //mkt matched
@@ -972,9 +1586,7 @@ mod test {
 
     #[test]
     fn mkt_order_match_price_sell_buy() {
-        let mut order_book_collection = MatchingEngine {
-            order_book_by_symbol_: HashMap::new(),
-        };
+        let mut order_book_collection = MatchingEngine::new();
         let mut matched_order_ids = Vec::new();
         let mut order = Order {
             id_: String::from("1"),
@@ -983,7 +1595,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Sell,
             type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
         //200@101 buy added to book
@@ -996,7 +1612,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Sell,
             type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
         //Another 200@100 buy added to book
@@ -1009,7 +1629,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Buy,
             type_: OrderType::Mkt,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
         //mkt matched to best price which is 100 at this time
@@ -1023,7 +1647,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Buy,
             type_: OrderType::Mkt,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
         //mkt matched
@@ -1034,9 +1662,7 @@ mod test {
 
     #[test]
     fn cancel_order_simple() {
-        let mut order_book_collection = MatchingEngine {
-            order_book_by_symbol_: HashMap::new(),
-        };
+        let mut order_book_collection = MatchingEngine::new();
 
         //New order
         let mut order = Order {
@@ -1046,7 +1672,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Buy,
             type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
         //mkt matched to best price which is 100 at this time
@@ -1061,7 +1691,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Buy,
             type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
 
         let result = process_event(EventType::Cxl, &mut order, &mut order_book_collection);
@@ -1077,7 +1711,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Sell,
             type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
 
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
@@ -1093,7 +1731,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Buy,
             type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
 
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
@@ -1104,9 +1746,7 @@ mod test {
 
     #[test]
     fn simple_replace_order() {
-        let mut order_book_collection = MatchingEngine {
-            order_book_by_symbol_: HashMap::new(),
-        };
+        let mut order_book_collection = MatchingEngine::new();
 
         //New order
         let mut order = Order {
@@ -1116,7 +1756,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Buy,
             type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
         //mkt matched to best price which is 100 at this time
@@ -1131,7 +1775,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Buy,
             type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::Rpl, &mut order, &mut order_book_collection);
         //mkt matched to best price which is 100 at this time
@@ -1146,7 +1794,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Sell,
             type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(EventType::New, &mut order, &mut order_book_collection);
         //mkt matched to best price which is 100 at this time
@@ -1161,7 +1813,11 @@ mod test {
             qty_: 200,
             side_: OrderSide::Sell,
             type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
             entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
         };
         let result = process_event(
           EventType::Rpl, 
@@ -1173,4 +1829,227 @@ mod test {
         matched_order_ids.push(String::from("1"));
         validate_result(&result, 200, 100.0, Some(&matched_order_ids));
     }
+
+    #[test]
+    fn limit_order_finds_opposite_level_behind_the_best() {
+        let mut order_book_collection = MatchingEngine::new();
+        let template = Order {
+            id_: String::new(),
+            price_: 0.0,
+            symbol_: String::from("REL"),
+            qty_: 100,
+            side_: OrderSide::Buy,
+            type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
+            entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
+        };
+        let order = |p_id: &str, p_side: OrderSide, p_price: f32| Order {
+            id_: String::from(p_id),
+            side_: p_side,
+            price_: p_price,
+            ..template.clone()
+        };
+        let resting_orders = [
+            order("1", OrderSide::Sell, 100.0),
+            order("2", OrderSide::Sell, 101.0),
+            order("3", OrderSide::Buy, 99.0),
+            order("4", OrderSide::Buy, 98.0),
+        ];
+        for mut order in resting_orders {
+            let result = process_event(EventType::New, &mut order, &mut order_book_collection);
+            validate_result(&result, 0, 0.0, None);
+        }
+
+        //the level is probed with the side of the set searched, not the incoming side
+        let mut buy = order("5", OrderSide::Buy, 101.0);
+        let result = process_event(EventType::New, &mut buy, &mut order_book_collection);
+        validate_result(&result, 100, 101.0, Some(&vec![String::from("2")]));
+
+        let mut sell = order("6", OrderSide::Sell, 98.0);
+        let result = process_event(EventType::New, &mut sell, &mut order_book_collection);
+        validate_result(&result, 100, 98.0, Some(&vec![String::from("4")]));
+    }
+
+    #[test]
+    fn session_state_validation_and_open_uncross() {
+        let mut order_book_collection = MatchingEngine::new();
+        let symbol = String::from("REL");
+        order_book_collection.transition(&symbol, TradingState::Halted).unwrap();
+        order_book_collection.transition(&symbol, TradingState::Closed).unwrap();
+        order_book_collection.transition(&symbol, TradingState::PreOpen).unwrap();
+
+        //no market orders in pre-open
+        let mut order = Order {
+            id_: String::from("1"),
+            price_: 0.0,
+            symbol_: String::from("REL"),
+            qty_: 200,
+            side_: OrderSide::Buy,
+            type_: OrderType::Mkt,
+            time_in_force_: TimeInForce::Day,
+            entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
+        };
+        let result = process_event(EventType::New, &mut order, &mut order_book_collection);
+        assert!(result.is_err());
+
+        //crossed limit orders rest without matching
+        let mut order = Order {
+            id_: String::from("2"),
+            price_: 100.1,
+            symbol_: String::from("REL"),
+            qty_: 200,
+            side_: OrderSide::Buy,
+            type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
+            entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
+        };
+        let result = process_event(EventType::New, &mut order, &mut order_book_collection);
+        validate_result(&result, 0, 0.0, None);
+
+        let mut order = Order {
+            id_: String::from("3"),
+            price_: 100.0,
+            symbol_: String::from("REL"),
+            qty_: 200,
+            side_: OrderSide::Sell,
+            type_: OrderType::Limit,
+            time_in_force_: TimeInForce::Day,
+            entry_time_: std::time::SystemTime::now(),
+            seq_: 0,
+            client_id_: String::new(),
+            stp_group_: None,
+        };
+        let result = process_event(EventType::New, &mut order, &mut order_book_collection);
+        validate_result(&result, 0, 0.0, None);
+
+        //opening uncross executes both orders
+        let result = order_book_collection.transition(&symbol, TradingState::Continuous);
+        let matched_order_ids = vec![String::from("2"), String::from("3")];
+        validate_result(&result, 200, 100.0, Some(&matched_order_ids));
+
+        //only cancels are accepted while halted
+        order_book_collection.transition(&symbol, TradingState::Halted).unwrap();
+        let result = process_event(EventType::New, &mut order, &mut order_book_collection);
+        assert!(result.is_err());
+        assert!(order_book_collection
+            .transition(&symbol, TradingState::PreOpen)
+            .is_err());
+    }
+
+    #[test]
+    fn exact_fill_of_several_orders() {
+        let mut order_book_collection = MatchingEngine::new();
+        for id in ["1", "2"] {
+            let mut order = test_order(id, OrderSide::Sell, 100, 100.0);
+            let result = process_event(EventType::New, &mut order, &mut order_book_collection);
+            validate_result(&result, 0, 0.0, None);
+        }
+
+        //the last resting order fills the rest of the buy exactly
+        let mut order = test_order("3", OrderSide::Buy, 200, 100.0);
+        let result = process_event(EventType::New, &mut order, &mut order_book_collection);
+        let matched_order_ids = vec![String::from("1"), String::from("2")];
+        validate_result(&result, 200, 100.0, Some(&matched_order_ids));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let mut order_book_collection = MatchingEngine::new();
+        let order_json = r#"{"id":"1","symbol":"REL","qty":100,"price":100.0,
+            "entry_time":{"secs_since_epoch":1000,"nanos_since_epoch":0},
+            "side":"Sell","type":"Limit","client_id":"C1","stp_group":null}"#;
+        let mut sell: Order = serde_json::from_str(order_json).unwrap();
+        assert_eq!(sell.seq_, 0);
+        process_event(EventType::New, &mut sell, &mut order_book_collection).unwrap();
+
+        let mut buy = sell.clone();
+        buy.id_ = String::from("2");
+        buy.side_ = OrderSide::Buy;
+        buy.client_id_ = String::from("C2");
+        let result = process_event(EventType::New, &mut buy, &mut order_book_collection).unwrap();
+        let json = serde_json::to_string(&result).unwrap();
+        let decoded: Option<MatchingResult> = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.unwrap().fills_, result.unwrap().fills_);
+
+        let events = order_book_collection.drain_events();
+        let json = serde_json::to_string(&events).unwrap();
+        assert!(json.contains(r#""maker_order_id":"1""#));
+        let decoded: Vec<EngineEvent> = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, events);
+        let event_type: EventType = serde_json::from_str(r#""Rpl""#).unwrap();
+        assert!(matches!(event_type, EventType::Rpl));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_external_names() {
+        let entry_time = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1000);
+        let mut order_book_collection = MatchingEngine::new();
+        order_book_collection.set_clock(crate::clock::ManualClock::new(entry_time));
+        let mut sell = test_order("1", OrderSide::Sell, 100, 100.0)
+            .with_time(entry_time)
+            .with_client("C1");
+        process_event(EventType::New, &mut sell, &mut order_book_collection).unwrap();
+        assert_eq!(
+            serde_json::to_string(&sell).unwrap(),
+            r#"{"id":"1","symbol":"REL","qty":100,"price":100.0,"#.to_owned()
+                + r#""entry_time":{"secs_since_epoch":1000,"nanos_since_epoch":0},"seq":1,"#
+                + r#""side":"Sell","type":"Limit","time_in_force":"Day","client_id":"C1","#
+                + r#""stp_group":null}"#
+        );
+
+        let mut buy = test_order("2", OrderSide::Buy, 40, 100.0)
+            .with_time(entry_time)
+            .with_client("C2");
+        let result = process_event(EventType::New, &mut buy, &mut order_book_collection).unwrap();
+        assert_eq!(
+            serde_json::to_string(&result).unwrap(),
+            r#"{"matched_order_ids":["1"],"executed_qty":40,"executed_price":100.0,"#.to_owned()
+                + r#""aggressor_cancelled_qty":0,"self_trade_events":[],"fills":[{"#
+                + r#""maker_order_id":"1","side":"Sell","price":100.0,"qty":40,"trade_id":1,"#
+                + r#""time":{"secs_since_epoch":1000,"nanos_since_epoch":0}}]}"#
+        );
+
+        let events: Vec<String> = order_book_collection
+            .drain_events()
+            .iter()
+            .map(|event| serde_json::to_string(event).unwrap())
+            .collect();
+        assert!(events.contains(
+            &(r#"{"OrderFeed":{"symbol":"REL","seq":2,"update":{"Execute":{"#.to_owned()
+                + r#""order_id":"1","side":"Sell","price":100.0,"qty":40,"trade_id":1}}}}"#)
+        ));
+        assert!(events.contains(
+            &(r#"{"Trade":{"symbol":"REL","trade_id":1,"price":100.0,"qty":40,"#.to_owned()
+                + r#""maker_order_id":"1","taker_order_id":"2","#
+                + r#""time":{"secs_since_epoch":1000,"nanos_since_epoch":0}}}"#)
+        ));
+        let cancelled = EngineEvent::OrderCancelled {
+            symbol_: String::from("REL"),
+            order_id_: String::from("1"),
+            reason_: String::from("end of day"),
+        };
+        assert_eq!(
+            serde_json::to_string(&cancelled).unwrap(),
+            r#"{"OrderCancelled":{"symbol":"REL","order_id":"1","reason":"end of day"}}"#
+        );
+
+        assert_eq!(
+            serde_json::to_string(&order_book_collection.order_status("1").unwrap()).unwrap(),
+            r#"{"order_id":"1","symbol":"REL","client_id":"C1","side":"Sell","price":100.0,"#
+                .to_owned()
+                + r#""original_qty":100,"leaves_qty":60,"cum_qty":40,"avg_price":100.0,"#
+                + r#""state":"PartiallyFilled"}"#
+        );
+    }
 }
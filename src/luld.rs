@@ -0,0 +1,343 @@
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+
+use crate::command::EngineCommand;
+use crate::events::EngineEvent;
+use crate::session::TradingState;
+use crate::{MatchingEngine, MatchingResult, Order, OrderSide, OrderType};
+
+//Limit-Up/Limit-Down price bands
+// Each configured symbol keeps a reference price, the volume weighted average of
+// its fills over a rolling window, and accepts continuous trading only between
+// reference * (1 - band) and reference * (1 + band). When the best bid sits on the
+// upper band or the best offer on the lower band the symbol is in a limit state,
+// and a limit state lasting longer than the configured duration halts the symbol.
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+pub enum OutOfBandPolicy {
+    Reject,
+    Reprice,
+}
+
+#[derive(Clone, Debug)]
+pub struct PriceBandConfig {
+    band_pct_: f32,
+    reference_window_: Duration,
+    limit_state_duration_: Duration,
+    out_of_band_policy_: OutOfBandPolicy,
+}
+
+impl PriceBandConfig {
+    pub fn new(
+        p_band_pct: f32,
+        p_reference_window: Duration,
+        p_limit_state_duration: Duration,
+        p_out_of_band_policy: OutOfBandPolicy,
+    ) -> Self {
+        PriceBandConfig {
+            band_pct_: p_band_pct,
+            reference_window_: p_reference_window,
+            limit_state_duration_: p_limit_state_duration,
+            out_of_band_policy_: p_out_of_band_policy,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct PriceBand {
+    config_: PriceBandConfig,
+    // (fill time, qty, price) of fills inside the reference window
+    fills_: VecDeque<(SystemTime, i32, f32)>,
+    reference_price_: f32,
+    limit_state_since_: Option<SystemTime>,
+}
+
+impl PriceBand {
+    fn new(p_config: PriceBandConfig, p_reference_price: f32) -> Self {
+        PriceBand {
+            config_: p_config,
+            fills_: VecDeque::new(),
+            reference_price_: p_reference_price,
+            limit_state_since_: None,
+        }
+    }
+
+    pub(crate) fn bands(&self) -> (f32, f32) {
+        let lower = self.reference_price_ * (1.0 - self.config_.band_pct_);
+        let upper = self.reference_price_ * (1.0 + self.config_.band_pct_);
+        return (lower, upper);
+    }
+
+    //true when an execution at p_price for p_side would trade through a band
+    fn outside(&self, p_side: OrderSide, p_price: f32) -> bool {
+        let (lower, upper) = self.bands();
+        match p_side {
+            OrderSide::Buy => p_price > upper,
+            OrderSide::Sell => p_price < lower,
+        }
+    }
+
+    fn band_for(&self, p_side: OrderSide) -> f32 {
+        let (lower, upper) = self.bands();
+        match p_side {
+            OrderSide::Buy => upper,
+            OrderSide::Sell => lower,
+        }
+    }
+
+    //returns true when the reference price moved
+    fn record_fill(&mut self, p_time: SystemTime, p_qty: i32, p_price: f32) -> bool {
+        self.fills_.push_back((p_time, p_qty, p_price));
+        while let Some((fill_time, _, _)) = self.fills_.front() {
+            let age = p_time.duration_since(*fill_time).unwrap_or_default();
+            if age <= self.config_.reference_window_ {
+                break;
+            }
+            self.fills_.pop_front();
+        }
+
+        let total_qty: i32 = self.fills_.iter().map(|(_, qty, _)| qty).sum();
+        if total_qty == 0 {
+            return false;
+        }
+        let notional: f32 = self
+            .fills_
+            .iter()
+            .map(|(_, qty, price)| *qty as f32 * price)
+            .sum();
+        let previous_reference = self.reference_price_;
+        self.reference_price_ = notional / total_qty as f32;
+        return previous_reference != self.reference_price_;
+    }
+}
+
+impl MatchingEngine {
+    pub fn set_price_band(
+        &mut self,
+        p_symbol: &String,
+        p_config: PriceBandConfig,
+        p_reference_price: f32,
+    ) {
+        self.price_band_by_symbol_.insert(
+            p_symbol.to_owned(),
+            PriceBand::new(p_config, p_reference_price),
+        );
+    }
+
+    //(lower band, upper band) of p_symbol if it has price bands configured
+    pub fn price_bands(&self, p_symbol: &String) -> Option<(f32, f32)> {
+        self.price_band_by_symbol_
+            .get(p_symbol)
+            .map(|price_band| price_band.bands())
+    }
+
+    //rejects or reprices p_order when it would execute outside the bands
+    pub(crate) fn apply_price_band(&mut self, p_order: &mut Order) -> Result<(), String> {
+        if self.trading_state(&p_order.symbol_) != TradingState::Continuous {
+            return Ok(());
+        }
+        let price_band = match self.price_band_by_symbol_.get(&p_order.symbol_) {
+            None => return Ok(()),
+            Some(price_band) => price_band,
+        };
+
+        let execution_price = match p_order.type_ {
+            OrderType::Moc | OrderType::Loc => None,
+            OrderType::Limit => Some(p_order.price_),
+            OrderType::Mkt => self.order_book_by_symbol_.get(&p_order.symbol_).and_then(
+                |order_book| match p_order.side_ {
+                    OrderSide::Buy => order_book.best_ask_price(),
+                    OrderSide::Sell => order_book.best_bid_price(),
+                },
+            ),
+        };
+        let execution_price = match execution_price {
+            None => return Ok(()),
+            Some(price) => price,
+        };
+        if !price_band.outside(p_order.side_, execution_price) {
+            return Ok(());
+        }
+
+        match price_band.config_.out_of_band_policy_ {
+            OutOfBandPolicy::Reject => {
+                return Err(format!(
+                    "Order id {} at {} is outside the price bands {:?} of {}",
+                    p_order.id_,
+                    execution_price,
+                    price_band.bands(),
+                    p_order.symbol_
+                ));
+            }
+            OutOfBandPolicy::Reprice => {
+                p_order.price_ = price_band.band_for(p_order.side_);
+                p_order.type_ = OrderType::Limit;
+                println!(
+                    "Order id {} repriced to band {}",
+                    p_order.id_, p_order.price_
+                );
+                return Ok(());
+            }
+        }
+    }
+
+    //moves the reference price with p_result and tracks the limit state
+    pub(crate) fn update_price_band(
+        &mut self,
+        p_symbol: &String,
+        p_result: &Option<MatchingResult>,
+        p_now: SystemTime,
+    ) -> Result<(), String> {
+        let price_band = match self.price_band_by_symbol_.get_mut(p_symbol) {
+            None => return Ok(()),
+            Some(price_band) => price_band,
+        };
+
+        if let Some(match_result) = p_result {
+            if match_result.executed_qty_ > 0
+                && price_band.record_fill(
+                    p_now,
+                    match_result.executed_qty_,
+                    match_result.executed_price_,
+                )
+            {
+                let (lower, upper) = price_band.bands();
+                self.events_.push(EngineEvent::PriceBand {
+                    symbol_: p_symbol.to_owned(),
+                    lower_band_: lower,
+                    upper_band_: upper,
+                });
+            }
+        }
+        return self.check_limit_state(p_symbol, p_now);
+    }
+
+    //halts symbols whose limit state has lasted too long, meant to be called from a timer
+    pub fn check_price_bands(&mut self, p_now: SystemTime) -> Result<(), String> {
+        let command = EngineCommand::CheckPriceBands { now_: p_now };
+        return self.process_command(&command, |engine| engine.check_limit_states(p_now));
+    }
+
+    fn check_limit_states(&mut self, p_now: SystemTime) -> Result<(), String> {
+        let mut symbols: Vec<String> = self.price_band_by_symbol_.keys().cloned().collect();
+        symbols.sort();
+        for symbol in symbols {
+            self.check_limit_state(&symbol, p_now)?;
+        }
+        return Ok(());
+    }
+
+    fn check_limit_state(&mut self, p_symbol: &String, p_now: SystemTime) -> Result<(), String> {
+        if self.trading_state(p_symbol) != TradingState::Continuous {
+            return Ok(());
+        }
+        let (best_bid, best_ask) = match self.order_book_by_symbol_.get(p_symbol) {
+            None => (None, None),
+            Some(order_book) => (order_book.best_bid_price(), order_book.best_ask_price()),
+        };
+        let price_band = match self.price_band_by_symbol_.get_mut(p_symbol) {
+            None => return Ok(()),
+            Some(price_band) => price_band,
+        };
+
+        let (lower, upper) = price_band.bands();
+        let at_band =
+            best_bid.is_some_and(|bid| bid >= upper) || best_ask.is_some_and(|ask| ask <= lower);
+
+        match (price_band.limit_state_since_, at_band) {
+            (None, true) => {
+                price_band.limit_state_since_ = Some(p_now);
+                self.events_.push(EngineEvent::LimitState {
+                    symbol_: p_symbol.to_owned(),
+                    entered_: true,
+                });
+            }
+            (Some(_), false) => {
+                price_band.limit_state_since_ = None;
+                self.events_.push(EngineEvent::LimitState {
+                    symbol_: p_symbol.to_owned(),
+                    entered_: false,
+                });
+            }
+            (Some(since), true) => {
+                let in_limit_state = p_now.duration_since(since).unwrap_or_default();
+                if in_limit_state >= price_band.config_.limit_state_duration_ {
+                    price_band.limit_state_since_ = None;
+                    self.change_state(p_symbol, TradingState::Halted, "limit state")?;
+                }
+            }
+            (None, false) => {}
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::test_order;
+    use crate::{process_event, EventType};
+
+    fn order(p_id: &str, p_side: OrderSide, p_price: f32, p_time: SystemTime) -> Order {
+        test_order(p_id, p_side, 100, p_price).with_time(p_time)
+    }
+
+    fn engine(p_policy: OutOfBandPolicy) -> MatchingEngine {
+        let mut engine = MatchingEngine::new();
+        let config = PriceBandConfig::new(
+            0.05,
+            Duration::from_secs(300),
+            Duration::from_secs(15),
+            p_policy,
+        );
+        engine.set_price_band(&String::from("REL"), config, 100.0);
+        engine
+    }
+
+    #[test]
+    fn out_of_band_rejected_or_repriced() {
+        let now = SystemTime::now();
+        let mut engine = engine(OutOfBandPolicy::Reject);
+        let mut buy = order("1", OrderSide::Buy, 106.0, now);
+        assert!(process_event(EventType::New, &mut buy, &mut engine).is_err());
+
+        let mut engine = self::engine(OutOfBandPolicy::Reprice);
+        let mut buy = order("1", OrderSide::Buy, 106.0, now);
+        assert!(process_event(EventType::New, &mut buy, &mut engine).is_ok());
+        assert!((buy.price_ - 105.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn reference_follows_fills_and_limit_state_halts() {
+        let symbol = String::from("REL");
+        let start = SystemTime::now();
+        let mut engine = engine(OutOfBandPolicy::Reject);
+        engine.set_clock(ManualClock::new(start));
+
+        let mut sell = order("1", OrderSide::Sell, 102.0, start);
+        process_event(EventType::New, &mut sell, &mut engine).unwrap();
+        let mut buy = order("2", OrderSide::Buy, 102.0, start);
+        process_event(EventType::New, &mut buy, &mut engine).unwrap();
+        let (lower, upper) = engine.price_bands(&symbol).unwrap();
+        assert!((lower - 96.9).abs() < 0.001);
+        assert!((upper - 107.1).abs() < 0.001);
+
+        //a bid resting at the upper band starts the limit state
+        let mut buy = order("3", OrderSide::Buy, upper, start);
+        process_event(EventType::New, &mut buy, &mut engine).unwrap();
+        assert!(engine.drain_events().contains(&EngineEvent::LimitState {
+            symbol_: symbol.to_owned(),
+            entered_: true,
+        }));
+
+        engine
+            .check_price_bands(start + Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(engine.trading_state(&symbol), TradingState::Continuous);
+        engine
+            .check_price_bands(start + Duration::from_secs(15))
+            .unwrap();
+        assert_eq!(engine.trading_state(&symbol), TradingState::Halted);
+    }
+}
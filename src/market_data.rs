@@ -0,0 +1,308 @@
+use crate::session::TradingState;
+use crate::{Level, MatchingEngine, OrderBook};
+
+//Market data snapshots
+// Read only views of an OrderBook: level 1 (best bid and offer with size) and
+// level 2 (aggregated qty and order count per price, best price first), plus a
+// one line summary per symbol for watchlists built from level 1 and the trade
+// statistics only.
+
+#[derive(Clone, Debug, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PriceLevel {
+    #[cfg_attr(feature = "serde", serde(rename = "price"))]
+    pub(crate) price_: f32,
+    #[cfg_attr(feature = "serde", serde(rename = "qty"))]
+    pub(crate) qty_: i32,
+    #[cfg_attr(feature = "serde", serde(rename = "order_count"))]
+    pub(crate) order_count_: usize,
+}
+
+impl PriceLevel {
+    pub fn new(p_price: f32, p_qty: i32, p_order_count: usize) -> Self {
+        PriceLevel {
+            price_: p_price,
+            qty_: p_qty,
+            order_count_: p_order_count,
+        }
+    }
+
+    pub fn price(&self) -> f32 {
+        self.price_
+    }
+
+    pub fn qty(&self) -> i32 {
+        self.qty_
+    }
+
+    pub fn order_count(&self) -> usize {
+        self.order_count_
+    }
+}
+
+#[derive(Clone, Debug, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BestBidOffer {
+    #[cfg_attr(feature = "serde", serde(rename = "bid"))]
+    bid_: Option<PriceLevel>,
+    #[cfg_attr(feature = "serde", serde(rename = "ask"))]
+    ask_: Option<PriceLevel>,
+}
+
+impl BestBidOffer {
+    pub fn bid(&self) -> Option<PriceLevel> {
+        self.bid_
+    }
+
+    pub fn ask(&self) -> Option<PriceLevel> {
+        self.ask_
+    }
+
+    pub fn spread(&self) -> Option<f32> {
+        match (self.bid_, self.ask_) {
+            (Some(bid), Some(ask)) => Some(ask.price_ - bid.price_),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BookDepth {
+    #[cfg_attr(feature = "serde", serde(rename = "bids"))]
+    pub(crate) bids_: Vec<PriceLevel>,
+    #[cfg_attr(feature = "serde", serde(rename = "asks"))]
+    pub(crate) asks_: Vec<PriceLevel>,
+}
+
+impl BookDepth {
+    pub fn bids(&self) -> &Vec<PriceLevel> {
+        &self.bids_
+    }
+
+    pub fn asks(&self) -> &Vec<PriceLevel> {
+        &self.asks_
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SymbolSummary {
+    #[cfg_attr(feature = "serde", serde(rename = "symbol"))]
+    symbol_: String,
+    #[cfg_attr(feature = "serde", serde(rename = "state"))]
+    state_: TradingState,
+    #[cfg_attr(feature = "serde", serde(rename = "best_bid_offer"))]
+    best_bid_offer_: BestBidOffer,
+    //None before the first trade
+    #[cfg_attr(feature = "serde", serde(rename = "last_price"))]
+    last_price_: Option<f32>,
+    #[cfg_attr(feature = "serde", serde(rename = "last_qty"))]
+    last_qty_: i32,
+    #[cfg_attr(feature = "serde", serde(rename = "volume"))]
+    volume_: i64,
+    #[cfg_attr(feature = "serde", serde(rename = "trade_count"))]
+    trade_count_: u64,
+}
+
+impl SymbolSummary {
+    pub fn symbol(&self) -> &String {
+        &self.symbol_
+    }
+
+    pub fn state(&self) -> TradingState {
+        self.state_
+    }
+
+    pub fn best_bid_offer(&self) -> BestBidOffer {
+        self.best_bid_offer_
+    }
+
+    pub fn spread(&self) -> Option<f32> {
+        self.best_bid_offer_.spread()
+    }
+
+    pub fn last_price(&self) -> Option<f32> {
+        self.last_price_
+    }
+
+    pub fn last_qty(&self) -> i32 {
+        self.last_qty_
+    }
+
+    pub fn volume(&self) -> i64 {
+        self.volume_
+    }
+
+    pub fn trade_count(&self) -> u64 {
+        self.trade_count_
+    }
+}
+
+impl Level {
+    pub(crate) fn summary(&self) -> PriceLevel {
+        PriceLevel {
+            price_: self.price_,
+            qty_: self.orders_.iter().map(|order| order.qty_).sum(),
+            order_count_: self.orders_.len(),
+        }
+    }
+}
+
+impl OrderBook {
+    pub(crate) fn best_bid_offer(&self) -> BestBidOffer {
+        BestBidOffer {
+            bid_: self.bids_.first().map(|level| level.summary()),
+            ask_: self.asks_.first().map(|level| level.summary()),
+        }
+    }
+
+    pub(crate) fn depth(&self, p_levels: usize) -> BookDepth {
+        BookDepth {
+            bids_: self
+                .bids_
+                .iter()
+                .take(p_levels)
+                .map(|level| level.summary())
+                .collect(),
+            asks_: self
+                .asks_
+                .iter()
+                .take(p_levels)
+                .map(|level| level.summary())
+                .collect(),
+        }
+    }
+}
+
+impl MatchingEngine {
+    pub fn best_bid_offer(&self, p_symbol: &String) -> Option<BestBidOffer> {
+        self.order_book_by_symbol_
+            .get(p_symbol)
+            .map(|order_book| order_book.best_bid_offer())
+    }
+
+    //top p_levels aggregated price levels per side
+    pub fn depth(&self, p_symbol: &String, p_levels: usize) -> Option<BookDepth> {
+        self.order_book_by_symbol_
+            .get(p_symbol)
+            .map(|order_book| order_book.depth(p_levels))
+    }
+
+    pub fn full_depth(&self, p_symbol: &String) -> Option<BookDepth> {
+        self.depth(p_symbol, usize::MAX)
+    }
+
+    //one summary per symbol of p_symbols in the same order, unknown symbols included
+    pub fn watchlist(&self, p_symbols: &[String]) -> Vec<SymbolSummary> {
+        p_symbols
+            .iter()
+            .map(|symbol| self.symbol_summary(symbol))
+            .collect()
+    }
+
+    //watchlist of every symbol with a book or a session, sorted by symbol
+    pub fn market_overview(&self) -> Vec<SymbolSummary> {
+        self.watchlist(&self.known_symbols())
+    }
+
+    fn symbol_summary(&self, p_symbol: &String) -> SymbolSummary {
+        let best_bid_offer = match self.order_book_by_symbol_.get(p_symbol) {
+            None => BestBidOffer {
+                bid_: None,
+                ask_: None,
+            },
+            Some(order_book) => order_book.best_bid_offer(),
+        };
+        let statistics = self.trade_statistics(p_symbol);
+        SymbolSummary {
+            symbol_: p_symbol.to_owned(),
+            state_: self.trading_state(p_symbol),
+            best_bid_offer_: best_bid_offer,
+            last_price_: statistics.map(|statistics| statistics.last_price()),
+            last_qty_: statistics.map_or(0, |statistics| statistics.last_qty()),
+            volume_: statistics.map_or(0, |statistics| statistics.volume()),
+            trade_count_: statistics.map_or(0, |statistics| statistics.trade_count()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_order as order;
+    use crate::{process_event, EventType, OrderSide};
+
+    #[test]
+    fn bbo_and_depth() {
+        let symbol = String::from("REL");
+        let mut engine = MatchingEngine::new();
+        assert!(engine.best_bid_offer(&symbol).is_none());
+
+        let orders = [
+            order("1", OrderSide::Buy, 100, 99.0),
+            order("2", OrderSide::Buy, 50, 99.0),
+            order("3", OrderSide::Buy, 70, 98.0),
+            order("4", OrderSide::Buy, 10, 97.0),
+            order("5", OrderSide::Sell, 40, 101.0),
+        ];
+        for mut order in orders {
+            process_event(EventType::New, &mut order, &mut engine).unwrap();
+        }
+
+        let bbo = engine.best_bid_offer(&symbol).unwrap();
+        assert_eq!(bbo.bid(), Some(PriceLevel::new(99.0, 150, 2)));
+        assert_eq!(bbo.ask(), Some(PriceLevel::new(101.0, 40, 1)));
+        assert_eq!(bbo.spread(), Some(2.0));
+
+        let depth = engine.depth(&symbol, 2).unwrap();
+        assert_eq!(
+            depth.bids(),
+            &vec![PriceLevel::new(99.0, 150, 2), PriceLevel::new(98.0, 70, 1)]
+        );
+        assert_eq!(depth.asks().len(), 1);
+        assert_eq!(engine.full_depth(&symbol).unwrap().bids().len(), 3);
+    }
+
+    #[test]
+    fn watchlist_summaries() {
+        let rel = String::from("REL");
+        let tcs = String::from("TCS");
+        let mut engine = MatchingEngine::new();
+        let orders = [
+            order("1", OrderSide::Sell, 100, 101.0),
+            order("2", OrderSide::Buy, 30, 101.0),
+            order("3", OrderSide::Buy, 50, 99.0),
+        ];
+        for mut order in orders {
+            process_event(EventType::New, &mut order, &mut engine).unwrap();
+        }
+        engine.halt(&tcs, "news pending").unwrap();
+
+        let watchlist = engine.watchlist(&[tcs.to_owned(), rel.to_owned(), String::from("INFY")]);
+        assert_eq!(watchlist.len(), 3);
+        assert_eq!(watchlist[0].state(), TradingState::Halted);
+        assert_eq!(watchlist[0].best_bid_offer().bid(), None);
+
+        let summary = &watchlist[1];
+        assert_eq!(summary.state(), TradingState::Continuous);
+        assert_eq!(
+            summary.best_bid_offer().ask(),
+            Some(PriceLevel::new(101.0, 70, 1))
+        );
+        assert_eq!(summary.spread(), Some(2.0));
+        assert_eq!(
+            (summary.last_price(), summary.last_qty()),
+            (Some(101.0), 30)
+        );
+        assert_eq!((summary.volume(), summary.trade_count()), (30, 1));
+        assert_eq!(watchlist[2].last_price(), None);
+
+        let overview: Vec<String> = engine
+            .market_overview()
+            .iter()
+            .map(|summary| summary.symbol().to_owned())
+            .collect();
+        assert_eq!(overview, vec![rel, tcs]);
+    }
+}
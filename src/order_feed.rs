@@ -0,0 +1,349 @@
+use std::time::SystemTime;
+
+use crate::events::EngineEvent;
+use crate::{Level, MatchingEngine, MatchingResult, OrderBook, OrderSide};
+
+//Level 3 order feed
+// Every change to a resting order is published order by order with a sequence
+// number per symbol, so a consumer applying the messages in sequence ends up with
+// exactly the same Level queues as the engine. An Execute of the whole remaining
+// qty of an order removes it from the book, there is no separate Delete for it.
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OrderUpdate {
+    Add {
+        #[cfg_attr(feature = "serde", serde(rename = "order_id"))]
+        order_id_: String,
+        #[cfg_attr(feature = "serde", serde(rename = "side"))]
+        side_: OrderSide,
+        #[cfg_attr(feature = "serde", serde(rename = "price"))]
+        price_: f32,
+        #[cfg_attr(feature = "serde", serde(rename = "qty"))]
+        qty_: i32,
+    },
+    //qty reduced in place, the order keeps its queue position
+    Modify {
+        #[cfg_attr(feature = "serde", serde(rename = "order_id"))]
+        order_id_: String,
+        #[cfg_attr(feature = "serde", serde(rename = "side"))]
+        side_: OrderSide,
+        #[cfg_attr(feature = "serde", serde(rename = "price"))]
+        price_: f32,
+        #[cfg_attr(feature = "serde", serde(rename = "qty"))]
+        qty_: i32,
+    },
+    Delete {
+        #[cfg_attr(feature = "serde", serde(rename = "order_id"))]
+        order_id_: String,
+        #[cfg_attr(feature = "serde", serde(rename = "side"))]
+        side_: OrderSide,
+        #[cfg_attr(feature = "serde", serde(rename = "price"))]
+        price_: f32,
+    },
+    Execute {
+        #[cfg_attr(feature = "serde", serde(rename = "order_id"))]
+        order_id_: String,
+        #[cfg_attr(feature = "serde", serde(rename = "side"))]
+        side_: OrderSide,
+        #[cfg_attr(feature = "serde", serde(rename = "price"))]
+        price_: f32,
+        #[cfg_attr(feature = "serde", serde(rename = "qty"))]
+        qty_: i32,
+        #[cfg_attr(feature = "serde", serde(rename = "trade_id"))]
+        trade_id_: u64,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OrderFeedMessage {
+    #[cfg_attr(feature = "serde", serde(rename = "symbol"))]
+    symbol_: String,
+    #[cfg_attr(feature = "serde", serde(rename = "seq"))]
+    seq_: u64,
+    #[cfg_attr(feature = "serde", serde(rename = "update"))]
+    update_: OrderUpdate,
+}
+
+impl OrderFeedMessage {
+    pub fn symbol(&self) -> &String {
+        &self.symbol_
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq_
+    }
+
+    pub fn update(&self) -> &OrderUpdate {
+        &self.update_
+    }
+}
+
+//execution of a resting order
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Fill {
+    #[cfg_attr(feature = "serde", serde(rename = "maker_order_id"))]
+    maker_order_id_: String,
+    #[cfg_attr(feature = "serde", serde(rename = "side"))]
+    side_: OrderSide,
+    #[cfg_attr(feature = "serde", serde(rename = "price"))]
+    price_: f32,
+    #[cfg_attr(feature = "serde", serde(rename = "qty"))]
+    qty_: i32,
+    #[cfg_attr(feature = "serde", serde(rename = "trade_id"))]
+    trade_id_: u64,
+    #[cfg_attr(feature = "serde", serde(rename = "time"))]
+    pub(crate) time_: SystemTime,
+}
+
+impl Fill {
+    pub(crate) fn new(p_maker_order_id: &str, p_side: OrderSide, p_price: f32, p_qty: i32) -> Self {
+        Fill {
+            maker_order_id_: p_maker_order_id.to_owned(),
+            side_: p_side,
+            price_: p_price,
+            qty_: p_qty,
+            trade_id_: 0,
+            time_: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    pub fn maker_order_id(&self) -> &String {
+        &self.maker_order_id_
+    }
+
+    pub fn side(&self) -> OrderSide {
+        self.side_
+    }
+
+    pub fn price(&self) -> f32 {
+        self.price_
+    }
+
+    pub fn qty(&self) -> i32 {
+        self.qty_
+    }
+
+    pub fn trade_id(&self) -> u64 {
+        self.trade_id_
+    }
+
+    pub fn time(&self) -> SystemTime {
+        self.time_
+    }
+}
+
+impl OrderBook {
+    pub(crate) fn publish(&mut self, p_update: OrderUpdate) {
+        self.touch_level(&p_update);
+        self.feed_seq_ += 1;
+        self.feed_.push(OrderFeedMessage {
+            symbol_: self.symbol_.to_owned(),
+            seq_: self.feed_seq_,
+            update_: p_update,
+        });
+    }
+
+    pub(crate) fn next_trade_id(&mut self) -> u64 {
+        self.trade_seq_ += 1;
+        self.trade_seq_
+    }
+
+    //publishes the executions of p_result, every fill is a trade on its own
+    //unless p_trade_id is given for all of them as in an auction
+    pub(crate) fn publish_executions(
+        &mut self,
+        p_result: &mut MatchingResult,
+        p_trade_id: Option<u64>,
+    ) {
+        for fill_index in 0..p_result.fills_.len() {
+            let trade_id = match p_trade_id {
+                None => self.next_trade_id(),
+                Some(trade_id) => trade_id,
+            };
+            let fill = &mut p_result.fills_[fill_index];
+            fill.trade_id_ = trade_id;
+            let update = OrderUpdate::Execute {
+                order_id_: fill.maker_order_id_.to_owned(),
+                side_: fill.side_,
+                price_: fill.price_,
+                qty_: fill.qty_,
+                trade_id_: trade_id,
+            };
+            self.publish(update);
+        }
+    }
+
+    //publishes resting orders reduced or removed by self-trade prevention in p_level
+    pub(crate) fn publish_self_trades(&mut self, p_level: &Level, p_result: &MatchingResult) {
+        for event in &p_result.self_trade_events_ {
+            if let EngineEvent::SelfTradePrevented {
+                resting_order_id_,
+                resting_cancelled_qty_,
+                ..
+            } = event
+            {
+                if *resting_cancelled_qty_ == 0 {
+                    continue;
+                }
+                let resting_order = p_level
+                    .orders_
+                    .iter()
+                    .find(|order| &order.id_ == resting_order_id_);
+                let update = match resting_order {
+                    Some(resting_order) => OrderUpdate::Modify {
+                        order_id_: resting_order.id_.to_owned(),
+                        side_: p_level.side_,
+                        price_: p_level.price_,
+                        qty_: resting_order.qty_,
+                    },
+                    None => OrderUpdate::Delete {
+                        order_id_: resting_order_id_.to_owned(),
+                        side_: p_level.side_,
+                        price_: p_level.price_,
+                    },
+                };
+                self.publish(update);
+            }
+        }
+    }
+}
+
+impl MatchingEngine {
+    //moves the order feed of p_symbol's book into the engine events
+    pub(crate) fn collect_order_feed(&mut self, p_symbol: &String) {
+        if let Some(order_book) = self.order_book_by_symbol_.get_mut(p_symbol) {
+            let feed = std::mem::take(&mut order_book.feed_);
+            self.events_
+                .extend(feed.into_iter().map(EngineEvent::OrderFeed));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_order as order;
+    use crate::{process_event, EventType};
+    use std::collections::BTreeMap;
+
+    fn feed(p_engine: &mut MatchingEngine) -> Vec<OrderFeedMessage> {
+        p_engine
+            .drain_events()
+            .into_iter()
+            .filter_map(|event| match event {
+                EngineEvent::OrderFeed(message) => Some(message),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn feed_messages_in_sequence() {
+        let mut engine = MatchingEngine::new();
+        let mut sell = order("1", OrderSide::Sell, 100, 100.0);
+        process_event(EventType::New, &mut sell, &mut engine).unwrap();
+        let mut sell = order("2", OrderSide::Sell, 100, 100.0);
+        process_event(EventType::New, &mut sell, &mut engine).unwrap();
+        let mut buy = order("3", OrderSide::Buy, 150, 100.0);
+        process_event(EventType::New, &mut buy, &mut engine).unwrap();
+        let mut cancel = order("2", OrderSide::Sell, 50, 100.0);
+        process_event(EventType::Cxl, &mut cancel, &mut engine).unwrap();
+
+        let messages = feed(&mut engine);
+        let seqs: Vec<u64> = messages.iter().map(|message| message.seq()).collect();
+        assert_eq!(seqs, vec![1, 2, 3, 4, 5]);
+        let updates: Vec<OrderUpdate> = messages
+            .into_iter()
+            .map(|message| message.update_)
+            .collect();
+        assert_eq!(
+            updates[2..],
+            [
+                OrderUpdate::Execute {
+                    order_id_: String::from("1"),
+                    side_: OrderSide::Sell,
+                    price_: 100.0,
+                    qty_: 100,
+                    trade_id_: 1,
+                },
+                OrderUpdate::Execute {
+                    order_id_: String::from("2"),
+                    side_: OrderSide::Sell,
+                    price_: 100.0,
+                    qty_: 50,
+                    trade_id_: 2,
+                },
+                OrderUpdate::Delete {
+                    order_id_: String::from("2"),
+                    side_: OrderSide::Sell,
+                    price_: 100.0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rebuilt_book_matches_engine() {
+        let symbol = String::from("REL");
+        let mut engine = MatchingEngine::new();
+        let orders = [
+            order("1", OrderSide::Buy, 100, 99.0),
+            order("2", OrderSide::Buy, 100, 99.0),
+            order("3", OrderSide::Sell, 100, 101.0),
+            order("4", OrderSide::Sell, 130, 99.0),
+            order("5", OrderSide::Buy, 30, 98.0),
+        ];
+        for mut order in orders {
+            process_event(EventType::New, &mut order, &mut engine).unwrap();
+        }
+
+        //order id -> (side, price, qty) rebuilt from the feed
+        let mut rebuilt: BTreeMap<String, (OrderSide, f32, i32)> = BTreeMap::new();
+        for message in feed(&mut engine) {
+            match message.update_ {
+                OrderUpdate::Add {
+                    order_id_,
+                    side_,
+                    price_,
+                    qty_,
+                }
+                | OrderUpdate::Modify {
+                    order_id_,
+                    side_,
+                    price_,
+                    qty_,
+                } => {
+                    rebuilt.insert(order_id_, (side_, price_, qty_));
+                }
+                OrderUpdate::Delete { order_id_, .. } => {
+                    rebuilt.remove(&order_id_);
+                }
+                OrderUpdate::Execute {
+                    order_id_, qty_, ..
+                } => {
+                    let resting = rebuilt.get_mut(&order_id_).unwrap();
+                    resting.2 -= qty_;
+                    if resting.2 == 0 {
+                        rebuilt.remove(&order_id_);
+                    }
+                }
+            }
+        }
+
+        let mut expected: BTreeMap<String, (OrderSide, f32, i32)> = BTreeMap::new();
+        let order_book = &engine.order_book_by_symbol_[&symbol];
+        for level in order_book.bids_.iter().chain(order_book.asks_.iter()) {
+            for order in &level.orders_ {
+                expected.insert(
+                    order.id_.to_owned(),
+                    (order.side_, order.price_, order.qty_),
+                );
+            }
+        }
+        assert_eq!(rebuilt, expected);
+        assert_eq!(rebuilt["2"], (OrderSide::Buy, 99.0, 70));
+    }
+}
@@ -0,0 +1,183 @@
+use std::fmt::Debug;
+use std::time::SystemTime;
+
+use crate::command::{put_command_input, EngineCommand};
+use crate::journal::put_order;
+use crate::{EventType, MatchingEngine, Order};
+
+//Deterministic replay
+// The same inputs in the same order give the same outputs: the per symbol state is
+// kept in ordered maps, resting orders with equal entry times queue in the order
+// they arrived in and times come from the engine clock, which stamps them on the
+// inputs before they are journaled. Every input, orders and engine commands alike,
+// and everything it produced (its result and the engine events) is folded into a
+// rolling state hash, so a primary and a replay of its journal can be compared at
+// any input sequence number.
+
+pub(crate) const INITIAL_STATE_HASH: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+//64 bit FNV-1a, unlike DefaultHasher it is the same on every platform and release
+fn fnv1a(p_hash: u64, p_bytes: &[u8]) -> u64 {
+    p_bytes.iter().fold(p_hash, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+impl MatchingEngine {
+    //number of inputs processed, the sequence number state_hash is at
+    pub fn input_seq(&self) -> u64 {
+        self.input_seq_
+    }
+
+    pub fn state_hash(&self) -> u64 {
+        self.state_hash_
+    }
+
+    //numbers p_order and folds it into the state hash,
+    //returns the index its engine events will start at
+    pub(crate) fn accept_input(&mut self, p_event_type: EventType, p_order: &mut Order) -> usize {
+        self.input_seq_ += 1;
+        p_order.seq_ = self.input_seq_;
+
+        let mut input = self.input_seq_.to_be_bytes().to_vec();
+        input.extend_from_slice(format!("{:?}", p_event_type).as_bytes());
+        put_order(&mut input, p_order);
+        self.state_hash_ = fnv1a(self.state_hash_, &input);
+        return self.events_.len();
+    }
+
+    //numbers p_command accepted at p_time and folds it into the state hash,
+    //returns the index its engine events will start at
+    pub(crate) fn accept_command(
+        &mut self,
+        p_time: SystemTime,
+        p_command: &EngineCommand,
+    ) -> usize {
+        self.input_seq_ += 1;
+
+        let mut input = self.input_seq_.to_be_bytes().to_vec();
+        put_command_input(&mut input, p_time, p_command);
+        self.state_hash_ = fnv1a(self.state_hash_, &input);
+        return self.events_.len();
+    }
+
+    //folds p_result and the engine events from p_first_event on into the state hash
+    pub(crate) fn hash_outputs<T: Debug>(&mut self, p_first_event: usize, p_result: &T) {
+        let mut hash = fnv1a(self.state_hash_, format!("{:?}", p_result).as_bytes());
+        for event in self.events_.iter().skip(p_first_event) {
+            hash = fnv1a(hash, format!("{:?}", event).as_bytes());
+        }
+        self.state_hash_ = hash;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::journal::Journal;
+    use crate::test_order;
+    use crate::{process_event, OrderSide};
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+
+    fn order(p_id: &str, p_side: OrderSide, p_qty: i32, p_price: f32, p_time: SystemTime) -> Order {
+        test_order(p_id, p_side, p_qty, p_price).with_time(p_time)
+    }
+
+    fn journal_path(p_name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("ome_{}_{}.journal", p_name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        return path;
+    }
+
+    #[test]
+    fn equal_entry_times_queue_in_arrival_order() {
+        let symbol = String::from("REL");
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let mut engine = MatchingEngine::new();
+        engine.set_clock(ManualClock::new(time));
+        for id in ["1", "2", "3"] {
+            let mut buy = order(id, OrderSide::Buy, 100, 100.0, time);
+            process_event(EventType::New, &mut buy, &mut engine).unwrap();
+        }
+        assert_eq!(engine.depth(&symbol, 1).unwrap().bids()[0].qty(), 300);
+
+        let mut sell = order("4", OrderSide::Sell, 150, 100.0, time);
+        let result = process_event(EventType::New, &mut sell, &mut engine)
+            .unwrap()
+            .unwrap();
+        assert_eq!(result.matched_order_ids_, vec!["1", "2"]);
+        assert_eq!(engine.input_seq(), 4);
+        assert_eq!(engine.engine_time(), time);
+    }
+
+    #[test]
+    fn replay_matches_primary_at_every_input() {
+        let path = journal_path("replay_hash");
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let at = |seconds| start + Duration::from_secs(seconds);
+        let clock = ManualClock::new(start);
+        let mut primary = MatchingEngine::new();
+        primary.set_clock(clock.clone());
+        primary.attach_journal(Journal::open(&path).unwrap());
+        let inputs = [
+            (EventType::New, order("1", OrderSide::Buy, 100, 99.0, at(0))),
+            (EventType::New, order("2", OrderSide::Buy, 50, 99.0, at(0))),
+            (
+                EventType::New,
+                order("3", OrderSide::Sell, 120, 99.0, at(1)),
+            ),
+            (EventType::Rpl, order("2", OrderSide::Buy, 80, 99.0, at(2))),
+            (
+                EventType::New,
+                order("4", OrderSide::Sell, 10, 101.0, at(2)),
+            ),
+            (
+                EventType::Cxl,
+                order("9", OrderSide::Sell, 10, 101.0, at(3)),
+            ),
+            (
+                EventType::New,
+                order("5", OrderSide::Sell, 100, 99.0, at(3)),
+            ),
+        ];
+        let mut checkpoints = Vec::new();
+        for (event_type, mut order) in inputs {
+            clock.set(order.entry_time_);
+            let _ = process_event(event_type, &mut order, &mut primary);
+            checkpoints.push((
+                primary.input_seq(),
+                primary.state_hash(),
+                primary.drain_events(),
+            ));
+        }
+
+        let mut replica = MatchingEngine::new();
+        for (record, (input_seq, state_hash, events)) in
+            Journal::read(&path).unwrap().iter().zip(&checkpoints)
+        {
+            replica.replay(std::slice::from_ref(record));
+            assert_eq!(replica.input_seq(), *input_seq);
+            assert_eq!(replica.state_hash(), *state_hash);
+            assert_eq!(&replica.drain_events(), events);
+        }
+        assert_eq!(
+            replica.full_depth(&String::from("REL")),
+            primary.full_depth(&String::from("REL"))
+        );
+
+        //a different outcome shows in the hash
+        let mut diverged = MatchingEngine::new();
+        diverged.set_clock(ManualClock::new(at(1)));
+        let records = Journal::read(&path).unwrap();
+        diverged.replay(&records[..2]);
+        let mut other = order("3", OrderSide::Sell, 100, 99.0, at(1));
+        process_event(EventType::New, &mut other, &mut diverged).unwrap();
+        assert_eq!(diverged.input_seq(), checkpoints[2].0);
+        assert_ne!(diverged.state_hash(), checkpoints[2].1);
+        let _ = std::fs::remove_file(&path);
+    }
+}
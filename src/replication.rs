@@ -0,0 +1,546 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use crate::command::{put_input, EngineCommand, EngineInput};
+use crate::journal::{crc32, Reader};
+use crate::{apply_event, EventType, MatchingEngine, MatchingResult, Order};
+
+//Primary/backup replication
+// The primary stamps every input, order or engine command, with its acceptance
+// time and sends it to the backup, which applies it and acknowledges with its input sequence and state
+// hash. Only then does the primary apply the input itself and answer, so anything
+// a client got an answer for is in the backup already. The primary compares the
+// acknowledged state hash with its own after applying, and its heartbeats carry
+// both so the backup checks them too; replicas that diverge stop replicating.
+// A backup that hears nothing for the heartbeat timeout, or loses the connection,
+// takes over with the engine as it stands. A backup that fails leaves the primary
+// trading on its own.
+//
+// A frame is the payload length (4), the payload and a CRC-32 of the payload (4),
+// integers big endian. The payload starts with a message kind byte.
+
+const START: u8 = b'S';
+const INPUT: u8 = b'I';
+const HEARTBEAT: u8 = b'H';
+const ACK: u8 = b'A';
+
+#[derive(Clone, Debug)]
+enum ReplicationMessage {
+    //state the primary replicates from, the backup has to be in the same one
+    Start { input_seq_: u64, state_hash_: u64 },
+    Input { input_: EngineInput },
+    Heartbeat { input_seq_: u64, state_hash_: u64 },
+    Ack { input_seq_: u64, state_hash_: u64 },
+}
+
+impl ReplicationMessage {
+    fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        match self {
+            ReplicationMessage::Start {
+                input_seq_,
+                state_hash_,
+            } => {
+                payload.push(START);
+                payload.extend_from_slice(&input_seq_.to_be_bytes());
+                payload.extend_from_slice(&state_hash_.to_be_bytes());
+            }
+            ReplicationMessage::Input { input_ } => {
+                payload.push(INPUT);
+                put_input(&mut payload, input_);
+            }
+            ReplicationMessage::Heartbeat {
+                input_seq_,
+                state_hash_,
+            } => {
+                payload.push(HEARTBEAT);
+                payload.extend_from_slice(&input_seq_.to_be_bytes());
+                payload.extend_from_slice(&state_hash_.to_be_bytes());
+            }
+            ReplicationMessage::Ack {
+                input_seq_,
+                state_hash_,
+            } => {
+                payload.push(ACK);
+                payload.extend_from_slice(&input_seq_.to_be_bytes());
+                payload.extend_from_slice(&state_hash_.to_be_bytes());
+            }
+        }
+
+        let mut frame = Vec::with_capacity(payload.len() + 8);
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&payload);
+        frame.extend_from_slice(&crc32(&payload).to_be_bytes());
+        return frame;
+    }
+
+    fn decode(p_payload: &[u8]) -> Result<ReplicationMessage, String> {
+        let mut reader = Reader::new(p_payload);
+        let message = match reader.u8()? {
+            START => ReplicationMessage::Start {
+                input_seq_: reader.u64()?,
+                state_hash_: reader.u64()?,
+            },
+            INPUT => ReplicationMessage::Input {
+                input_: reader.input()?,
+            },
+            HEARTBEAT => ReplicationMessage::Heartbeat {
+                input_seq_: reader.u64()?,
+                state_hash_: reader.u64()?,
+            },
+            ACK => ReplicationMessage::Ack {
+                input_seq_: reader.u64()?,
+                state_hash_: reader.u64()?,
+            },
+            kind => return Err(format!("Unknown replication message kind {}", kind)),
+        };
+        if !reader.is_empty() {
+            return Err(String::from("Replication message has trailing data"));
+        }
+        return Ok(message);
+    }
+
+    fn write_to(&self, p_stream: &mut TcpStream) -> std::io::Result<()> {
+        p_stream.write_all(&self.encode())
+    }
+
+    //io errors as they are, a damaged frame as InvalidData
+    fn read_from(p_stream: &mut TcpStream) -> std::io::Result<ReplicationMessage> {
+        let mut len = [0u8; 4];
+        p_stream.read_exact(&mut len)?;
+        let mut payload = vec![0u8; u32::from_be_bytes(len) as usize];
+        p_stream.read_exact(&mut payload)?;
+        let mut checksum = [0u8; 4];
+        p_stream.read_exact(&mut checksum)?;
+        if crc32(&payload) != u32::from_be_bytes(checksum) {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "Replication frame is corrupt",
+            ));
+        }
+        return ReplicationMessage::decode(&payload)
+            .map_err(|error| std::io::Error::new(ErrorKind::InvalidData, error));
+    }
+}
+
+fn connection_lost(p_error: &std::io::Error) -> bool {
+    matches!(
+        p_error.kind(),
+        ErrorKind::UnexpectedEof
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+    )
+}
+
+fn timed_out(p_error: &std::io::Error) -> bool {
+    matches!(p_error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+pub struct Primary {
+    engine_: MatchingEngine,
+    backup_: Option<TcpStream>,
+    heartbeat_interval_: Duration,
+    last_sent_: Instant,
+}
+
+impl Primary {
+    //connects to the backup at p_backup_addr and starts replicating p_engine.
+    //an acknowledgement not received within three heartbeat intervals drops the backup
+    pub fn connect(
+        p_engine: MatchingEngine,
+        p_backup_addr: impl ToSocketAddrs,
+        p_heartbeat_interval: Duration,
+    ) -> Result<Primary, String> {
+        let mut stream = TcpStream::connect(p_backup_addr)
+            .map_err(|error| format!("Failed to connect to the backup: {}", error))?;
+        stream
+            .set_nodelay(true)
+            .and_then(|_| stream.set_read_timeout(Some(p_heartbeat_interval * 3)))
+            .and_then(|_| stream.set_write_timeout(Some(p_heartbeat_interval * 3)))
+            .map_err(|error| format!("Failed to set up the backup connection: {}", error))?;
+        ReplicationMessage::Start {
+            input_seq_: p_engine.input_seq(),
+            state_hash_: p_engine.state_hash(),
+        }
+        .write_to(&mut stream)
+        .map_err(|error| format!("Failed to start replication: {}", error))?;
+        return Ok(Primary {
+            engine_: p_engine,
+            backup_: Some(stream),
+            heartbeat_interval_: p_heartbeat_interval,
+            last_sent_: Instant::now(),
+        });
+    }
+
+    pub fn engine(&self) -> &MatchingEngine {
+        &self.engine_
+    }
+
+    //for draining events, inputs have to go through process_event and
+    //process_command to be replicated
+    pub fn engine_mut(&mut self) -> &mut MatchingEngine {
+        &mut self.engine_
+    }
+
+    pub fn into_engine(self) -> MatchingEngine {
+        self.engine_
+    }
+
+    pub fn is_replicating(&self) -> bool {
+        self.backup_.is_some()
+    }
+
+    //process_event with the input acknowledged by the backup before it is applied
+    pub fn process_event(
+        &mut self,
+        p_event_type: EventType,
+        p_order: &mut Order,
+    ) -> Result<Option<MatchingResult>, String> {
+        self.engine_.stamp_acceptance(p_order);
+        let acknowledged = self.replicate(EngineInput::Order {
+            event_type_: p_event_type,
+            order_: p_order.to_owned(),
+        });
+        let result = apply_event(p_event_type, p_order, &mut self.engine_);
+        self.check_acknowledged(acknowledged);
+        return result;
+    }
+
+    //apply_command with the command acknowledged by the backup before it is applied,
+    //halts, transitions, the price band timer, the purge and the GTC import have to
+    //go through here to be replicated
+    pub fn process_command(
+        &mut self,
+        p_command: &EngineCommand,
+    ) -> Result<Vec<MatchingResult>, String> {
+        let time = self.engine_.engine_time();
+        let acknowledged = self.replicate(EngineInput::Command {
+            time_: time,
+            command_: p_command.to_owned(),
+        });
+        let result = self
+            .engine_
+            .at_input_time(time, |engine| engine.apply_command(p_command));
+        self.check_acknowledged(acknowledged);
+        return result;
+    }
+
+    //drops the backup when the state it acknowledged with differs from the one of the primary
+    fn check_acknowledged(&mut self, p_acknowledged: Option<(u64, u64)>) {
+        if let Some((input_seq, state_hash)) = p_acknowledged {
+            if (input_seq, state_hash) != (self.engine_.input_seq(), self.engine_.state_hash()) {
+                self.drop_backup(&format!(
+                    "backup is at input {} with state hash {:x}, primary at {} with {:x}",
+                    input_seq,
+                    state_hash,
+                    self.engine_.input_seq(),
+                    self.engine_.state_hash()
+                ));
+            }
+        }
+    }
+
+    //sends a heartbeat when nothing went to the backup for a heartbeat interval,
+    //to be called regularly by the thread driving the primary
+    pub fn heartbeat(&mut self) {
+        if self.last_sent_.elapsed() < self.heartbeat_interval_ {
+            return;
+        }
+        let heartbeat = ReplicationMessage::Heartbeat {
+            input_seq_: self.engine_.input_seq(),
+            state_hash_: self.engine_.state_hash(),
+        };
+        if let Some(backup) = &mut self.backup_ {
+            match heartbeat.write_to(backup) {
+                Ok(()) => self.last_sent_ = Instant::now(),
+                Err(error) => self.drop_backup(&format!("heartbeat failed: {}", error)),
+            }
+        }
+    }
+
+    //input sequence and state hash the backup acknowledged p_input with
+    fn replicate(&mut self, p_input: EngineInput) -> Option<(u64, u64)> {
+        let backup = self.backup_.as_mut()?;
+        let input = ReplicationMessage::Input { input_: p_input };
+        let acknowledged = input
+            .write_to(backup)
+            .and_then(|_| ReplicationMessage::read_from(backup));
+        self.last_sent_ = Instant::now();
+        match acknowledged {
+            Ok(ReplicationMessage::Ack {
+                input_seq_,
+                state_hash_,
+            }) => return Some((input_seq_, state_hash_)),
+            Ok(message) => self.drop_backup(&format!("unexpected {:?}", message)),
+            Err(error) => self.drop_backup(&format!("no acknowledgement: {}", error)),
+        }
+        return None;
+    }
+
+    fn drop_backup(&mut self, p_reason: &str) {
+        println!("Replication to the backup stopped, {}", p_reason);
+        if let Some(backup) = self.backup_.take() {
+            let _ = backup.shutdown(std::net::Shutdown::Both);
+        }
+    }
+}
+
+pub struct Backup {
+    engine_: MatchingEngine,
+    listener_: TcpListener,
+    heartbeat_timeout_: Duration,
+}
+
+impl Backup {
+    //p_engine has to be in the state the primary starts from, both new or both
+    //restored from the same snapshot and journal
+    pub fn bind(
+        p_engine: MatchingEngine,
+        p_addr: impl ToSocketAddrs,
+        p_heartbeat_timeout: Duration,
+    ) -> Result<Backup, String> {
+        let listener = TcpListener::bind(p_addr)
+            .map_err(|error| format!("Failed to bind the backup: {}", error))?;
+        return Ok(Backup {
+            engine_: p_engine,
+            listener_: listener,
+            heartbeat_timeout_: p_heartbeat_timeout,
+        });
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, String> {
+        self.listener_
+            .local_addr()
+            .map_err(|error| format!("Failed to get the backup address: {}", error))
+    }
+
+    //follows the primary in lock-step until it fails, then returns the engine to
+    //take over with. Err when the replicas diverged, the engine can not be trusted
+    pub fn follow(mut self) -> Result<MatchingEngine, String> {
+        let (mut stream, primary_addr) = self
+            .listener_
+            .accept()
+            .map_err(|error| format!("Failed to accept the primary: {}", error))?;
+        stream
+            .set_nodelay(true)
+            .and_then(|_| stream.set_read_timeout(Some(self.heartbeat_timeout_)))
+            .map_err(|error| format!("Failed to set up the primary connection: {}", error))?;
+        println!("Backup following the primary at {}", primary_addr);
+
+        loop {
+            let message = match ReplicationMessage::read_from(&mut stream) {
+                Ok(message) => message,
+                Err(error) if timed_out(&error) => {
+                    println!(
+                        "No heartbeat from the primary for {:?}, taking over at input {}",
+                        self.heartbeat_timeout_,
+                        self.engine_.input_seq()
+                    );
+                    return Ok(self.engine_);
+                }
+                Err(error) if connection_lost(&error) => {
+                    println!(
+                        "Primary connection lost ({}), taking over at input {}",
+                        error,
+                        self.engine_.input_seq()
+                    );
+                    return Ok(self.engine_);
+                }
+                Err(error) => return Err(format!("Replication failed: {}", error)),
+            };
+
+            match message {
+                ReplicationMessage::Start {
+                    input_seq_,
+                    state_hash_,
+                }
+                | ReplicationMessage::Heartbeat {
+                    input_seq_,
+                    state_hash_,
+                } => self.cross_check(input_seq_, state_hash_)?,
+                ReplicationMessage::Input { input_ } => {
+                    //rejections are replicated as they are, the primary gets the same
+                    let _ = self.engine_.apply_input(&input_);
+                    let ack = ReplicationMessage::Ack {
+                        input_seq_: self.engine_.input_seq(),
+                        state_hash_: self.engine_.state_hash(),
+                    };
+                    if let Err(error) = ack.write_to(&mut stream) {
+                        println!(
+                            "Primary connection lost ({}), taking over at input {}",
+                            error,
+                            self.engine_.input_seq()
+                        );
+                        return Ok(self.engine_);
+                    }
+                }
+                ReplicationMessage::Ack { .. } => {
+                    return Err(String::from("Replication failed: backup got an Ack"));
+                }
+            }
+        }
+    }
+
+    fn cross_check(&self, p_input_seq: u64, p_state_hash: u64) -> Result<(), String> {
+        if (p_input_seq, p_state_hash) != (self.engine_.input_seq(), self.engine_.state_hash()) {
+            return Err(format!(
+                "Replicas diverged: primary at input {} with state hash {:x}, backup at {} with {:x}",
+                p_input_seq,
+                p_state_hash,
+                self.engine_.input_seq(),
+                self.engine_.state_hash()
+            ));
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::session::{HaltOrderPolicy, TradingState};
+    use crate::test_order as order;
+    use crate::{process_event, OrderSide, TimeInForce};
+    use std::thread::JoinHandle;
+
+    fn start_backup(
+        p_engine: MatchingEngine,
+        p_timeout: Duration,
+    ) -> (SocketAddr, JoinHandle<Result<MatchingEngine, String>>) {
+        let backup = Backup::bind(p_engine, "127.0.0.1:0", p_timeout).unwrap();
+        let addr = backup.local_addr().unwrap();
+        return (addr, std::thread::spawn(move || backup.follow()));
+    }
+
+    fn inputs() -> Vec<(EventType, Order)> {
+        vec![
+            (EventType::New, order("1", OrderSide::Buy, 100, 99.0)),
+            (EventType::New, order("2", OrderSide::Buy, 100, 99.0)),
+            (EventType::New, order("3", OrderSide::Sell, 150, 99.0)),
+            (EventType::Rpl, order("2", OrderSide::Buy, 20, 99.0)),
+            (EventType::Cxl, order("7", OrderSide::Buy, 20, 99.0)),
+            (EventType::New, order("4", OrderSide::Sell, 10, 101.0)),
+        ]
+    }
+
+    #[test]
+    fn backup_takes_over_when_primary_is_killed() {
+        let symbol = String::from("REL");
+        let (addr, backup) = start_backup(MatchingEngine::new(), Duration::from_secs(5));
+        let mut primary =
+            Primary::connect(MatchingEngine::new(), addr, Duration::from_millis(50)).unwrap();
+        for (event_type, mut order) in inputs() {
+            let _ = primary.process_event(event_type, &mut order);
+            primary.heartbeat();
+        }
+        assert!(primary.is_replicating());
+
+        //killed in the middle of sending the next input
+        let frame = ReplicationMessage::Input {
+            input_: EngineInput::Order {
+                event_type_: EventType::New,
+                order_: order("5", OrderSide::Buy, 10, 101.0),
+            },
+        }
+        .encode();
+        let stream = primary.backup_.as_mut().unwrap();
+        stream.write_all(&frame[..frame.len() / 2]).unwrap();
+        let primary = primary.into_engine();
+
+        let mut backup = backup.join().unwrap().unwrap();
+        assert_eq!(backup.input_seq(), primary.input_seq());
+        assert_eq!(backup.state_hash(), primary.state_hash());
+        assert_eq!(backup.full_depth(&symbol), primary.full_depth(&symbol));
+
+        //the new primary carries on from there
+        let mut buy = order("5", OrderSide::Buy, 10, 101.0);
+        let result = process_event(EventType::New, &mut buy, &mut backup).unwrap();
+        assert_eq!(result.unwrap().matched_order_ids_, vec!["4"]);
+    }
+
+    #[test]
+    fn session_changes_replicated_before_failover() {
+        let symbol = String::from("REL");
+        let path = std::env::temp_dir().join(format!("replication_{}.gtc", std::process::id()));
+        let mut previous_day = MatchingEngine::new();
+        let mut gtc = order("1", OrderSide::Buy, 100, 99.0).with_time_in_force(TimeInForce::Gtc);
+        process_event(EventType::New, &mut gtc, &mut previous_day).unwrap();
+        previous_day.export_gtc_orders(&path).unwrap();
+
+        let queueing_engine = || {
+            let mut engine = MatchingEngine::new();
+            engine.set_halt_order_policy(HaltOrderPolicy::Queue);
+            engine
+        };
+        let (addr, backup) = start_backup(queueing_engine(), Duration::from_secs(5));
+        let mut primary =
+            Primary::connect(queueing_engine(), addr, Duration::from_millis(50)).unwrap();
+        let transition = |state| EngineCommand::Transition {
+            symbol_: symbol.to_owned(),
+            state_: state,
+        };
+        primary
+            .process_command(&transition(TradingState::Closed))
+            .unwrap();
+        primary
+            .process_command(&transition(TradingState::PreOpen))
+            .unwrap();
+        let import = EngineCommand::ImportGtcOrders {
+            orders_: MatchingEngine::read_gtc_orders(&path).unwrap(),
+        };
+        primary.process_command(&import).unwrap();
+        primary
+            .process_command(&transition(TradingState::Continuous))
+            .unwrap();
+        let halt = EngineCommand::Halt {
+            symbol_: symbol.to_owned(),
+            reason_: String::from("news pending"),
+        };
+        primary.process_command(&halt).unwrap();
+        let mut sell = order("2", OrderSide::Sell, 100, 99.0);
+        assert!(primary
+            .process_event(EventType::New, &mut sell)
+            .unwrap()
+            .is_none());
+        assert!(primary.is_replicating());
+        let primary = primary.into_engine();
+
+        //the backup takes over halted with the imported order and the queued one
+        let mut backup = backup.join().unwrap().unwrap();
+        assert_eq!(backup.input_seq(), primary.input_seq());
+        assert_eq!(backup.state_hash(), primary.state_hash());
+        assert_eq!(backup.trading_state(&symbol), TradingState::Halted);
+        let results = backup.resume(&symbol, TradingState::Continuous).unwrap();
+        assert_eq!(results[0].matched_order_ids_, vec!["1"]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn heartbeat_timeout_and_divergence() {
+        let (addr, backup) = start_backup(MatchingEngine::new(), Duration::from_millis(200));
+        let mut primary =
+            Primary::connect(MatchingEngine::new(), addr, Duration::from_millis(20)).unwrap();
+        let mut buy = order("1", OrderSide::Buy, 100, 99.0);
+        primary.process_event(EventType::New, &mut buy).unwrap();
+        for _ in 0..10 {
+            std::thread::sleep(Duration::from_millis(30));
+            primary.heartbeat();
+        }
+        //alive but silent
+        let started = Instant::now();
+        let backup = backup.join().unwrap().unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(backup.state_hash(), primary.engine().state_hash());
+
+        //a backup not in the state of the primary refuses to follow it
+        let (addr, backup) = start_backup(backup, Duration::from_secs(5));
+        let mut primary =
+            Primary::connect(MatchingEngine::new(), addr, Duration::from_millis(20)).unwrap();
+        assert!(backup.join().unwrap().unwrap_err().contains("diverged"));
+        let mut buy = order("2", OrderSide::Buy, 100, 99.0);
+        primary.process_event(EventType::New, &mut buy).unwrap();
+        assert!(!primary.is_replicating());
+    }
+}
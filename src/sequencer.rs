@@ -0,0 +1,455 @@
+use std::collections::BTreeMap;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::command::{EngineCommand, EngineInput};
+use crate::events::EngineEvent;
+use crate::{apply_event, EventType, MatchingEngine, MatchingResult, Order};
+
+//Central sequencer
+// Gateways hand their inputs, orders and engine commands, to the sequencer over
+// one queue, so whatever the number of gateways and threads there is a single
+// order: the order the inputs reach the queue in, each gateway's own inputs
+// staying in the order it sent them.
+// The sequencer applies them one at a time to its MatchingEngine and fans every
+// one out with its global sequence number, the engine input sequence, to all
+// subscribers: the input as accepted, its result, the engine events it produced
+// and the state hash after it. Gateways pick their answers out of that stream,
+// replicas apply it with apply_sequenced and end up in the same state. An input
+// which never reaches the engine, its journal failing, is not sequenced: nothing
+// goes out for it and only the gateway which submitted it is told why.
+
+#[derive(Clone, Debug)]
+struct GatewayInput {
+    gateway_id_: String,
+    gateway_seq_: u64,
+    input_: EngineInput,
+}
+
+//input side of one gateway, may live on any thread
+pub struct Gateway {
+    id_: String,
+    next_seq_: u64,
+    sender_: Sender<GatewayInput>,
+    //gateway sequence number and reason of the inputs refused by the sequencer
+    unsequenced_: Receiver<(u64, String)>,
+}
+
+impl Gateway {
+    pub fn id(&self) -> &String {
+        &self.id_
+    }
+
+    //queues p_order for sequencing, returns its gateway sequence number
+    pub fn submit(&mut self, p_event_type: EventType, p_order: Order) -> Result<u64, String> {
+        return self.send(EngineInput::Order {
+            event_type_: p_event_type,
+            order_: p_order,
+        });
+    }
+
+    //queues p_command for sequencing, returns its gateway sequence number. The
+    //sequencer stamps it with its acceptance time
+    pub fn submit_command(&mut self, p_command: EngineCommand) -> Result<u64, String> {
+        return self.send(EngineInput::Command {
+            time_: SystemTime::now(),
+            command_: p_command,
+        });
+    }
+
+    fn send(&mut self, p_input: EngineInput) -> Result<u64, String> {
+        let gateway_seq = self.next_seq_ + 1;
+        self.sender_
+            .send(GatewayInput {
+                gateway_id_: self.id_.to_owned(),
+                gateway_seq_: gateway_seq,
+                input_: p_input,
+            })
+            .map_err(|_| format!("Sequencer is gone, gateway {} can not submit", self.id_))?;
+        self.next_seq_ = gateway_seq;
+        return Ok(gateway_seq);
+    }
+
+    //inputs refused by the sequencer since the last call, gateway sequence number
+    //and reason
+    pub fn unsequenced(&self) -> Vec<(u64, String)> {
+        self.unsequenced_.try_iter().collect()
+    }
+}
+
+//what became of the input sequence_next waited for
+#[derive(Clone, Debug, PartialEq)]
+pub enum SequenceOutcome {
+    //global sequence number of the input
+    Sequenced(u64),
+    //the input never reached the engine, its gateway has the reason too
+    Unsequenced(String),
+    //no input within the timeout
+    TimedOut,
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SequencedOutput {
+    #[cfg_attr(feature = "serde", serde(rename = "seq"))]
+    seq_: u64,
+    #[cfg_attr(feature = "serde", serde(rename = "gateway_id"))]
+    gateway_id_: String,
+    #[cfg_attr(feature = "serde", serde(rename = "gateway_seq"))]
+    gateway_seq_: u64,
+    //stamped with its acceptance time
+    #[cfg_attr(feature = "serde", serde(rename = "input"))]
+    input_: EngineInput,
+    //the result of an order, or the uncrosses and released orders of a command
+    #[cfg_attr(feature = "serde", serde(rename = "result"))]
+    result_: Result<Vec<MatchingResult>, String>,
+    #[cfg_attr(feature = "serde", serde(rename = "events"))]
+    events_: Vec<EngineEvent>,
+    #[cfg_attr(feature = "serde", serde(rename = "state_hash"))]
+    state_hash_: u64,
+}
+
+impl SequencedOutput {
+    pub fn seq(&self) -> u64 {
+        self.seq_
+    }
+
+    pub fn gateway_id(&self) -> &String {
+        &self.gateway_id_
+    }
+
+    pub fn gateway_seq(&self) -> u64 {
+        self.gateway_seq_
+    }
+
+    pub fn input(&self) -> &EngineInput {
+        &self.input_
+    }
+
+    pub fn result(&self) -> &Result<Vec<MatchingResult>, String> {
+        &self.result_
+    }
+
+    pub fn events(&self) -> &[EngineEvent] {
+        &self.events_
+    }
+
+    pub fn state_hash(&self) -> u64 {
+        self.state_hash_
+    }
+}
+
+pub struct Sequencer {
+    engine_: MatchingEngine,
+    sender_: Sender<GatewayInput>,
+    inputs_: Receiver<GatewayInput>,
+    //where each gateway hears about its unsequenced inputs
+    unsequenced_by_gateway_: BTreeMap<String, Sender<(u64, String)>>,
+    subscribers_: Vec<Sender<Arc<SequencedOutput>>>,
+}
+
+impl Sequencer {
+    //the engine events are drained into the outputs from now on
+    pub fn new(p_engine: MatchingEngine) -> Self {
+        let (sender, inputs) = channel();
+        Sequencer {
+            engine_: p_engine,
+            sender_: sender,
+            inputs_: inputs,
+            unsequenced_by_gateway_: BTreeMap::new(),
+            subscribers_: Vec::new(),
+        }
+    }
+
+    pub fn engine(&self) -> &MatchingEngine {
+        &self.engine_
+    }
+
+    pub fn into_engine(self) -> MatchingEngine {
+        self.engine_
+    }
+
+    pub fn add_gateway(&mut self, p_id: &str) -> Result<Gateway, String> {
+        if self.unsequenced_by_gateway_.contains_key(p_id) {
+            return Err(format!("Gateway {p_id} is already connected"));
+        }
+        let (unsequenced_sender, unsequenced) = channel();
+        self.unsequenced_by_gateway_
+            .insert(p_id.to_owned(), unsequenced_sender);
+        return Ok(Gateway {
+            id_: p_id.to_owned(),
+            next_seq_: 0,
+            sender_: self.sender_.clone(),
+            unsequenced_: unsequenced,
+        });
+    }
+
+    //outputs sequenced from now on, a subscriber dropping its receiver is forgotten
+    pub fn subscribe(&mut self) -> Receiver<Arc<SequencedOutput>> {
+        let (sender, receiver) = channel();
+        self.subscribers_.push(sender);
+        return receiver;
+    }
+
+    //sequences the inputs already queued without waiting for more, returns how many
+    //were sequenced
+    pub fn sequence_pending(&mut self) -> usize {
+        let mut sequenced = 0;
+        while let Ok(input) = self.inputs_.try_recv() {
+            if let SequenceOutcome::Sequenced(_) = self.sequence(input) {
+                sequenced += 1;
+            }
+        }
+        return sequenced;
+    }
+
+    //waits up to p_timeout for the next input and sequences it. The sequencer
+    //keeps a sender for the gateways still to come, so the queue never disconnects
+    pub fn sequence_next(&mut self, p_timeout: Duration) -> SequenceOutcome {
+        match self.inputs_.recv_timeout(p_timeout) {
+            Ok(input) => self.sequence(input),
+            Err(_) => SequenceOutcome::TimedOut,
+        }
+    }
+
+    fn sequence(&mut self, p_input: GatewayInput) -> SequenceOutcome {
+        let GatewayInput {
+            gateway_id_,
+            gateway_seq_,
+            input_,
+        } = p_input;
+        let previous_seq = self.engine_.input_seq();
+        let (accepted_input, result) = match input_ {
+            EngineInput::Order {
+                event_type_,
+                mut order_,
+            } => {
+                self.engine_.stamp_acceptance(&mut order_);
+                //processing changes the order, what goes out is the order as accepted
+                let accepted_input = EngineInput::Order {
+                    event_type_,
+                    order_: order_.clone(),
+                };
+                let result = apply_event(event_type_, &mut order_, &mut self.engine_)
+                    .map(|match_result| match_result.into_iter().collect());
+                (accepted_input, result)
+            }
+            EngineInput::Command { command_, .. } => {
+                let time = self.engine_.engine_time();
+                let result = self
+                    .engine_
+                    .at_input_time(time, |engine| engine.apply_command(&command_));
+                let accepted_input = EngineInput::Command {
+                    time_: time,
+                    command_,
+                };
+                (accepted_input, result)
+            }
+        };
+        if self.engine_.input_seq() == previous_seq {
+            //never reached the engine, nothing to fan out
+            let reason = result.err().unwrap_or_default();
+            println!(
+                "Input {} of gateway {} not sequenced: {}",
+                gateway_seq_, gateway_id_, reason
+            );
+            if let Some(unsequenced) = self.unsequenced_by_gateway_.get(&gateway_id_) {
+                let _ = unsequenced.send((gateway_seq_, reason.to_owned()));
+            }
+            return SequenceOutcome::Unsequenced(reason);
+        }
+
+        let output = Arc::new(SequencedOutput {
+            seq_: self.engine_.input_seq(),
+            gateway_id_,
+            gateway_seq_,
+            input_: accepted_input,
+            result_: result,
+            events_: self.engine_.drain_events(),
+            state_hash_: self.engine_.state_hash(),
+        });
+        self.subscribers_
+            .retain(|subscriber| subscriber.send(output.clone()).is_ok());
+        return SequenceOutcome::Sequenced(output.seq_);
+    }
+}
+
+impl MatchingEngine {
+    //applies an output of a sequencer feeding an engine in the same state as self
+    pub fn apply_sequenced(&mut self, p_output: &SequencedOutput) -> Result<(), String> {
+        if p_output.seq_ != self.input_seq_ + 1 {
+            return Err(format!(
+                "Sequenced input {} received at input {}",
+                p_output.seq_, self.input_seq_
+            ));
+        }
+        let _ = self.apply_input(&p_output.input_);
+        if self.state_hash_ != p_output.state_hash_ {
+            return Err(format!(
+                "State hash {:x} after sequenced input {} where the sequencer had {:x}",
+                self.state_hash_, p_output.seq_, p_output.state_hash_
+            ));
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::journal::Journal;
+    use crate::session::TradingState;
+    use crate::test_order as order;
+    use crate::OrderSide;
+
+    #[test]
+    fn gateways_totally_ordered_and_fanned_out() {
+        let mut sequencer = Sequencer::new(MatchingEngine::new());
+        let consumer = sequencer.subscribe();
+        let replica_feed = sequencer.subscribe();
+        assert!(sequencer.add_gateway("A").is_ok());
+        assert!(sequencer.add_gateway("A").is_err());
+
+        let senders: Vec<_> = ["B", "C", "D"]
+            .iter()
+            .enumerate()
+            .map(|(index, id)| {
+                let mut gateway = sequencer.add_gateway(id).unwrap();
+                std::thread::spawn(move || {
+                    let side = if index % 2 == 0 {
+                        OrderSide::Buy
+                    } else {
+                        OrderSide::Sell
+                    };
+                    for n in 0..20 {
+                        let id = format!("{}{}", gateway.id(), n);
+                        gateway
+                            .submit(EventType::New, order(&id, side, 10, 100.0))
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for seq in 1..=60 {
+            assert_eq!(
+                sequencer.sequence_next(Duration::from_secs(5)),
+                SequenceOutcome::Sequenced(seq)
+            );
+        }
+        for sender in senders {
+            sender.join().unwrap();
+        }
+        assert_eq!(sequencer.sequence_pending(), 0);
+
+        let outputs: Vec<Arc<SequencedOutput>> = consumer.try_iter().collect();
+        let seqs: Vec<u64> = outputs.iter().map(|output| output.seq()).collect();
+        assert_eq!(seqs, (1..=60).collect::<Vec<u64>>());
+        for gateway_id in ["B", "C", "D"] {
+            let gateway_seqs: Vec<u64> = outputs
+                .iter()
+                .filter(|output| output.gateway_id() == gateway_id)
+                .map(|output| output.gateway_seq())
+                .collect();
+            assert_eq!(gateway_seqs, (1..=20).collect::<Vec<u64>>());
+        }
+
+        let mut replica = MatchingEngine::new();
+        for output in replica_feed.try_iter() {
+            replica.apply_sequenced(&output).unwrap();
+            assert_eq!(replica.drain_events(), output.events());
+        }
+        assert_eq!(replica.state_hash(), sequencer.engine().state_hash());
+
+        //a gap is refused
+        assert!(replica.apply_sequenced(&outputs[10]).is_err());
+    }
+
+    #[test]
+    fn commands_sequenced_with_the_orders() {
+        let symbol = String::from("REL");
+        let mut sequencer = Sequencer::new(MatchingEngine::new());
+        let replica_feed = sequencer.subscribe();
+        let mut gateway = sequencer.add_gateway("A").unwrap();
+        let mut control = sequencer.add_gateway("OPS").unwrap();
+
+        gateway
+            .submit(EventType::New, order("1", OrderSide::Buy, 10, 100.0))
+            .unwrap();
+        control
+            .submit_command(EngineCommand::Halt {
+                symbol_: symbol.to_owned(),
+                reason_: String::from("news pending"),
+            })
+            .unwrap();
+        gateway
+            .submit(EventType::New, order("2", OrderSide::Sell, 10, 100.0))
+            .unwrap();
+        control
+            .submit_command(EngineCommand::Resume {
+                symbol_: symbol.to_owned(),
+                state_: TradingState::Continuous,
+            })
+            .unwrap();
+        gateway
+            .submit(EventType::New, order("3", OrderSide::Sell, 10, 100.0))
+            .unwrap();
+        assert_eq!(sequencer.sequence_pending(), 5);
+        assert_eq!(
+            sequencer.engine().trading_state(&symbol),
+            TradingState::Continuous
+        );
+
+        let outputs: Vec<Arc<SequencedOutput>> = replica_feed.try_iter().collect();
+        assert!(matches!(
+            outputs[1].input(),
+            EngineInput::Command {
+                command_: EngineCommand::Halt { .. },
+                ..
+            }
+        ));
+        assert_eq!(outputs[1].seq(), 2);
+        assert!(outputs[2].result().is_err());
+        assert_eq!(outputs[4].result().as_ref().unwrap()[0].executed_qty_, 10);
+
+        let mut replica = MatchingEngine::new();
+        for output in &outputs {
+            replica.apply_sequenced(output).unwrap();
+            assert_eq!(replica.drain_events(), output.events());
+        }
+        assert_eq!(replica.state_hash(), sequencer.engine().state_hash());
+    }
+
+    #[test]
+    fn unsequenced_input_reported_to_its_gateway() {
+        let journal_path =
+            std::env::temp_dir().join(format!("sequencer_{}_unsequenced.bin", std::process::id()));
+        std::fs::write(&journal_path, []).unwrap();
+        let mut engine = MatchingEngine::new();
+        engine.attach_journal(Journal::read_only(&journal_path));
+        let mut sequencer = Sequencer::new(engine);
+        let consumer = sequencer.subscribe();
+        let mut gateway = sequencer.add_gateway("A").unwrap();
+        let other_gateway = sequencer.add_gateway("B").unwrap();
+
+        gateway
+            .submit(EventType::New, order("1", OrderSide::Buy, 10, 100.0))
+            .unwrap();
+        let outcome = sequencer.sequence_next(Duration::from_secs(5));
+        let reason = match outcome {
+            SequenceOutcome::Unsequenced(reason) => reason,
+            _ => panic!("input sequenced without its journal record: {:?}", outcome),
+        };
+        assert_eq!(gateway.unsequenced(), vec![(1, reason)]);
+        assert!(other_gateway.unsequenced().is_empty());
+        assert!(consumer.try_recv().is_err());
+        assert_eq!(sequencer.engine().input_seq(), 0);
+
+        assert_eq!(
+            sequencer.sequence_next(Duration::from_millis(10)),
+            SequenceOutcome::TimedOut
+        );
+        std::fs::remove_file(&journal_path).unwrap();
+    }
+}
@@ -0,0 +1,249 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{EventType, Order, OrderType};
+
+//Trading session
+// Every symbol moves through these phases during a trading day. Symbols without
+// a configured session are treated as Continuous so the engine keeps matching
+// everything as before.
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TradingState {
+    PreOpen,
+    OpeningAuction,
+    Continuous,
+    ClosingAuction,
+    Halted,
+    Closed,
+    PostClose,
+}
+
+impl TradingState {
+    //true when incoming orders are allowed to match immediately
+    pub fn is_matching(&self) -> bool {
+        *self == TradingState::Continuous
+    }
+
+    //true when the book is in a call period and is uncrossed when the phase ends
+    pub fn is_auction_call(&self) -> bool {
+        matches!(
+            self,
+            TradingState::PreOpen | TradingState::OpeningAuction | TradingState::ClosingAuction
+        )
+    }
+
+    pub fn can_transition_to(&self, p_next: TradingState) -> bool {
+        if *self == p_next {
+            return false;
+        }
+        match self {
+            TradingState::PreOpen => matches!(
+                p_next,
+                TradingState::OpeningAuction
+                    | TradingState::Continuous
+                    | TradingState::Halted
+                    | TradingState::Closed
+            ),
+            TradingState::OpeningAuction => matches!(
+                p_next,
+                TradingState::Continuous | TradingState::Halted | TradingState::Closed
+            ),
+            TradingState::Continuous => matches!(
+                p_next,
+                TradingState::ClosingAuction | TradingState::Halted | TradingState::Closed
+            ),
+            TradingState::ClosingAuction => {
+                matches!(p_next, TradingState::Closed | TradingState::Halted)
+            }
+            TradingState::Halted => matches!(
+                p_next,
+                TradingState::OpeningAuction | TradingState::Continuous | TradingState::Closed
+            ),
+            TradingState::Closed => {
+                matches!(p_next, TradingState::PostClose | TradingState::PreOpen)
+            }
+            TradingState::PostClose => {
+                matches!(p_next, TradingState::Closed | TradingState::PreOpen)
+            }
+        }
+    }
+
+    //true when leaving self for p_next has to uncross the orders collected so far
+    pub fn uncrosses_into(&self, p_next: TradingState) -> bool {
+        match self {
+            TradingState::PreOpen | TradingState::OpeningAuction | TradingState::Halted => {
+                p_next == TradingState::Continuous
+            }
+            TradingState::ClosingAuction => p_next == TradingState::Closed,
+            _ => false,
+        }
+    }
+
+    //checks whether p_event_type for p_order is allowed in this state
+    pub fn validate(&self, p_event_type: EventType, p_order: &Order) -> Result<(), String> {
+        let allowed = match (self, p_event_type) {
+            (TradingState::Continuous, _) => true,
+            (TradingState::PreOpen, EventType::New) | (TradingState::PreOpen, EventType::Rpl) => {
+                !matches!(p_order.type_, OrderType::Mkt)
+            }
+            (TradingState::PreOpen, EventType::Cxl) => true,
+            (TradingState::OpeningAuction, EventType::New)
+            | (TradingState::OpeningAuction, EventType::Rpl)
+            | (TradingState::ClosingAuction, EventType::New)
+            | (TradingState::ClosingAuction, EventType::Rpl) => {
+                !matches!(p_order.type_, OrderType::Mkt)
+            }
+            (TradingState::OpeningAuction, EventType::Cxl)
+            | (TradingState::ClosingAuction, EventType::Cxl) => true,
+            (TradingState::Halted, EventType::Cxl) | (TradingState::PostClose, EventType::Cxl) => {
+                true
+            }
+            _ => false,
+        };
+
+        if !allowed {
+            return Err(format!(
+                "{:?} of {:?} order {} is not allowed while {} is {:?}",
+                p_event_type, p_order.type_, p_order.id_, p_order.symbol_, self
+            ));
+        }
+        return Ok(());
+    }
+}
+
+//what happens to new orders sent for a halted symbol
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+pub enum HaltOrderPolicy {
+    Reject,
+    Queue,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TradingSchedule {
+    // (time of day in UTC, state entered at that time) kept sorted by time
+    pub(crate) transitions_: Vec<(Duration, TradingState)>,
+}
+
+impl TradingSchedule {
+    pub fn new() -> Self {
+        TradingSchedule {
+            transitions_: Vec::new(),
+        }
+    }
+
+    pub fn add_transition(&mut self, p_time_of_day: Duration, p_state: TradingState) -> &mut Self {
+        self.transitions_.push((p_time_of_day, p_state));
+        self.transitions_
+            .sort_by_key(|(time_of_day, _)| *time_of_day);
+        self
+    }
+
+    //state the schedule expects at p_now, None before the first transition of the day
+    pub fn state_at(&self, p_now: SystemTime) -> Option<TradingState> {
+        let time_of_day = time_of_day(p_now);
+        let mut scheduled_state = None;
+        for (transition_time, state) in &self.transitions_ {
+            if *transition_time > time_of_day {
+                break;
+            }
+            scheduled_state = Some(*state);
+        }
+        return scheduled_state;
+    }
+}
+
+pub fn time_of_day(p_time: SystemTime) -> Duration {
+    let since_epoch = p_time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs() % SECONDS_PER_DAY;
+    return Duration::new(seconds, since_epoch.subsec_nanos());
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct TradingSession {
+    pub(crate) state_: TradingState,
+    pub(crate) schedule_: Option<TradingSchedule>,
+    // new orders received while halted with HaltOrderPolicy::Queue, in arrival order
+    pub(crate) queued_orders_: Vec<Order>,
+}
+
+impl TradingSession {
+    pub(crate) fn new(p_state: TradingState) -> Self {
+        TradingSession {
+            state_: p_state,
+            schedule_: None,
+            queued_orders_: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_order;
+    use crate::OrderSide;
+
+    fn order(p_type: OrderType) -> Order {
+        test_order("1", OrderSide::Buy, 100, 100.0).with_type(p_type)
+    }
+
+    #[test]
+    fn validate_per_state() {
+        let mkt = order(OrderType::Mkt);
+        let limit = order(OrderType::Limit);
+
+        assert!(TradingState::PreOpen
+            .validate(EventType::New, &mkt)
+            .is_err());
+        assert!(TradingState::PreOpen
+            .validate(EventType::New, &limit)
+            .is_ok());
+        assert!(TradingState::Continuous
+            .validate(EventType::New, &mkt)
+            .is_ok());
+        assert!(TradingState::Halted
+            .validate(EventType::New, &limit)
+            .is_err());
+        assert!(TradingState::Halted
+            .validate(EventType::Rpl, &limit)
+            .is_err());
+        assert!(TradingState::Halted
+            .validate(EventType::Cxl, &limit)
+            .is_ok());
+        assert!(TradingState::Closed
+            .validate(EventType::Cxl, &limit)
+            .is_err());
+    }
+
+    #[test]
+    fn schedule_state_at() {
+        let mut schedule = TradingSchedule::new();
+        schedule
+            .add_transition(Duration::from_secs(16 * 3600), TradingState::Closed)
+            .add_transition(Duration::from_secs(9 * 3600), TradingState::PreOpen)
+            .add_transition(
+                Duration::from_secs(9 * 3600 + 1800),
+                TradingState::Continuous,
+            );
+
+        let midnight = UNIX_EPOCH + Duration::from_secs(SECONDS_PER_DAY * 20000);
+        assert_eq!(
+            schedule.state_at(midnight + Duration::from_secs(3600)),
+            None
+        );
+        assert_eq!(
+            schedule.state_at(midnight + Duration::from_secs(9 * 3600)),
+            Some(TradingState::PreOpen)
+        );
+        assert_eq!(
+            schedule.state_at(midnight + Duration::from_secs(12 * 3600)),
+            Some(TradingState::Continuous)
+        );
+        assert_eq!(
+            schedule.state_at(midnight + Duration::from_secs(17 * 3600)),
+            Some(TradingState::Closed)
+        );
+    }
+}
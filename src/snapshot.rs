@@ -0,0 +1,530 @@
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::closing::ClosingBook;
+use crate::depth_feed::DepthHistory;
+use crate::journal::{crc32, put_order, put_string, put_time, Journal, Reader};
+use crate::session::{HaltOrderPolicy, TradingSchedule, TradingSession, TradingState};
+use crate::stp::StpMode;
+use crate::ticker::TradeStatistics;
+use crate::{Level, MatchingEngine, Order, OrderBook, OrderSide};
+
+//Engine snapshots
+// A snapshot file holds everything needed to carry on from the journal sequence
+// number it was taken at: per symbol the resting orders level by level in queue
+// order with the feed and trade sequence counters, the depth feed sequence, the
+// trading session with its schedule and queued orders, the closing book and the
+// trade statistics, plus the engine wide modes and the input sequence, state hash
+// and clock time of deterministic replay. Restoring loads it and replays the
+// journal records after that sequence number. Price band configuration, bar
+// intervals and analytics accumulators are not part of it and have to be set up
+// again after a restore.
+//
+// Layout: magic (8), format version (4), journal sequence number (8), the engine
+// state, then a CRC-32 of everything before it (4). Integers are big endian,
+// strings and lists are prefixed by their length (4).
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"OMESNAP\0";
+const SNAPSHOT_VERSION: u32 = 3;
+
+pub(crate) fn state_to_byte(p_state: TradingState) -> u8 {
+    match p_state {
+        TradingState::PreOpen => 0,
+        TradingState::OpeningAuction => 1,
+        TradingState::Continuous => 2,
+        TradingState::ClosingAuction => 3,
+        TradingState::Halted => 4,
+        TradingState::Closed => 5,
+        TradingState::PostClose => 6,
+    }
+}
+
+pub(crate) fn state_from_byte(p_byte: u8) -> Result<TradingState, String> {
+    match p_byte {
+        0 => Ok(TradingState::PreOpen),
+        1 => Ok(TradingState::OpeningAuction),
+        2 => Ok(TradingState::Continuous),
+        3 => Ok(TradingState::ClosingAuction),
+        4 => Ok(TradingState::Halted),
+        5 => Ok(TradingState::Closed),
+        6 => Ok(TradingState::PostClose),
+        _ => Err(format!("Unknown trading state {}", p_byte)),
+    }
+}
+
+pub(crate) fn put_u32(p_buffer: &mut Vec<u8>, p_value: u32) {
+    p_buffer.extend_from_slice(&p_value.to_be_bytes());
+}
+
+fn put_u64(p_buffer: &mut Vec<u8>, p_value: u64) {
+    p_buffer.extend_from_slice(&p_value.to_be_bytes());
+}
+
+fn put_duration(p_buffer: &mut Vec<u8>, p_duration: Duration) {
+    put_u64(p_buffer, p_duration.as_secs());
+    put_u32(p_buffer, p_duration.subsec_nanos());
+}
+
+pub(crate) fn put_orders<'a>(
+    p_buffer: &mut Vec<u8>,
+    p_orders: impl ExactSizeIterator<Item = &'a Order>,
+) {
+    put_u32(p_buffer, p_orders.len() as u32);
+    for order in p_orders {
+        put_order(p_buffer, order);
+        put_u64(p_buffer, order.seq_);
+    }
+}
+
+//levels best price first, orders in queue order
+fn put_levels(p_buffer: &mut Vec<u8>, p_levels: &BTreeSet<Level>) {
+    let orders: Vec<&Order> = p_levels
+        .iter()
+        .flat_map(|level| level.orders_.iter())
+        .collect();
+    put_orders(p_buffer, orders.into_iter());
+}
+
+impl Reader<'_> {
+    fn duration(&mut self) -> Result<Duration, String> {
+        let seconds = self.u64()?;
+        let nanos = self.u32()?;
+        return Ok(Duration::new(seconds, nanos));
+    }
+
+    pub(crate) fn orders(&mut self) -> Result<Vec<Order>, String> {
+        let count = self.u32()?;
+        let mut orders = Vec::new();
+        for _ in 0..count {
+            let mut order = self.order()?;
+            order.seq_ = self.u64()?;
+            orders.push(order);
+        }
+        return Ok(orders);
+    }
+
+    fn levels(&mut self, p_side: OrderSide) -> Result<BTreeSet<Level>, String> {
+        let mut levels: Vec<Level> = Vec::new();
+        for order in self.orders()? {
+            if order.side_ != p_side {
+                return Err(format!("Order {} restored on the wrong side", order.id_));
+            }
+            match levels.last_mut() {
+                Some(level) if level.price_ == order.price_ => {
+                    level.orders_.insert(order);
+                }
+                _ => {
+                    let mut level = Level::new(order.price_, p_side);
+                    level.orders_.insert(order);
+                    levels.push(level);
+                }
+            }
+        }
+        return Ok(levels.into_iter().collect());
+    }
+
+    fn f32(&mut self) -> Result<f32, String> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+}
+
+impl MatchingEngine {
+    //writes the engine state to p_path, returns the journal sequence number it covers
+    pub fn snapshot(&self, p_path: &Path) -> Result<u64, String> {
+        let journal_seq = self
+            .journal_
+            .as_ref()
+            .map_or(0, |journal| journal.last_seq());
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(SNAPSHOT_MAGIC);
+        put_u32(&mut buffer, SNAPSHOT_VERSION);
+        put_u64(&mut buffer, journal_seq);
+
+        buffer.push(match self.stp_mode_ {
+            StpMode::CancelNewest => 0,
+            StpMode::CancelOldest => 1,
+            StpMode::CancelBoth => 2,
+            StpMode::DecrementAndCancel => 3,
+        });
+        buffer.push(match self.halt_order_policy_ {
+            HaltOrderPolicy::Reject => 0,
+            HaltOrderPolicy::Queue => 1,
+        });
+        buffer.push(self.market_halted_ as u8);
+        put_u64(&mut buffer, self.input_seq_);
+        put_u64(&mut buffer, self.state_hash_);
+        put_time(&mut buffer, self.clock_.now());
+
+        let mut symbols: Vec<&String> = self
+            .order_book_by_symbol_
+            .keys()
+            .chain(self.session_by_symbol_.keys())
+            .chain(self.closing_book_by_symbol_.keys())
+            .chain(self.statistics_by_symbol_.keys())
+            .collect();
+        symbols.sort();
+        symbols.dedup();
+        put_u32(&mut buffer, symbols.len() as u32);
+        for symbol in symbols {
+            put_string(&mut buffer, symbol);
+            self.put_symbol_state(&mut buffer, symbol);
+        }
+
+        let checksum = crc32(&buffer);
+        put_u32(&mut buffer, checksum);
+        let written_path = p_path.with_extension("partial");
+        std::fs::write(&written_path, &buffer)
+            .and_then(|_| std::fs::rename(&written_path, p_path))
+            .map_err(|error| format!("Failed to write snapshot {}: {}", p_path.display(), error))?;
+        println!(
+            "Snapshot at journal sequence {} written to {}",
+            journal_seq,
+            p_path.display()
+        );
+        return Ok(journal_seq);
+    }
+
+    fn put_symbol_state(&self, p_buffer: &mut Vec<u8>, p_symbol: &String) {
+        match self.order_book_by_symbol_.get(p_symbol) {
+            None => p_buffer.push(0),
+            Some(order_book) => {
+                p_buffer.push(1);
+                put_u64(p_buffer, order_book.feed_seq_);
+                put_u64(p_buffer, order_book.trade_seq_);
+                put_levels(p_buffer, &order_book.bids_);
+                put_levels(p_buffer, &order_book.asks_);
+                let depth_seq = self
+                    .depth_history_by_symbol_
+                    .get(p_symbol)
+                    .map_or(0, |history| history.seq());
+                put_u64(p_buffer, depth_seq);
+            }
+        }
+
+        match self.session_by_symbol_.get(p_symbol) {
+            None => p_buffer.push(0),
+            Some(session) => {
+                p_buffer.push(1);
+                p_buffer.push(state_to_byte(session.state_));
+                let transitions = session
+                    .schedule_
+                    .as_ref()
+                    .map_or(&[][..], |schedule| &schedule.transitions_[..]);
+                p_buffer.push(session.schedule_.is_some() as u8);
+                put_u32(p_buffer, transitions.len() as u32);
+                for (time_of_day, state) in transitions {
+                    put_duration(p_buffer, *time_of_day);
+                    p_buffer.push(state_to_byte(*state));
+                }
+                put_orders(p_buffer, session.queued_orders_.iter());
+            }
+        }
+
+        match self.closing_book_by_symbol_.get(p_symbol) {
+            None => p_buffer.push(0),
+            Some(closing_book) => {
+                p_buffer.push(1);
+                put_orders(p_buffer, closing_book.orders_.iter());
+                match closing_book.cutoff_ {
+                    None => p_buffer.push(0),
+                    Some(cutoff) => {
+                        p_buffer.push(1);
+                        put_duration(p_buffer, cutoff);
+                    }
+                }
+            }
+        }
+
+        match self.statistics_by_symbol_.get(p_symbol) {
+            None => p_buffer.push(0),
+            Some(statistics) => {
+                p_buffer.push(1);
+                put_u32(p_buffer, statistics.last_price_.to_bits());
+                put_u32(p_buffer, statistics.last_qty_ as u32);
+                put_u32(p_buffer, statistics.open_.to_bits());
+                put_u32(p_buffer, statistics.high_.to_bits());
+                put_u32(p_buffer, statistics.low_.to_bits());
+                put_u64(p_buffer, statistics.volume_ as u64);
+                put_u64(p_buffer, statistics.turnover_.to_bits());
+                put_u64(p_buffer, statistics.trade_count_);
+            }
+        }
+    }
+
+    //engine loaded from the snapshot at p_snapshot_path and brought up to date
+    //with the journal at p_journal_path, which is attached to it
+    pub fn restore(
+        p_snapshot_path: &Path,
+        p_journal_path: &Path,
+    ) -> Result<MatchingEngine, String> {
+        let (mut engine, snapshot_seq) = MatchingEngine::load_snapshot(p_snapshot_path)?;
+        let records = Journal::read(p_journal_path)?;
+        if let Some(first_record) = records.first() {
+            if first_record.seq() > snapshot_seq + 1 {
+                return Err(format!(
+                    "Journal starts at {} but the snapshot stops at {}",
+                    first_record.seq(),
+                    snapshot_seq
+                ));
+            }
+        }
+        let tail: Vec<_> = records
+            .into_iter()
+            .filter(|record| record.seq() > snapshot_seq)
+            .collect();
+        println!(
+            "Replaying {} journal records after snapshot sequence {}",
+            tail.len(),
+            snapshot_seq
+        );
+        engine.replay(&tail);
+        engine.attach_journal(Journal::open(p_journal_path)?);
+        return Ok(engine);
+    }
+
+    //engine state of the snapshot at p_path and the journal sequence number it covers
+    pub fn load_snapshot(p_path: &Path) -> Result<(MatchingEngine, u64), String> {
+        let bytes = std::fs::read(p_path)
+            .map_err(|error| format!("Failed to read snapshot {}: {}", p_path.display(), error))?;
+        if bytes.len() < SNAPSHOT_MAGIC.len() + 4
+            || &bytes[..SNAPSHOT_MAGIC.len()] != SNAPSHOT_MAGIC
+        {
+            return Err(format!("{} is not a snapshot", p_path.display()));
+        }
+        let (content, checksum) = bytes.split_at(bytes.len() - 4);
+        if crc32(content) != u32::from_be_bytes(checksum.try_into().unwrap()) {
+            return Err(format!("Snapshot {} is corrupt", p_path.display()));
+        }
+
+        let mut reader = Reader::new(&content[SNAPSHOT_MAGIC.len()..]);
+        let version = reader.u32()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(format!(
+                "Snapshot {} has version {}, only version {} is supported",
+                p_path.display(),
+                version,
+                SNAPSHOT_VERSION
+            ));
+        }
+        let journal_seq = reader.u64()?;
+
+        let mut engine = MatchingEngine::new();
+        engine.stp_mode_ = match reader.u8()? {
+            0 => StpMode::CancelNewest,
+            1 => StpMode::CancelOldest,
+            2 => StpMode::CancelBoth,
+            3 => StpMode::DecrementAndCancel,
+            stp_mode => return Err(format!("Unknown STP mode {}", stp_mode)),
+        };
+        engine.halt_order_policy_ = match reader.u8()? {
+            0 => HaltOrderPolicy::Reject,
+            1 => HaltOrderPolicy::Queue,
+            policy => return Err(format!("Unknown halt order policy {}", policy)),
+        };
+        engine.market_halted_ = reader.u8()? != 0;
+        engine.input_seq_ = reader.u64()?;
+        engine.state_hash_ = reader.u64()?;
+        engine.advance_time(reader.time()?);
+
+        let symbol_count = reader.u32()?;
+        for _ in 0..symbol_count {
+            let symbol = reader.string()?;
+            engine.read_symbol_state(&mut reader, &symbol)?;
+        }
+        if !reader.is_empty() {
+            return Err(format!("Snapshot {} has trailing data", p_path.display()));
+        }
+        engine.track_live_orders();
+        return Ok((engine, journal_seq));
+    }
+
+    fn read_symbol_state(
+        &mut self,
+        p_reader: &mut Reader,
+        p_symbol: &String,
+    ) -> Result<(), String> {
+        if p_reader.u8()? != 0 {
+            let mut order_book = OrderBook::new(p_symbol);
+            order_book.feed_seq_ = p_reader.u64()?;
+            order_book.trade_seq_ = p_reader.u64()?;
+            order_book.bids_ = p_reader.levels(OrderSide::Buy)?;
+            order_book.asks_ = p_reader.levels(OrderSide::Sell)?;
+            order_book.rebuild_order_index();
+            let depth_seq = p_reader.u64()?;
+            if depth_seq > 0 {
+                self.depth_history_by_symbol_.insert(
+                    p_symbol.to_owned(),
+                    DepthHistory::restored(order_book.depth(usize::MAX), depth_seq),
+                );
+            }
+            self.order_book_by_symbol_
+                .insert(p_symbol.to_owned(), order_book);
+        }
+
+        if p_reader.u8()? != 0 {
+            let mut session = TradingSession::new(state_from_byte(p_reader.u8()?)?);
+            let has_schedule = p_reader.u8()? != 0;
+            let transition_count = p_reader.u32()?;
+            let mut schedule = TradingSchedule::new();
+            for _ in 0..transition_count {
+                let time_of_day = p_reader.duration()?;
+                schedule.add_transition(time_of_day, state_from_byte(p_reader.u8()?)?);
+            }
+            if has_schedule {
+                session.schedule_ = Some(schedule);
+            }
+            session.queued_orders_ = p_reader.orders()?;
+            self.session_by_symbol_.insert(p_symbol.to_owned(), session);
+        }
+
+        if p_reader.u8()? != 0 {
+            let orders = p_reader.orders()?;
+            let cutoff = match p_reader.u8()? {
+                0 => None,
+                _ => Some(p_reader.duration()?),
+            };
+            self.closing_book_by_symbol_.insert(
+                p_symbol.to_owned(),
+                ClosingBook {
+                    orders_: orders,
+                    cutoff_: cutoff,
+                },
+            );
+        }
+
+        if p_reader.u8()? != 0 {
+            let mut statistics = TradeStatistics::new(p_symbol);
+            statistics.last_price_ = p_reader.f32()?;
+            statistics.last_qty_ = p_reader.u32()? as i32;
+            statistics.open_ = p_reader.f32()?;
+            statistics.high_ = p_reader.f32()?;
+            statistics.low_ = p_reader.f32()?;
+            statistics.volume_ = p_reader.u64()? as i64;
+            statistics.turnover_ = f64::from_bits(p_reader.u64()?);
+            statistics.trade_count_ = p_reader.u64()?;
+            self.statistics_by_symbol_
+                .insert(p_symbol.to_owned(), statistics);
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::test_order as order;
+    use crate::{process_event, EventType};
+    use std::path::PathBuf;
+    use std::time::SystemTime;
+
+    fn temp_path(p_name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("snapshot_{}_{}", std::process::id(), p_name));
+        let _ = std::fs::remove_file(&path);
+        return path;
+    }
+
+    fn run(p_engine: &mut MatchingEngine, p_events: Vec<(EventType, Order)>) {
+        for (event_type, mut order) in p_events {
+            let _ = process_event(event_type, &mut order, p_engine);
+        }
+    }
+
+    #[test]
+    fn restore_replays_journal_tail() {
+        let symbol = String::from("REL");
+        let journal_path = temp_path("tail.journal");
+        let snapshot_path = temp_path("tail.snapshot");
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000));
+        let mut engine = MatchingEngine::new();
+        engine.set_clock(clock.clone());
+        engine.set_stp_mode(StpMode::CancelBoth);
+        engine.attach_journal(Journal::open(&journal_path).unwrap());
+        run(
+            &mut engine,
+            vec![
+                (EventType::New, order("1", OrderSide::Buy, 100, 99.0)),
+                (EventType::New, order("2", OrderSide::Buy, 100, 99.0)),
+                (EventType::New, order("3", OrderSide::Buy, 100, 98.0)),
+                (EventType::New, order("4", OrderSide::Sell, 150, 99.0)),
+            ],
+        );
+        assert_eq!(engine.snapshot(&snapshot_path).unwrap(), 4);
+        engine.journal_.as_mut().unwrap().compact(4).unwrap();
+
+        run(
+            &mut engine,
+            vec![
+                (EventType::New, order("5", OrderSide::Sell, 30, 99.0)),
+                (EventType::Cxl, order("3", OrderSide::Buy, 100, 98.0)),
+                (EventType::New, order("6", OrderSide::Sell, 10, 101.0)),
+            ],
+        );
+
+        let mut restored = MatchingEngine::restore(&snapshot_path, &journal_path).unwrap();
+        assert_eq!(
+            format!("{:?}", restored.order_book_by_symbol_[&symbol]),
+            format!("{:?}", engine.order_book_by_symbol_[&symbol])
+        );
+        assert_eq!(
+            restored.trade_statistics(&symbol),
+            engine.trade_statistics(&symbol)
+        );
+        assert_eq!(
+            restored.depth_snapshot(&symbol),
+            engine.depth_snapshot(&symbol)
+        );
+        assert_eq!(restored.stp_mode_, StpMode::CancelBoth);
+        assert_eq!(restored.journal().unwrap().last_seq(), 7);
+
+        //both engines carry on with the same sequence numbers
+        restored.set_clock(clock.clone());
+        let mut buy = order("7", OrderSide::Buy, 10, 101.0);
+        let expected = process_event(EventType::New, &mut buy.clone(), &mut engine).unwrap();
+        let result = process_event(EventType::New, &mut buy, &mut restored).unwrap();
+        assert_eq!(result.unwrap().fills_, expected.unwrap().fills_);
+        assert_eq!(restored.drain_events().last(), engine.drain_events().last());
+        assert_eq!(restored.state_hash(), engine.state_hash());
+
+        let _ = std::fs::remove_file(&journal_path);
+        let _ = std::fs::remove_file(&snapshot_path);
+    }
+
+    #[test]
+    fn sessions_and_damaged_files() {
+        let symbol = String::from("REL");
+        let snapshot_path = temp_path("session.snapshot");
+        let mut engine = MatchingEngine::new();
+        engine.set_halt_order_policy(HaltOrderPolicy::Queue);
+        let mut schedule = TradingSchedule::new();
+        schedule.add_transition(Duration::from_secs(9 * 3600), TradingState::Continuous);
+        engine.set_schedule(&symbol, schedule);
+        engine.halt(&symbol, "news pending").unwrap();
+        run(
+            &mut engine,
+            vec![(EventType::New, order("1", OrderSide::Buy, 100, 99.0))],
+        );
+        engine.snapshot(&snapshot_path).unwrap();
+
+        let (mut restored, journal_seq) = MatchingEngine::load_snapshot(&snapshot_path).unwrap();
+        assert_eq!(journal_seq, 0);
+        assert_eq!(restored.trading_state(&symbol), TradingState::Halted);
+        assert_eq!(
+            restored.session_by_symbol_[&symbol]
+                .schedule_
+                .as_ref()
+                .unwrap()
+                .transitions_,
+            vec![(Duration::from_secs(9 * 3600), TradingState::Continuous)]
+        );
+        restored.resume(&symbol, TradingState::Continuous).unwrap();
+        assert_eq!(restored.full_depth(&symbol).unwrap().bids().len(), 1);
+
+        let mut bytes = std::fs::read(&snapshot_path).unwrap();
+        bytes[20] ^= 0xFF;
+        std::fs::write(&snapshot_path, &bytes).unwrap();
+        assert!(MatchingEngine::load_snapshot(&snapshot_path).is_err());
+        let _ = std::fs::remove_file(&snapshot_path);
+    }
+}
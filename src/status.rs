@@ -0,0 +1,571 @@
+use std::collections::BTreeMap;
+
+use crate::events::EngineEvent;
+use crate::{EventType, Level, MatchingEngine, MatchingResult, Order, OrderBook, OrderSide};
+
+//Order status
+// Each OrderBook keeps an index of its resting orders by id, the side and price
+// of the level they rest at, so an order is found without walking the book. On
+// top of it the engine follows every order it accepts: its original qty, what was
+// filled and at which average price, and how it ended when the book alone can
+// not tell (rejected, expired or cancelled on request). The leaves qty is the one
+// of the order where it lives, in the book, the closing book or a halt queue.
+// Orders restored from a snapshot or a GTC file start with nothing filled.
+// An ended order stays known until the end of the day after the one it ended in,
+// the purge of the DAY orders forgets the ones which ended before it.
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OrderState {
+    New,
+    PartiallyFilled,
+    Filled,
+    Cancelled,
+    Expired,
+    Rejected,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OrderStatus {
+    #[cfg_attr(feature = "serde", serde(rename = "order_id"))]
+    order_id_: String,
+    #[cfg_attr(feature = "serde", serde(rename = "symbol"))]
+    symbol_: String,
+    #[cfg_attr(feature = "serde", serde(rename = "client_id"))]
+    client_id_: String,
+    #[cfg_attr(feature = "serde", serde(rename = "side"))]
+    side_: OrderSide,
+    #[cfg_attr(feature = "serde", serde(rename = "price"))]
+    price_: f32,
+    // qty filled plus the qty of the last new or replace request
+    #[cfg_attr(feature = "serde", serde(rename = "original_qty"))]
+    original_qty_: i32,
+    #[cfg_attr(feature = "serde", serde(rename = "leaves_qty"))]
+    leaves_qty_: i32,
+    #[cfg_attr(feature = "serde", serde(rename = "cum_qty"))]
+    cum_qty_: i32,
+    // 0 while nothing is filled
+    #[cfg_attr(feature = "serde", serde(rename = "avg_price"))]
+    avg_price_: f32,
+    #[cfg_attr(feature = "serde", serde(rename = "state"))]
+    state_: OrderState,
+}
+
+impl OrderStatus {
+    pub fn order_id(&self) -> &String {
+        &self.order_id_
+    }
+
+    pub fn symbol(&self) -> &String {
+        &self.symbol_
+    }
+
+    pub fn client_id(&self) -> &String {
+        &self.client_id_
+    }
+
+    pub fn side(&self) -> OrderSide {
+        self.side_
+    }
+
+    pub fn price(&self) -> f32 {
+        self.price_
+    }
+
+    pub fn original_qty(&self) -> i32 {
+        self.original_qty_
+    }
+
+    pub fn leaves_qty(&self) -> i32 {
+        self.leaves_qty_
+    }
+
+    pub fn cum_qty(&self) -> i32 {
+        self.cum_qty_
+    }
+
+    pub fn avg_price(&self) -> f32 {
+        self.avg_price_
+    }
+
+    pub fn state(&self) -> OrderState {
+        self.state_
+    }
+
+    pub fn is_open(&self) -> bool {
+        matches!(self.state_, OrderState::New | OrderState::PartiallyFilled)
+    }
+}
+
+//what the engine follows of an order beyond what rests in the book
+#[derive(Clone, Debug)]
+pub(crate) struct OrderProgress {
+    symbol_: String,
+    client_id_: String,
+    side_: OrderSide,
+    price_: f32,
+    original_qty_: i32,
+    cum_qty_: i32,
+    notional_: f64,
+    // set when the order ended otherwise than filled or cancelled by the engine
+    ended_: Option<OrderState>,
+}
+
+impl OrderProgress {
+    fn new(p_order: &Order) -> Self {
+        OrderProgress {
+            symbol_: p_order.symbol_.to_owned(),
+            client_id_: p_order.client_id_.to_owned(),
+            side_: p_order.side_,
+            price_: p_order.price_,
+            original_qty_: p_order.qty_,
+            cum_qty_: 0,
+            notional_: 0.0,
+            ended_: None,
+        }
+    }
+
+    fn fill(&mut self, p_price: f32, p_qty: i32) {
+        self.cum_qty_ += p_qty;
+        self.notional_ += p_price as f64 * p_qty as f64;
+    }
+
+    //p_live_order is the order where it rests, if it does
+    fn status(&self, p_order_id: &str, p_live_order: Option<&Order>) -> OrderStatus {
+        let state = match (self.ended_, p_live_order) {
+            (Some(ended), _) => ended,
+            (None, Some(_)) if self.cum_qty_ > 0 => OrderState::PartiallyFilled,
+            (None, Some(_)) => OrderState::New,
+            (None, None) if self.cum_qty_ >= self.original_qty_ => OrderState::Filled,
+            (None, None) => OrderState::Cancelled,
+        };
+        let avg_price = if self.cum_qty_ > 0 {
+            (self.notional_ / self.cum_qty_ as f64) as f32
+        } else {
+            0.0
+        };
+        OrderStatus {
+            order_id_: p_order_id.to_owned(),
+            symbol_: self.symbol_.to_owned(),
+            client_id_: self.client_id_.to_owned(),
+            side_: self.side_,
+            price_: self.price_,
+            original_qty_: self.original_qty_,
+            leaves_qty_: p_live_order.map(|order| order.qty_).unwrap_or_default(),
+            cum_qty_: self.cum_qty_,
+            avg_price_: avg_price,
+            state_: state,
+        }
+    }
+}
+
+impl OrderBook {
+    pub(crate) fn index_order(&mut self, p_order: &Order) {
+        self.order_index_
+            .insert(p_order.id_.to_owned(), (p_order.side_, p_order.price_));
+    }
+
+    pub(crate) fn rebuild_order_index(&mut self) {
+        self.order_index_ = self
+            .bids_
+            .iter()
+            .chain(self.asks_.iter())
+            .flat_map(|level| level.orders_.iter())
+            .map(|order| (order.id_.to_owned(), (order.side_, order.price_)))
+            .collect();
+    }
+
+    //the resting order p_order_id and its level
+    pub(crate) fn find_order(&self, p_order_id: &str) -> Option<(&Level, &Order)> {
+        let (side, price) = self.order_index_.get(p_order_id)?;
+        let level = match side {
+            OrderSide::Buy => self.bids_.get(&Level::new(*price, OrderSide::Buy))?,
+            OrderSide::Sell => self.asks_.get(&Level::new(*price, OrderSide::Sell))?,
+        };
+        let order = level.orders_.iter().find(|order| order.id_ == p_order_id)?;
+        return Some((level, order));
+    }
+
+    //forgets the orders of p_result no longer resting, executed or cancelled by
+    //self-trade prevention
+    pub(crate) fn unindex_executed(&mut self, p_result: &MatchingResult) {
+        let self_traded_order_ids =
+            p_result
+                .self_trade_events_
+                .iter()
+                .filter_map(|event| match event {
+                    EngineEvent::SelfTradePrevented {
+                        resting_order_id_, ..
+                    } => Some(resting_order_id_),
+                    _ => None,
+                });
+        let order_ids: Vec<String> = p_result
+            .matched_order_ids_
+            .iter()
+            .chain(self_traded_order_ids)
+            .cloned()
+            .collect();
+        for order_id in order_ids {
+            if self.find_order(&order_id).is_none() {
+                self.order_index_.remove(&order_id);
+            }
+        }
+    }
+}
+
+impl MatchingEngine {
+    pub fn order_status(&self, p_order_id: &str) -> Option<OrderStatus> {
+        let progress = self.order_progress_.get(p_order_id)?;
+        let live_order = self.live_order(&progress.symbol_, p_order_id);
+        return Some(progress.status(p_order_id, live_order));
+    }
+
+    //orders of p_symbol in the book by id, then the on-close and the queued ones
+    pub fn open_orders(&self, p_symbol: &String) -> Vec<OrderStatus> {
+        let mut order_ids: Vec<&String> = Vec::new();
+        if let Some(order_book) = self.order_book_by_symbol_.get(p_symbol) {
+            order_ids.extend(order_book.order_index_.keys());
+        }
+        if let Some(closing_book) = self.closing_book_by_symbol_.get(p_symbol) {
+            order_ids.extend(closing_book.orders_.iter().map(|order| &order.id_));
+        }
+        if let Some(session) = self.session_by_symbol_.get(p_symbol) {
+            order_ids.extend(session.queued_orders_.iter().map(|order| &order.id_));
+        }
+        order_ids
+            .into_iter()
+            .filter_map(|order_id| self.order_status(order_id))
+            .filter(|status| status.is_open() && &status.symbol_ == p_symbol)
+            .collect()
+    }
+
+    //open orders of p_client_id, symbol by symbol
+    pub fn open_orders_by_client(&self, p_client_id: &str) -> Vec<OrderStatus> {
+        self.known_symbols()
+            .iter()
+            .flat_map(|symbol| self.open_orders(symbol))
+            .filter(|status| status.client_id_ == p_client_id)
+            .collect()
+    }
+
+    //the order p_order_id where it waits in p_symbol, book, closing book or halt queue
+    fn live_order(&self, p_symbol: &String, p_order_id: &str) -> Option<&Order> {
+        if let Some((_, order)) = self
+            .order_book_by_symbol_
+            .get(p_symbol)
+            .and_then(|order_book| order_book.find_order(p_order_id))
+        {
+            return Some(order);
+        }
+        let closing_orders = self
+            .closing_book_by_symbol_
+            .get(p_symbol)
+            .map(|closing_book| closing_book.orders_.iter());
+        let queued_orders = self
+            .session_by_symbol_
+            .get(p_symbol)
+            .map(|session| session.queued_orders_.iter());
+        return closing_orders
+            .into_iter()
+            .flatten()
+            .chain(queued_orders.into_iter().flatten())
+            .find(|order| order.id_ == p_order_id);
+    }
+
+    //true when p_order_id waits in p_symbol or in the symbol it is followed for
+    pub(crate) fn is_live_order(&self, p_symbol: &String, p_order_id: &str) -> bool {
+        let followed_symbol = self
+            .order_progress_
+            .get(p_order_id)
+            .map(|progress| &progress.symbol_);
+        return std::iter::once(p_symbol)
+            .chain(followed_symbol)
+            .any(|symbol| self.live_order(symbol, p_order_id).is_some());
+    }
+
+    //stops following the orders which no longer wait anywhere
+    pub(crate) fn forget_ended_orders(&mut self) {
+        let ended_order_ids: Vec<String> = self
+            .order_progress_
+            .iter()
+            .filter(|(order_id, progress)| self.live_order(&progress.symbol_, order_id).is_none())
+            .map(|(order_id, _)| order_id.to_owned())
+            .collect();
+        for order_id in ended_order_ids {
+            self.order_progress_.remove(&order_id);
+        }
+    }
+
+    //follows p_received, an input as received, through p_result
+    pub(crate) fn track_order(
+        &mut self,
+        p_event_type: EventType,
+        p_received: &Order,
+        p_result: &Result<Option<MatchingResult>, String>,
+    ) {
+        let match_result = match p_result {
+            Err(_) => {
+                if let EventType::New = p_event_type {
+                    let is_open = self
+                        .order_status(&p_received.id_)
+                        .is_some_and(|status| status.is_open());
+                    if !is_open {
+                        let mut progress = OrderProgress::new(p_received);
+                        progress.ended_ = Some(OrderState::Rejected);
+                        self.order_progress_
+                            .insert(p_received.id_.to_owned(), progress);
+                    }
+                }
+                return;
+            }
+            Ok(match_result) => match_result,
+        };
+
+        match p_event_type {
+            EventType::New => {
+                self.order_progress_
+                    .insert(p_received.id_.to_owned(), OrderProgress::new(p_received));
+            }
+            EventType::Rpl => {
+                if let Some(progress) = self.order_progress_.get_mut(&p_received.id_) {
+                    progress.price_ = p_received.price_;
+                    progress.original_qty_ = progress.cum_qty_ + p_received.qty_;
+                }
+            }
+            EventType::Cxl => {
+                if let Some(progress) = self.order_progress_.get_mut(&p_received.id_) {
+                    progress.ended_ = Some(OrderState::Cancelled);
+                }
+            }
+        }
+        self.track_fills(Some(&p_received.id_), match_result);
+    }
+
+    //adds the fills of p_result to the resting orders and to p_taker_order_id, the
+    //incoming order they traded against if any
+    pub(crate) fn track_fills(
+        &mut self,
+        p_taker_order_id: Option<&String>,
+        p_result: &Option<MatchingResult>,
+    ) {
+        let match_result = match p_result {
+            None => return,
+            Some(match_result) => match_result,
+        };
+        for fill in &match_result.fills_ {
+            let order_ids = std::iter::once(fill.maker_order_id()).chain(p_taker_order_id);
+            for order_id in order_ids {
+                if let Some(progress) = self.order_progress_.get_mut(order_id) {
+                    progress.fill(fill.price(), fill.qty());
+                }
+            }
+        }
+    }
+
+    pub(crate) fn end_order(&mut self, p_order_id: &str, p_state: OrderState) {
+        if let Some(progress) = self.order_progress_.get_mut(p_order_id) {
+            progress.ended_ = Some(p_state);
+        }
+    }
+
+    //starts following the orders waiting in the books, the closing books and the
+    //halt queues which are not followed yet
+    pub(crate) fn track_live_orders(&mut self) {
+        let order_books = self
+            .order_book_by_symbol_
+            .values()
+            .flat_map(|order_book| order_book.bids_.iter().chain(order_book.asks_.iter()))
+            .flat_map(|level| level.orders_.iter());
+        let closing_books = self
+            .closing_book_by_symbol_
+            .values()
+            .flat_map(|closing_book| closing_book.orders_.iter());
+        let halt_queues = self
+            .session_by_symbol_
+            .values()
+            .flat_map(|session| session.queued_orders_.iter());
+        let mut untracked: BTreeMap<String, OrderProgress> = BTreeMap::new();
+        for order in order_books.chain(closing_books).chain(halt_queues) {
+            if !self.order_progress_.contains_key(&order.id_) {
+                untracked.insert(order.id_.to_owned(), OrderProgress::new(order));
+            }
+        }
+        self.order_progress_.append(&mut untracked);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::process_event;
+    use crate::session::TradingState;
+    use crate::test_order;
+    use crate::{HaltOrderPolicy, OrderType, TimeInForce};
+
+    fn order(p_id: &str, p_client_id: &str, p_side: OrderSide, p_qty: i32, p_price: f32) -> Order {
+        test_order(p_id, p_side, p_qty, p_price).with_client(p_client_id)
+    }
+
+    fn open_order_ids(p_statuses: Vec<OrderStatus>) -> Vec<String> {
+        p_statuses
+            .into_iter()
+            .map(|status| status.order_id_)
+            .collect()
+    }
+
+    #[test]
+    fn order_status_follows_fills_replaces_and_cancels() {
+        let symbol = String::from("REL");
+        let mut engine = MatchingEngine::new();
+        let inputs = [
+            (EventType::New, order("1", "C1", OrderSide::Buy, 100, 100.0)),
+            (EventType::New, order("2", "C2", OrderSide::Buy, 50, 99.0)),
+            (EventType::New, order("3", "C3", OrderSide::Sell, 30, 99.0)),
+            (
+                EventType::New,
+                order("8", "C3", OrderSide::Sell, 120, 100.0),
+            ),
+            (EventType::Rpl, order("2", "C2", OrderSide::Buy, 40, 98.0)),
+            (EventType::New, order("4", "C1", OrderSide::Sell, 10, 120.0)),
+            (EventType::Cxl, order("4", "C1", OrderSide::Sell, 10, 120.0)),
+            (EventType::New, order("5", "C2", OrderSide::Sell, 10, 150.0)),
+        ];
+        for (event_type, mut order) in inputs {
+            process_event(event_type, &mut order, &mut engine).unwrap();
+        }
+        engine.halt(&symbol, "news").unwrap();
+        let mut rejected = order("6", "C1", OrderSide::Buy, 10, 100.0);
+        assert!(process_event(EventType::New, &mut rejected, &mut engine).is_err());
+        engine.resume(&symbol, TradingState::Continuous).unwrap();
+
+        let filled = engine.order_status("1").unwrap();
+        assert_eq!(filled.state(), OrderState::Filled);
+        assert_eq!((filled.leaves_qty(), filled.cum_qty()), (0, 100));
+        let taker = engine.order_status("8").unwrap();
+        assert_eq!(taker.state(), OrderState::PartiallyFilled);
+        assert_eq!((taker.leaves_qty(), taker.cum_qty()), (20, 100));
+        assert_eq!(taker.avg_price(), 100.0);
+
+        let replaced = engine.order_status("2").unwrap();
+        assert_eq!(replaced.state(), OrderState::PartiallyFilled);
+        assert_eq!(replaced.side(), OrderSide::Buy);
+        assert_eq!(replaced.price(), 98.0);
+        assert_eq!(replaced.original_qty(), 70);
+        assert_eq!((replaced.leaves_qty(), replaced.cum_qty()), (40, 30));
+        assert_eq!(replaced.avg_price(), 99.0);
+
+        assert_eq!(
+            engine.order_status("4").unwrap().state(),
+            OrderState::Cancelled
+        );
+        assert_eq!(engine.order_status("5").unwrap().state(), OrderState::New);
+        assert_eq!(
+            engine.order_status("6").unwrap().state(),
+            OrderState::Rejected
+        );
+        assert!(engine.order_status("7").is_none());
+
+        assert_eq!(
+            open_order_ids(engine.open_orders(&symbol)),
+            vec!["2", "5", "8"]
+        );
+        assert_eq!(
+            open_order_ids(engine.open_orders_by_client("C2")),
+            vec!["2", "5"]
+        );
+        assert!(engine.open_orders_by_client("C1").is_empty());
+        assert_eq!(
+            open_order_ids(engine.open_orders_by_client("C3")),
+            vec!["8"]
+        );
+        let order_index: Vec<&String> = engine.order_book_by_symbol_[&symbol]
+            .order_index_
+            .keys()
+            .collect();
+        assert_eq!(order_index, vec!["2", "5", "8"]);
+
+        engine.purge_day_orders().unwrap();
+        assert_eq!(
+            engine.order_status("2").unwrap().state(),
+            OrderState::Expired
+        );
+        assert!(engine.open_orders(&symbol).is_empty());
+        assert!(engine.order_book_by_symbol_[&symbol]
+            .order_index_
+            .is_empty());
+    }
+
+    #[test]
+    fn new_order_with_a_live_id_rejected() {
+        let symbol = String::from("REL");
+        let mut engine = MatchingEngine::new();
+        engine.set_halt_order_policy(HaltOrderPolicy::Queue);
+        let mut resting = order("1", "C1", OrderSide::Buy, 100, 99.0);
+        process_event(EventType::New, &mut resting, &mut engine).unwrap();
+        let mut on_close = order("2", "C1", OrderSide::Buy, 100, 0.0).with_type(OrderType::Moc);
+        process_event(EventType::New, &mut on_close, &mut engine).unwrap();
+
+        //whether the live order rests in the book or in the closing book
+        for order_id in ["1", "2"] {
+            let mut duplicate = order(order_id, "C2", OrderSide::Sell, 50, 101.0);
+            assert!(process_event(EventType::New, &mut duplicate, &mut engine).is_err());
+        }
+        let resting_status = engine.order_status("1").unwrap();
+        assert_eq!(resting_status.state(), OrderState::New);
+        assert_eq!(resting_status.client_id(), "C1");
+        assert_eq!(resting_status.leaves_qty(), 100);
+        assert_eq!(engine.on_close_order_count(&symbol), 1);
+
+        //or in a halt queue
+        engine.halt(&symbol, "news").unwrap();
+        let mut queued = order("3", "C1", OrderSide::Buy, 10, 98.0);
+        process_event(EventType::New, &mut queued, &mut engine).unwrap();
+        let mut duplicate = order("3", "C2", OrderSide::Buy, 20, 97.0);
+        assert!(process_event(EventType::New, &mut duplicate, &mut engine).is_err());
+        engine.resume(&symbol, TradingState::Continuous).unwrap();
+        assert_eq!(engine.order_status("3").unwrap().leaves_qty(), 10);
+
+        //the earlier order can still be cancelled, then its id used again
+        let mut cancel = order("1", "C1", OrderSide::Buy, 100, 99.0);
+        process_event(EventType::Cxl, &mut cancel, &mut engine).unwrap();
+        assert!(engine.order_book_by_symbol_[&symbol]
+            .find_order("1")
+            .is_none());
+        let mut reused = order("1", "C2", OrderSide::Buy, 30, 96.0);
+        assert!(process_event(EventType::New, &mut reused, &mut engine).is_ok());
+        assert_eq!(engine.order_status("1").unwrap().client_id(), "C2");
+    }
+
+    #[test]
+    fn ended_orders_forgotten_at_end_of_day() {
+        let mut engine = MatchingEngine::new();
+        let inputs = [
+            order("1", "C1", OrderSide::Buy, 100, 100.0),
+            order("2", "C2", OrderSide::Sell, 100, 100.0),
+            order("3", "C1", OrderSide::Buy, 10, 90.0).with_time_in_force(TimeInForce::Gtc),
+            order("4", "C1", OrderSide::Buy, 10, 80.0),
+        ];
+        for mut order in inputs {
+            process_event(EventType::New, &mut order, &mut engine).unwrap();
+        }
+        assert_eq!(
+            engine.order_status("1").unwrap().state(),
+            OrderState::Filled
+        );
+
+        engine.purge_day_orders().unwrap();
+        assert!(engine.order_status("1").is_none());
+        assert!(engine.order_status("2").is_none());
+        assert_eq!(engine.order_status("3").unwrap().state(), OrderState::New);
+        assert_eq!(
+            engine.order_status("4").unwrap().state(),
+            OrderState::Expired
+        );
+
+        engine.purge_day_orders().unwrap();
+        assert!(engine.order_status("4").is_none());
+        assert_eq!(engine.order_status("3").unwrap().state(), OrderState::New);
+        assert_eq!(engine.order_progress_.len(), 1);
+    }
+}
@@ -0,0 +1,173 @@
+use crate::events::EngineEvent;
+use crate::{Level, MatchingResult, Order};
+
+//Self-trade prevention
+// Two orders belong to the same owner when they share an STP group, or when
+// neither has a group and they share a client id. Orders without either never
+// self trade. When an incoming order meets a resting order of the same owner the
+// configured StpMode decides which side gives up its quantity.
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum StpMode {
+    CancelNewest,
+    CancelOldest,
+    CancelBoth,
+    DecrementAndCancel,
+}
+
+impl Order {
+    fn stp_key(&self) -> Option<&String> {
+        match &self.stp_group_ {
+            Some(stp_group) => Some(stp_group),
+            None if self.client_id_.is_empty() => None,
+            None => Some(&self.client_id_),
+        }
+    }
+
+    pub fn is_self_trade(&self, p_other: &Order) -> bool {
+        match (self.stp_key(), p_other.stp_key()) {
+            (Some(key), Some(other_key)) => key == other_key,
+            _ => false,
+        }
+    }
+}
+
+impl Level {
+    //applies p_stp_mode between p_order and the first order of the level,
+    //returns the remaining qty of p_order
+    pub(crate) fn prevent_self_trade(
+        &mut self,
+        p_order: &Order,
+        p_remaining_qty: i32,
+        p_stp_mode: StpMode,
+        p_result: &mut MatchingResult,
+    ) -> i32 {
+        let mut resting_order = match self.orders_.pop_first() {
+            None => return p_remaining_qty,
+            Some(resting_order) => resting_order,
+        };
+
+        let (aggressor_cancelled_qty, resting_cancelled_qty) = match p_stp_mode {
+            StpMode::CancelNewest => (p_remaining_qty, 0),
+            StpMode::CancelOldest => (0, resting_order.qty_),
+            StpMode::CancelBoth => (p_remaining_qty, resting_order.qty_),
+            StpMode::DecrementAndCancel => {
+                let decrement = p_remaining_qty.min(resting_order.qty_);
+                (decrement, decrement)
+            }
+        };
+        println!(
+            "Self trade between {} and {} prevented with {:?}",
+            p_order.id_, resting_order.id_, p_stp_mode
+        );
+
+        resting_order.qty_ -= resting_cancelled_qty;
+        if resting_order.qty_ > 0 {
+            self.orders_.insert(resting_order.to_owned());
+        }
+        p_result.aggressor_cancelled_qty_ += aggressor_cancelled_qty;
+        p_result
+            .self_trade_events_
+            .push(EngineEvent::SelfTradePrevented {
+                symbol_: p_order.symbol_.to_owned(),
+                aggressor_order_id_: p_order.id_.to_owned(),
+                resting_order_id_: resting_order.id_,
+                mode_: p_stp_mode,
+                aggressor_cancelled_qty_: aggressor_cancelled_qty,
+                resting_cancelled_qty_: resting_cancelled_qty,
+            });
+        return p_remaining_qty - aggressor_cancelled_qty;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_order;
+    use crate::{process_event, EventType, MatchingEngine, OrderSide};
+
+    fn order(p_id: &str, p_client_id: &str, p_side: OrderSide, p_qty: i32) -> Order {
+        test_order(p_id, p_side, p_qty, 100.0).with_client(p_client_id)
+    }
+
+    //resting: A sells 100, B sells 100, then A buys p_qty
+    fn run(p_stp_mode: StpMode, p_qty: i32) -> (MatchingEngine, MatchingResult, Order) {
+        let mut engine = MatchingEngine::new();
+        engine.set_stp_mode(p_stp_mode);
+        let mut sell_a = order("1", "A", OrderSide::Sell, 100);
+        process_event(EventType::New, &mut sell_a, &mut engine).unwrap();
+        let mut sell_b = order("2", "B", OrderSide::Sell, 100);
+        process_event(EventType::New, &mut sell_b, &mut engine).unwrap();
+        engine.drain_events();
+
+        let mut buy_a = order("3", "A", OrderSide::Buy, p_qty);
+        let result = process_event(EventType::New, &mut buy_a, &mut engine)
+            .unwrap()
+            .unwrap();
+        (engine, result, buy_a)
+    }
+
+    fn resting_qty(p_engine: &MatchingEngine, p_side: OrderSide) -> i32 {
+        let order_book = &p_engine.order_book_by_symbol_[&String::from("REL")];
+        let levels = match p_side {
+            OrderSide::Buy => &order_book.bids_,
+            OrderSide::Sell => &order_book.asks_,
+        };
+        levels
+            .iter()
+            .flat_map(|level| level.orders_.iter())
+            .map(|order| order.qty_)
+            .sum()
+    }
+
+    #[test]
+    fn cancel_newest() {
+        let (mut engine, result, buy) = run(StpMode::CancelNewest, 150);
+        assert_eq!(result.executed_qty_, 0);
+        assert_eq!(buy.qty_, 0);
+        assert_eq!(resting_qty(&engine, OrderSide::Sell), 200);
+        assert_eq!(
+            engine.drain_events(),
+            vec![EngineEvent::SelfTradePrevented {
+                symbol_: String::from("REL"),
+                aggressor_order_id_: String::from("3"),
+                resting_order_id_: String::from("1"),
+                mode_: StpMode::CancelNewest,
+                aggressor_cancelled_qty_: 150,
+                resting_cancelled_qty_: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn cancel_oldest() {
+        let (engine, result, buy) = run(StpMode::CancelOldest, 150);
+        assert_eq!(result.executed_qty_, 100);
+        assert_eq!(result.matched_order_ids_, vec!["2"]);
+        assert_eq!(buy.qty_, 50);
+        assert_eq!(resting_qty(&engine, OrderSide::Sell), 0);
+        assert_eq!(resting_qty(&engine, OrderSide::Buy), 50);
+    }
+
+    #[test]
+    fn cancel_both() {
+        let (engine, result, _) = run(StpMode::CancelBoth, 150);
+        assert_eq!(result.executed_qty_, 0);
+        assert_eq!(resting_qty(&engine, OrderSide::Sell), 100);
+        assert_eq!(resting_qty(&engine, OrderSide::Buy), 0);
+    }
+
+    #[test]
+    fn decrement_and_cancel() {
+        let (engine, result, buy) = run(StpMode::DecrementAndCancel, 150);
+        assert_eq!(result.executed_qty_, 50);
+        assert_eq!(result.aggressor_cancelled_qty_, 100);
+        assert_eq!(buy.qty_, 0);
+        assert_eq!(resting_qty(&engine, OrderSide::Sell), 50);
+
+        let (engine, _, _) = run(StpMode::DecrementAndCancel, 60);
+        assert_eq!(resting_qty(&engine, OrderSide::Sell), 140);
+        assert_eq!(resting_qty(&engine, OrderSide::Buy), 0);
+    }
+}
@@ -0,0 +1,325 @@
+use std::time::SystemTime;
+
+use crate::events::EngineEvent;
+use crate::{MatchingEngine, MatchingResult, Order};
+
+//Trade tape
+// Every execution becomes a Trade and updates the last-sale statistics of its
+// symbol. A continuous match prints one trade per resting order filled, an auction
+// uncross prints a single trade for the whole uncrossed qty.
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Trade {
+    #[cfg_attr(feature = "serde", serde(rename = "symbol"))]
+    symbol_: String,
+    #[cfg_attr(feature = "serde", serde(rename = "trade_id"))]
+    trade_id_: u64,
+    #[cfg_attr(feature = "serde", serde(rename = "price"))]
+    price_: f32,
+    #[cfg_attr(feature = "serde", serde(rename = "qty"))]
+    qty_: i32,
+    // empty for auction trades which have no aggressor
+    #[cfg_attr(feature = "serde", serde(rename = "maker_order_id"))]
+    maker_order_id_: String,
+    #[cfg_attr(feature = "serde", serde(rename = "taker_order_id"))]
+    taker_order_id_: String,
+    #[cfg_attr(feature = "serde", serde(rename = "time"))]
+    time_: SystemTime,
+}
+
+impl Trade {
+    pub(crate) fn new(
+        p_symbol: &String,
+        p_trade_id: u64,
+        p_price: f32,
+        p_qty: i32,
+        p_maker_order_id: &str,
+        p_taker_order_id: &str,
+        p_time: SystemTime,
+    ) -> Self {
+        Trade {
+            symbol_: p_symbol.to_owned(),
+            trade_id_: p_trade_id,
+            price_: p_price,
+            qty_: p_qty,
+            maker_order_id_: p_maker_order_id.to_owned(),
+            taker_order_id_: p_taker_order_id.to_owned(),
+            time_: p_time,
+        }
+    }
+
+    pub fn symbol(&self) -> &String {
+        &self.symbol_
+    }
+
+    pub fn trade_id(&self) -> u64 {
+        self.trade_id_
+    }
+
+    pub fn price(&self) -> f32 {
+        self.price_
+    }
+
+    pub fn qty(&self) -> i32 {
+        self.qty_
+    }
+
+    pub fn maker_order_id(&self) -> &String {
+        &self.maker_order_id_
+    }
+
+    pub fn taker_order_id(&self) -> &String {
+        &self.taker_order_id_
+    }
+
+    pub fn time(&self) -> SystemTime {
+        self.time_
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TradeStatistics {
+    #[cfg_attr(feature = "serde", serde(rename = "symbol"))]
+    pub(crate) symbol_: String,
+    #[cfg_attr(feature = "serde", serde(rename = "last_price"))]
+    pub(crate) last_price_: f32,
+    #[cfg_attr(feature = "serde", serde(rename = "last_qty"))]
+    pub(crate) last_qty_: i32,
+    #[cfg_attr(feature = "serde", serde(rename = "open"))]
+    pub(crate) open_: f32,
+    #[cfg_attr(feature = "serde", serde(rename = "high"))]
+    pub(crate) high_: f32,
+    #[cfg_attr(feature = "serde", serde(rename = "low"))]
+    pub(crate) low_: f32,
+    #[cfg_attr(feature = "serde", serde(rename = "volume"))]
+    pub(crate) volume_: i64,
+    #[cfg_attr(feature = "serde", serde(rename = "turnover"))]
+    pub(crate) turnover_: f64,
+    #[cfg_attr(feature = "serde", serde(rename = "trade_count"))]
+    pub(crate) trade_count_: u64,
+}
+
+impl TradeStatistics {
+    pub(crate) fn new(p_symbol: &String) -> Self {
+        TradeStatistics {
+            symbol_: p_symbol.to_owned(),
+            last_price_: 0.0,
+            last_qty_: 0,
+            open_: 0.0,
+            high_: 0.0,
+            low_: 0.0,
+            volume_: 0,
+            turnover_: 0.0,
+            trade_count_: 0,
+        }
+    }
+
+    fn record(&mut self, p_trade: &Trade) {
+        if self.trade_count_ == 0 {
+            self.open_ = p_trade.price_;
+            self.high_ = p_trade.price_;
+            self.low_ = p_trade.price_;
+        }
+        self.last_price_ = p_trade.price_;
+        self.last_qty_ = p_trade.qty_;
+        self.high_ = self.high_.max(p_trade.price_);
+        self.low_ = self.low_.min(p_trade.price_);
+        self.volume_ += p_trade.qty_ as i64;
+        self.turnover_ += p_trade.qty_ as f64 * p_trade.price_ as f64;
+        self.trade_count_ += 1;
+    }
+
+    pub fn symbol(&self) -> &String {
+        &self.symbol_
+    }
+
+    pub fn last_price(&self) -> f32 {
+        self.last_price_
+    }
+
+    pub fn last_qty(&self) -> i32 {
+        self.last_qty_
+    }
+
+    pub fn open(&self) -> f32 {
+        self.open_
+    }
+
+    pub fn high(&self) -> f32 {
+        self.high_
+    }
+
+    pub fn low(&self) -> f32 {
+        self.low_
+    }
+
+    pub fn volume(&self) -> i64 {
+        self.volume_
+    }
+
+    pub fn turnover(&self) -> f64 {
+        self.turnover_
+    }
+
+    pub fn trade_count(&self) -> u64 {
+        self.trade_count_
+    }
+
+    pub fn vwap(&self) -> Option<f64> {
+        if self.volume_ == 0 {
+            return None;
+        }
+        return Some(self.turnover_ / self.volume_ as f64);
+    }
+}
+
+impl MatchingEngine {
+    pub fn trade_statistics(&self, p_symbol: &String) -> Option<&TradeStatistics> {
+        self.statistics_by_symbol_.get(p_symbol)
+    }
+
+    //starts a new trading day for p_symbol
+    pub fn reset_trade_statistics(&mut self, p_symbol: &String) {
+        self.statistics_by_symbol_.remove(p_symbol);
+    }
+
+    //prints the trades of p_result, p_aggressor is None for an auction uncross
+    pub(crate) fn record_trades(
+        &mut self,
+        p_symbol: &String,
+        p_aggressor: Option<&Order>,
+        p_result: &Option<MatchingResult>,
+        p_time: SystemTime,
+    ) {
+        let match_result = match p_result {
+            Some(match_result) if match_result.executed_qty_ > 0 => match_result,
+            _ => return,
+        };
+
+        let trades: Vec<Trade> = match p_aggressor {
+            Some(aggressor) => match_result
+                .fills_
+                .iter()
+                .map(|fill| {
+                    Trade::new(
+                        p_symbol,
+                        fill.trade_id(),
+                        fill.price(),
+                        fill.qty(),
+                        fill.maker_order_id(),
+                        &aggressor.id_,
+                        p_time,
+                    )
+                })
+                .collect(),
+            None => {
+                let trade_id = match_result
+                    .fills_
+                    .first()
+                    .map(|fill| fill.trade_id())
+                    .unwrap_or_default();
+                vec![Trade::new(
+                    p_symbol,
+                    trade_id,
+                    match_result.executed_price_,
+                    match_result.executed_qty_,
+                    "",
+                    "",
+                    p_time,
+                )]
+            }
+        };
+
+        let statistics = self
+            .statistics_by_symbol_
+            .entry(p_symbol.to_owned())
+            .or_insert_with(|| TradeStatistics::new(p_symbol));
+        for trade in &trades {
+            statistics.record(trade);
+        }
+        let statistics = statistics.clone();
+        self.events_
+            .extend(trades.iter().cloned().map(EngineEvent::Trade));
+        self.events_.push(EngineEvent::TradeStatistics(statistics));
+        self.aggregate_bars(p_symbol, &trades);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::session::TradingState;
+    use crate::test_order as order;
+    use crate::{process_event, EventType, OrderSide};
+
+    #[test]
+    fn statistics_follow_fills() {
+        let symbol = String::from("REL");
+        let mut engine = MatchingEngine::new();
+        let orders = [
+            order("1", OrderSide::Sell, 100, 100.0),
+            order("2", OrderSide::Sell, 100, 100.0),
+            order("3", OrderSide::Buy, 150, 100.0),
+            order("4", OrderSide::Sell, 50, 102.0),
+            order("5", OrderSide::Buy, 50, 102.0),
+        ];
+        for mut order in orders {
+            process_event(EventType::New, &mut order, &mut engine).unwrap();
+        }
+
+        let statistics = engine.trade_statistics(&symbol).unwrap();
+        assert_eq!(statistics.trade_count(), 3);
+        assert_eq!(statistics.volume(), 200);
+        assert_eq!(statistics.last_price(), 102.0);
+        assert_eq!(statistics.last_qty(), 50);
+        assert_eq!(
+            (statistics.open(), statistics.high(), statistics.low()),
+            (100.0, 102.0, 100.0)
+        );
+        assert_eq!(statistics.turnover(), 20100.0);
+        assert_eq!(statistics.vwap(), Some(100.5));
+
+        let trades: Vec<Trade> = engine
+            .drain_events()
+            .into_iter()
+            .filter_map(|event| match event {
+                EngineEvent::Trade(trade) => Some(trade),
+                _ => None,
+            })
+            .collect();
+        let makers: Vec<&str> = trades
+            .iter()
+            .map(|trade| trade.maker_order_id().as_str())
+            .collect();
+        assert_eq!(makers, vec!["1", "2", "4"]);
+        assert_eq!(trades[1].qty(), 50);
+        assert_eq!(trades[1].taker_order_id(), "3");
+    }
+
+    #[test]
+    fn auction_prints_single_trade() {
+        let symbol = String::from("REL");
+        let mut engine = MatchingEngine::new();
+        engine.transition(&symbol, TradingState::Halted).unwrap();
+        engine
+            .transition(&symbol, TradingState::OpeningAuction)
+            .unwrap();
+        let orders = [
+            order("1", OrderSide::Sell, 100, 99.0),
+            order("2", OrderSide::Sell, 100, 100.0),
+            order("3", OrderSide::Buy, 200, 101.0),
+        ];
+        for mut order in orders {
+            process_event(EventType::New, &mut order, &mut engine).unwrap();
+        }
+        engine
+            .transition(&symbol, TradingState::Continuous)
+            .unwrap();
+
+        let statistics = engine.trade_statistics(&symbol).unwrap();
+        assert_eq!(statistics.trade_count(), 1);
+        assert_eq!(statistics.volume(), 200);
+    }
+}